@@ -0,0 +1,75 @@
+#pragma once
+
+// Makes the token the monitor asks for before the progress updates when a secret is set, see
+// src/communication/auth.rs. Link with -lcrypto.
+//
+// The secret is read from the MONITOR_SECRET environment variable, and must be the auth.secret
+// of the settings of the monitor. Without it, no token is sent.
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+#include <unistd.h>
+#include <openssl/evp.h>
+#include <openssl/hmac.h>
+#include <openssl/rand.h>
+
+#define MONITOR_NONCE_SIZE 16
+#define MONITOR_TOKEN_SIZE (8 + MONITOR_NONCE_SIZE + 32)
+#define MONITOR_INGEST_PURPOSE "meshotron-ingest"
+
+// Fills token with a fresh token for the ingest server
+// Returns 1 when a token was made, 0 without a secret and -1 on failure
+static int monitorToken(unsigned char token[MONITOR_TOKEN_SIZE])
+{
+    const char* secret = getenv("MONITOR_SECRET");
+    if (secret == NULL || secret[0] == '\0')
+        return 0;
+
+    struct timespec now;
+    clock_gettime(CLOCK_REALTIME, &now);
+    uint64_t ms = (uint64_t)now.tv_sec * 1000 + now.tv_nsec / 1000000;
+
+    // big endian timestamp, then the nonce
+    for (int i = 0; i < 8; i++)
+        token[i] = ms >> (56 - 8 * i);
+    if (RAND_bytes(token + 8, MONITOR_NONCE_SIZE) != 1)
+        return -1;
+
+    // HMAC-SHA256 of the purpose, the timestamp and the nonce
+    unsigned char signed_part[sizeof(MONITOR_INGEST_PURPOSE) - 1 + 8 + MONITOR_NONCE_SIZE];
+    memcpy(signed_part, MONITOR_INGEST_PURPOSE, sizeof(MONITOR_INGEST_PURPOSE) - 1);
+    memcpy(signed_part + sizeof(MONITOR_INGEST_PURPOSE) - 1, token, 8 + MONITOR_NONCE_SIZE);
+
+    unsigned int mac_len = 0;
+    if (HMAC(EVP_sha256(), secret, strlen(secret), signed_part, sizeof(signed_part),
+             token + 8 + MONITOR_NONCE_SIZE, &mac_len) == NULL || mac_len != 32)
+        return -1;
+
+    return 1;
+}
+
+// Sends a fresh token on a new connection to the ingest server, if a secret is set
+// Returns false when the token could not be made or sent
+static bool monitorAuthenticate(int sockfd)
+{
+    unsigned char token[MONITOR_TOKEN_SIZE];
+    int res = monitorToken(token);
+
+    if (res == 0)
+        return true;
+    if (res < 0)
+    {
+        printf("Could not make the monitor token\n");
+        return false;
+    }
+    if (write(sockfd, token, MONITOR_TOKEN_SIZE) != MONITOR_TOKEN_SIZE)
+    {
+        printf("Something went wrong sending the token to the monitor\n");
+        return false;
+    }
+
+    return true;
+}
@@ -39,6 +39,12 @@ void monitorSend(MonitorData* monitorData)
         return;
     }
     else printf("connected to the server..\n");
+
+    if (!monitorAuthenticate(sockfd))
+    {
+        close(sockfd);
+        return;
+    }
    
     // debug to file
     // FILE* f = fopen("monitor.dbg", "w");
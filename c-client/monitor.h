@@ -9,6 +9,8 @@
 #include <arpa/inet.h>
 #include <netdb.h>
 
+#include "auth.h"
+
 #define PORT 49152
 #define SA struct sockaddr
 
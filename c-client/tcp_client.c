@@ -6,6 +6,8 @@
 #include <unistd.h>
 #include <stdbool.h>
 
+#include "auth.h"
+
 #define MAX 8
 #define PORT 49152
 #define SA struct sockaddr
@@ -49,6 +51,11 @@ void monitorInit(int idfk)
     }
     else
         printf("connected to the server..\n");
+
+    if (!monitorAuthenticate(sockfd)) {
+        close(sockfd);
+        exit(0);
+    }
 }
 
 // https://www.geeksforgeeks.org/tcp-server-client-implementation-in-c/
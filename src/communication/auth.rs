@@ -0,0 +1,252 @@
+//! Makes sure the clients of the ingest and file servers know the secret shared with the cluster
+//! programs and the room partitioner, when one is set in the [AuthSettings].
+//!
+//! # Token
+//!
+//! When a secret is set, a client starts every connection with a 56 bytes token, before the
+//! progress updates or the file:
+//!
+//! - `timestamp`: When the token was made, in milliseconds since the UNIX epoch, as a big endian
+//!   u64
+//! - `nonce`: 16 random bytes
+//! - `mac`: The HMAC-SHA256, keyed with the secret, of the purpose followed by the timestamp and
+//!   the nonce
+//!
+//! The purpose is `meshotron-ingest` on the ingest server and `meshotron-file` on the file
+//! server, so a token made for one is refused by the other. Every token is only accepted once,
+//! and only within [AuthSettings::max_skew_s] of the clock of the node.
+//!
+//! The clients that are turned away are logged and counted in the [MonitorStats]. For the scripts
+//! sending the files, `monitor token <ingest|file> <settings>` prints a fresh token, in hex.
+//!
+//! The DWM ranks make their own token on every connection with `c-client/auth.h`, linked with
+//! `-lcrypto`, from the secret in their `MONITOR_SECRET` environment variable. It must hold the
+//! same secret as the settings, and no token is sent without it. The test client asks
+//! `monitor token ingest` for one when `MONITOR_SETTINGS` names the settings of the monitor.
+
+use crate::communication::http_requests::now_ms;
+use crate::config::AuthSettings;
+use crate::monitor::self_stats::MonitorStats;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The size of a token, in bytes
+pub const TOKEN_SIZE: usize = 8 + NONCE_SIZE + 32;
+
+/// The time a client has to write its token
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The size of the nonce of a token, in bytes
+const NONCE_SIZE: usize = 16;
+
+/// The server a token is made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// The progress updates of the cluster programs
+    Ingest,
+    /// The room description files of the room partitioner
+    File,
+}
+
+impl Purpose {
+    /// Returns the purpose by the name given on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ingest" => Some(Purpose::Ingest),
+            "file" => Some(Purpose::File),
+            _ => None,
+        }
+    }
+
+    /// What starts the authenticated bytes of the tokens
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Purpose::Ingest => b"meshotron-ingest",
+            Purpose::File => b"meshotron-file",
+        }
+    }
+}
+
+/// Returns a new token
+///
+/// # Arguments
+///
+/// - `settings`: The secret to sign the token with
+/// - `purpose`: The server the token is for
+/// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+pub fn token(settings: &AuthSettings, purpose: Purpose, now_ms: u64) -> [u8; TOKEN_SIZE] {
+    let mut token = [0; TOKEN_SIZE];
+    token[..8].copy_from_slice(&now_ms.to_be_bytes());
+    token[8..8 + NONCE_SIZE].copy_from_slice(&rand::random::<[u8; NONCE_SIZE]>());
+
+    let mac = mac(
+        settings.secret.as_bytes(),
+        purpose,
+        &token[..8 + NONCE_SIZE],
+    );
+    token[8 + NONCE_SIZE..].copy_from_slice(&mac.finalize().into_bytes());
+
+    token
+}
+
+/// Checks the tokens of the clients of a server
+///
+/// # Properties
+/// -`settings`: The secret and how long the tokens stay valid
+/// -`purpose`: The server the tokens must be made for
+/// -`stats`: Where the clients turned away are counted
+/// -`seen`: The nonces of the tokens accepted recently, with their timestamp, so they cannot be
+///   used again
+pub struct Authenticator {
+    settings: AuthSettings,
+    purpose: Purpose,
+    stats: Arc<MonitorStats>,
+    seen: Mutex<HashMap<[u8; NONCE_SIZE], u64>>,
+}
+
+impl Authenticator {
+    /// Returns the authenticator of a server, if a secret is set
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: The secret and how long the tokens stay valid
+    /// - `purpose`: The server the tokens must be made for
+    /// - `stats`: Where to count the clients turned away
+    pub fn new(
+        settings: &AuthSettings,
+        purpose: Purpose,
+        stats: Arc<MonitorStats>,
+    ) -> Option<Self> {
+        if settings.secret.is_empty() {
+            return None;
+        }
+
+        Some(Authenticator {
+            settings: settings.clone(),
+            purpose,
+            stats,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Reads the token a client starts with and returns whether it is accepted. The clients
+    /// turned away are logged and counted
+    ///
+    /// # Arguments
+    ///
+    /// - `stream`: The connection of the client
+    /// - `peer`: The address of the client, for the logs
+    pub fn admit<S: Read>(&self, stream: &mut S, peer: &str) -> bool {
+        let mut token = [0; TOKEN_SIZE];
+        let res = match stream.read_exact(&mut token) {
+            Ok(_) => self.verify(&token, now_ms()),
+            Err(e) => Err(format!("no token: {}", e)),
+        };
+
+        match res {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Rejected {} on the {:?} server: {}", peer, self.purpose, e);
+                let counter = match self.purpose {
+                    Purpose::Ingest => &self.stats.rejected_ingest,
+                    Purpose::File => &self.stats.rejected_files,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Checks a token, and remembers it so it is not accepted again
+    ///
+    /// # Arguments
+    ///
+    /// - `token`: The token written by the client
+    /// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+    fn verify(&self, token: &[u8; TOKEN_SIZE], now_ms: u64) -> Result<(), String> {
+        let (signed, signature) = token.split_at(8 + NONCE_SIZE);
+        mac(self.settings.secret.as_bytes(), self.purpose, signed)
+            .verify_slice(signature)
+            .map_err(|_| "wrong token".to_owned())?;
+
+        let timestamp = u64::from_be_bytes(signed[..8].try_into().unwrap());
+        let max_skew = self.settings.max_skew().as_millis() as u64;
+        if now_ms.saturating_sub(timestamp) > max_skew
+            || timestamp.saturating_sub(now_ms) > max_skew
+        {
+            return Err(format!(
+                "token made at {}, too far from {}",
+                timestamp, now_ms
+            ));
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        // the tokens older than that are refused anyway
+        seen.retain(|_, t| now_ms.saturating_sub(*t) <= max_skew);
+        if seen
+            .insert(signed[8..].try_into().unwrap(), timestamp)
+            .is_some()
+        {
+            return Err("token already used".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the HMAC of the signed part of a token, before it is finalized
+fn mac(secret: &[u8], purpose: Purpose, signed: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+    mac.update(purpose.label());
+    mac.update(signed);
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_each_valid_token_once() {
+        let settings = AuthSettings {
+            secret: "s3cret".to_owned(),
+            max_skew_s: 30,
+        };
+        let stats = Arc::new(MonitorStats::default());
+        let files = Authenticator::new(&settings, Purpose::File, Arc::clone(&stats)).unwrap();
+        let now = now_ms();
+
+        let valid = token(&settings, Purpose::File, now);
+        assert!(files.admit(&mut &valid[..], "partitioner"));
+        // replayed
+        assert!(!files.admit(&mut &valid[..], "partitioner"));
+        // made for the ingest server
+        let ingest = token(&settings, Purpose::Ingest, now);
+        assert!(!files.admit(&mut &ingest[..], "partitioner"));
+        // too old
+        let stale = token(&settings, Purpose::File, now - 60_000);
+        assert!(!files.admit(&mut &stale[..], "partitioner"));
+        // signed with another secret
+        let other = AuthSettings {
+            secret: "guess".to_owned(),
+            ..settings.clone()
+        };
+        let forged = token(&other, Purpose::File, now);
+        assert!(!files.admit(&mut &forged[..], "student"));
+        // the connection closed before the whole token was written
+        assert!(!files.admit(&mut &valid[..10], "student"));
+
+        assert_eq!(stats.rejected_files.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.rejected_ingest.load(Ordering::Relaxed), 0);
+        assert!(Authenticator::new(&AuthSettings::default(), Purpose::Ingest, stats).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+//! Coalesces the node and process updates so the server is not flooded when many processes report
+//! at a high rate.
+//!
+//! During a window, only which processes reported is remembered: their latest state is already
+//! kept in the tracked processes. At the end of the window, the node and those processes are sent
+//! in a single [UpdateBatch]. Events never wait for the window.
+
+use crate::communication::sink::Sink;
+use crate::monitor::stats::{NodeData, ProcData, UpdateBatch};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Remembers the processes that reported during the current window
+///
+/// # Properties
+/// -`window`: The time during which the updates are coalesced. Zero sends every update right away
+/// -`pending`: The PIDs of the processes that reported since the last batch
+pub struct Coalescer {
+    window: Duration,
+    pending: Mutex<BTreeSet<i32>>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            pending: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Sends an update of the node and of a process, or queues it for the next batch
+    ///
+    /// # Arguments
+    ///
+    /// - `node`: The node's object
+    /// - `p`: The process that reported
+    /// - `upstream`: Where the updates for the room partitioner server go
+    pub fn update(&self, node: &NodeData, p: &ProcData, upstream: &dyn Sink) {
+        if self.window.is_zero() {
+            upstream.send(node);
+            upstream.send(p);
+        } else {
+            self.pending.lock().unwrap().insert(p.get_pid());
+        }
+    }
+
+    /// Sends an update of the node and of a process right away, taking the process out of the
+    /// next batch
+    ///
+    /// # Arguments
+    ///
+    /// - `node`: The node's object
+    /// - `p`: The process that reported, if it is tracked
+    /// - `upstream`: Where the updates for the room partitioner server go
+    pub fn flush(&self, node: &NodeData, p: Option<&ProcData>, upstream: &dyn Sink) {
+        upstream.send(node);
+        if let Some(p) = p {
+            self.pending.lock().unwrap().remove(&p.get_pid());
+            upstream.send(p);
+        }
+    }
+
+    /// Sends a batch at the end of every window, forever
+    ///
+    /// # Arguments
+    ///
+    /// - `procs`: The processes' object's list
+    /// - `node`: The node's object
+    /// - `upstream`: Where the updates for the room partitioner server go
+    pub fn run(
+        &self,
+        procs: &Mutex<HashMap<i32, ProcData>>,
+        node: &Mutex<NodeData>,
+        upstream: &dyn Sink,
+    ) {
+        loop {
+            thread::sleep(self.window);
+
+            let procs = procs.lock().unwrap();
+            let node = node.lock().unwrap();
+            if let Some(batch) = self.take(&procs, &node) {
+                upstream.send(&batch);
+            }
+        }
+    }
+
+    /// Returns the batch of the window that just ended, if any process reported during it
+    ///
+    /// # Arguments
+    ///
+    /// - `procs`: The tracked processes
+    /// - `node`: The node's object
+    fn take<'a>(
+        &self,
+        procs: &'a HashMap<i32, ProcData>,
+        node: &'a NodeData,
+    ) -> Option<UpdateBatch<'a>> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return None;
+        }
+
+        let processes = pending.iter().filter_map(|pid| procs.get(pid)).collect();
+
+        Some(UpdateBatch::new(node, processes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::http_requests::{MessageKind, RequestSerializable};
+    use crate::communication::sink::Recorder;
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+
+    #[test]
+    fn keeps_the_latest_state_of_every_process_until_the_window_ends() {
+        let node = NodeData::new(&mut FakeSource::new(NodeInfo::default()));
+        let mut snapshot = Snapshot::default();
+        snapshot.processes.insert(10, Default::default());
+        let mut procs = HashMap::new();
+        procs.insert(10, ProcData::new(10, 0, &snapshot));
+        procs.insert(11, ProcData::new(11, 0, &snapshot));
+
+        let upstream = Recorder::default();
+        let coalescer = Coalescer::new(Duration::from_secs(1));
+
+        for progress in [10.0, 20.0, 30.0] {
+            let p = procs.get_mut(&10).unwrap();
+            p.update(progress, 0.0, 0.0, 0.0, 0.0, &snapshot);
+            coalescer.update(&node, p, &upstream);
+        }
+        coalescer.update(&node, &procs[&11], &upstream);
+        assert!(upstream.messages().is_empty());
+
+        let batch = coalescer.take(&procs, &node).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&batch.serialize()).unwrap();
+        assert_eq!(json["processes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["processes"][0]["progress"], 30.0);
+        assert!(coalescer.take(&procs, &node).is_none());
+
+        // the end of a run is not held back
+        coalescer.update(&node, &procs[&10], &upstream);
+        coalescer.flush(&node, Some(&procs[&10]), &upstream);
+        assert_eq!(
+            upstream.kinds(),
+            vec![MessageKind::Node, MessageKind::Process]
+        );
+        assert!(coalescer.take(&procs, &node).is_none());
+    }
+}
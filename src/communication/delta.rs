@@ -0,0 +1,227 @@
+//! Sends only the fields of the node and process updates that changed since the previous update
+//! of the same node or process on the connection.
+//!
+//! # Protocol
+//!
+//! The first update of the node and of every process on a connection is sent in full, with
+//! `"delta": false`. The next ones only hold the fields that changed, with `"delta": true`, plus
+//! the fields identifying the object (`nodeId`, `pid`, `runId`). A field that is no longer sent is sent as
+//! `null`. In a batch, the node and every process are compared on their own.
+//!
+//! Every node, process and batch message carries `deltaSeq`, starting from 0 on every connection
+//! and increased by one on every message. A receiver that sees a gap missed an update and asks for
+//! a resync by writing the byte [RESYNC] on the connection, or by closing it: the node and every
+//! known process are then sent again in full, starting again from `deltaSeq` 0. Until then, the
+//! receiver ignores the deltas that were already on their way. The messages of a snapshot were
+//! already counted when they were first sent, so they have no `seq`.
+//!
+//! Events are sent unchanged, without `deltaSeq`.
+
+use crate::communication::http_requests::{MessageKind, SCHEMA_VERSION};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// The byte the server writes on the connection to ask for a resync
+pub const RESYNC: u8 = b'R';
+
+/// The fields sent in every update, as they identify what the update is about
+const IDENTITY: [&str; 4] = ["schemaVersion", "nodeId", "pid", "runId"];
+
+/// What an update is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Node,
+    Process(i64),
+}
+
+/// The state the server knows of the node and of every process
+///
+/// # Properties
+/// -`seq`: The `deltaSeq` of the next message
+/// -`last`: The latest full update of every object sent on the connection
+#[derive(Default)]
+pub struct DeltaEncoder {
+    seq: u64,
+    last: BTreeMap<Key, Map<String, Value>>,
+}
+
+impl DeltaEncoder {
+    /// Returns the message to send in place of an update
+    ///
+    /// # Arguments
+    ///
+    /// - `kind`: What the message is about
+    /// - `message`: The full message
+    pub fn encode(&mut self, kind: MessageKind, message: Value) -> Value {
+        let mut message = match (kind, message) {
+            (MessageKind::Node, Value::Object(m)) => self.diff(Key::Node, m),
+            (MessageKind::Process, Value::Object(m)) => match process_key(&m) {
+                Some(key) => self.diff(key, m),
+                None => m,
+            },
+            (MessageKind::Batch, Value::Object(mut m)) => {
+                if let Some(Value::Object(node)) = m.remove("node") {
+                    m.insert("node".to_owned(), Value::Object(self.diff(Key::Node, node)));
+                }
+                if let Some(Value::Array(processes)) = m.remove("processes") {
+                    let processes = processes
+                        .into_iter()
+                        .map(|p| match p {
+                            Value::Object(p) => match process_key(&p) {
+                                Some(key) => Value::Object(self.diff(key, p)),
+                                None => Value::Object(p),
+                            },
+                            p => p,
+                        })
+                        .collect();
+                    m.insert("processes".to_owned(), Value::Array(processes));
+                }
+                m
+            }
+            (MessageKind::Event, message) => {
+                // the server forgets the process, so it is not part of the next snapshots
+                if message["event"] == "processDisappeared" {
+                    if let Some(pid) = message["pid"].as_i64() {
+                        self.last.remove(&Key::Process(pid));
+                    }
+                }
+                return message;
+            }
+            (_, message) => return message,
+        };
+
+        self.stamp(&mut message);
+        Value::Object(message)
+    }
+
+    /// Returns the full state of the node and of every known process, and starts the sequence
+    /// again. Used on every new connection and when the server asks for a resync.
+    pub fn snapshot(&mut self) -> Vec<(MessageKind, Value)> {
+        self.seq = 0;
+
+        let messages: Vec<(MessageKind, Map<String, Value>)> = self
+            .last
+            .iter()
+            .map(|(key, m)| {
+                let kind = match key {
+                    Key::Node => MessageKind::Node,
+                    Key::Process(_) => MessageKind::Process,
+                };
+                let mut m = m.clone();
+                // the objects only seen in a batch have no version of their own
+                m.entry("schemaVersion").or_insert(SCHEMA_VERSION.into());
+                m.insert("delta".to_owned(), false.into());
+                m.remove("seq");
+
+                (kind, m)
+            })
+            .collect();
+
+        messages
+            .into_iter()
+            .map(|(kind, mut m)| {
+                self.stamp(&mut m);
+                (kind, Value::Object(m))
+            })
+            .collect()
+    }
+
+    /// Returns the fields of an object that changed since it was last sent, and remembers it
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: What the object is
+    /// - `current`: The full object
+    fn diff(&mut self, key: Key, current: Map<String, Value>) -> Map<String, Value> {
+        let mut out = match self.last.get(&key) {
+            None => {
+                let mut out = current.clone();
+                out.insert("delta".to_owned(), false.into());
+                out
+            }
+            Some(previous) => {
+                let mut out: Map<String, Value> = current
+                    .iter()
+                    .filter(|(k, v)| IDENTITY.contains(&k.as_str()) || previous.get(*k) != Some(v))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                for k in previous.keys() {
+                    if !current.contains_key(k) {
+                        out.insert(k.clone(), Value::Null);
+                    }
+                }
+                out.insert("delta".to_owned(), true.into());
+                out
+            }
+        };
+
+        out.remove("deltaSeq");
+        self.last.insert(key, current);
+
+        out
+    }
+
+    /// Gives a message the next sequence number
+    fn stamp(&mut self, message: &mut Map<String, Value>) {
+        message.insert("deltaSeq".to_owned(), self.seq.into());
+        self.seq += 1;
+    }
+}
+
+/// Returns the key of a process update
+fn process_key(process: &Map<String, Value>) -> Option<Key> {
+    process.get("pid")?.as_i64().map(Key::Process)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sends_only_what_changed_until_a_resync() {
+        let mut encoder = DeltaEncoder::default();
+        let node = |used_ram: u64| json!({"schemaVersion": 1, "nodeId": 0, "cores": 8, "usedRam": used_ram});
+        let process =
+            |pid: i32, progress: f32| json!({"nodeId": 0, "pid": pid, "progress": progress});
+
+        assert_eq!(
+            encoder.encode(MessageKind::Node, node(100)),
+            json!({"schemaVersion": 1, "nodeId": 0, "cores": 8, "usedRam": 100, "delta": false, "deltaSeq": 0})
+        );
+        assert_eq!(
+            encoder.encode(MessageKind::Node, node(200)),
+            json!({"schemaVersion": 1, "nodeId": 0, "usedRam": 200, "delta": true, "deltaSeq": 1})
+        );
+
+        let batch = json!({"schemaVersion": 1, "node": node(200), "processes": [process(10, 5.5)]});
+        assert_eq!(
+            encoder.encode(MessageKind::Batch, batch),
+            json!({
+                "schemaVersion": 1,
+                "node": {"schemaVersion": 1, "nodeId": 0, "delta": true},
+                "processes": [{"nodeId": 0, "pid": 10, "progress": 5.5, "delta": false}],
+                "deltaSeq": 2,
+            })
+        );
+        assert_eq!(
+            encoder.encode(MessageKind::Process, process(10, 7.5))["progress"],
+            7.5
+        );
+
+        let event = json!({"event": "processAppeared", "nodeId": 0, "pid": 11});
+        assert_eq!(encoder.encode(MessageKind::Event, event.clone()), event);
+
+        let snapshot = encoder.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].1["usedRam"], 200);
+        assert_eq!(snapshot[1].1["deltaSeq"], 1);
+        assert_eq!(snapshot[1].1["progress"], 7.5);
+        assert_eq!(snapshot[1].1["schemaVersion"], 1);
+        assert_eq!(snapshot[1].1["delta"], false);
+
+        let event = json!({"event": "processDisappeared", "nodeId": 0, "pid": 10});
+        encoder.encode(MessageKind::Event, event);
+        assert_eq!(encoder.snapshot().len(), 1);
+    }
+}
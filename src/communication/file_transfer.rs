@@ -1,7 +1,13 @@
 //! Holds methods to transfer and receive files.
 //! File reception is handled through a TCP server
+//!
+//! Both ways can be encrypted with TLS, see [tls](crate::communication::tls), and the files
+//! received can be required to start with a token, see [auth](crate::communication::auth)
 
+use crate::communication::auth::{Authenticator, AUTH_TIMEOUT};
+use crate::communication::tls::{Endpoint, Stream, TlsServer};
 use byteorder::{BigEndian, WriteBytesExt};
+use serde::Serialize;
 use std::{
     fs,
     fs::File,
@@ -9,8 +15,25 @@ use std::{
     net::{TcpListener, TcpStream},
     sync::{Arc, Mutex},
     thread,
+    time::UNIX_EPOCH,
 };
 
+/// A file received from the partitioner or waiting to be sent to it
+///
+/// # Properties
+/// -`name`: The name of the file, in the working directory
+/// -`kind`: `room` for the room description files received, `pcm` for the sound files to send
+/// -`size`: The size of the file, in bytes
+/// -`modified_at`: When the file was last written, in milliseconds since the UNIX epoch
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalFile {
+    pub name: String,
+    pub kind: &'static str,
+    pub size: u64,
+    pub modified_at: u64,
+}
+
 /// Starts a server that receives files from the partitioner.
 ///
 /// Since the monitor should only receive room description files, the file extension is assumed to be .dwm.
@@ -20,19 +43,58 @@ use std::{
 /// - `ip`: The ip to start the server on
 /// - `port`: The port to bind the server to
 /// - `file_name`: The base name of the file that will be received.
-/// All the files received will have the same name with a number appended, representing the arrival
-/// order of the file.
-pub fn start_file_server(ip: String, port: usize, file_name: &'static str) {
+///   All the files received will have the same name with a number appended, representing the arrival
+///   order of the file.
+/// - `tls`: How to encrypt the connections, if they are. The clients that fail the handshake are
+///   turned away before anything is written
+/// - `auth`: The token the files must start with, if any. The files without a valid token are
+///   not written
+pub fn start_file_server(
+    ip: String,
+    port: usize,
+    file_name: &'static str,
+    tls: Option<TlsServer>,
+    auth: Option<Authenticator>,
+) {
     let listener = TcpListener::bind(ip + ":" + &*port.to_string()).unwrap();
 
     let cnt = Arc::new(Mutex::new(0));
+    let tls = tls.map(Arc::new);
+    let auth = auth.map(Arc::new);
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let cnt_handle = Arc::clone(&cnt);
+                let tls = tls.clone();
+                let auth = auth.clone();
 
                 thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map(|a| a.to_string())
+                        .unwrap_or_default();
+                    let stream = match &tls {
+                        Some(tls) => tls.accept(stream),
+                        None => Ok(Stream::Plain(stream)),
+                    };
+
+                    let mut stream = match stream {
+                        Ok(s) => s,
+                        Err(e) => {
+                            println!("Rejected a file from {}: {}", peer, e);
+                            return;
+                        }
+                    };
+
+                    if let Some(auth) = &auth {
+                        let _ = stream.tcp().set_read_timeout(Some(AUTH_TIMEOUT));
+                        if !auth.admit(&mut stream, &peer) {
+                            return;
+                        }
+                        let _ = stream.tcp().set_read_timeout(None);
+                    }
+
                     receive_file(stream, file_name, &mut cnt_handle.lock().unwrap())
                 });
             }
@@ -47,17 +109,17 @@ pub fn start_file_server(ip: String, port: usize, file_name: &'static str) {
 /// Handles an incoming TCP byte stream containing a file
 ///
 /// # Arguments
-/// - `stream`: The incoming file TCP stream
+/// - `stream`: The incoming file stream
 /// - `file_name`: The name to write the file to
 /// - `counter`: A counter with the number of files received
 ///
 /// # Protocol
 ///
-/// The bytes stream should only contain the file
+/// The bytes stream should only contain the file, after the token when a secret is set
 ///
 /// # Acknowledgements
 /// With the help from [Stack Overflow](HTTPS://Stackoverflow.com/questions/53826371/how-to-create-a-binary-file-with-rust)
-fn receive_file(mut stream: TcpStream, file_name: &str, counter: &mut i32) {
+fn receive_file(mut stream: Stream, file_name: &str, counter: &mut i32) {
     // let file_name = file_name.to_owned() + &counter.to_string() + ".dwm";
     let file_name = file_name.to_owned() + ".dwm";
     println!("Writing to {}", file_name);
@@ -76,15 +138,58 @@ fn receive_file(mut stream: TcpStream, file_name: &str, counter: &mut i32) {
     }
 }
 
-/// Sends a file over a TCP stream
-/// It is assumed only pcm files will be transmitted and transmissions are only to the partitioner
-/// server.
-///
-/// # Arguments
-/// - `endpoint`: A string in the format `<ip>:<port>` that tells where to send the file to
-/// - `file_name`: The name of the file to send
-/// - `node_number`: The number of the node this process is running on.
-/// It is necessary to know this due to the way the merger deals with the files
+/// Whether a file is one of the pcm files sent to the partitioner
+fn is_pcm(file_name: &str) -> bool {
+    file_name.ends_with(".pcm") && file_name.starts_with("receiver_")
+}
+
+/// Lists the room description files received and the pcm files in the working directory, sorted
+/// by name
+pub fn list_files() -> Vec<LocalFile> {
+    let entries = match fs::read_dir("./") {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut files: Vec<LocalFile> = entries
+        .filter_map(|e| {
+            let e = e.ok()?;
+            let meta = e.metadata().ok()?;
+            let name = e.file_name().into_string().ok()?;
+            let kind = if is_pcm(&name) {
+                "pcm"
+            } else if name.ends_with(".dwm") {
+                "room"
+            } else {
+                return None;
+            };
+
+            meta.is_file().then(|| LocalFile {
+                kind,
+                size: meta.len(),
+                modified_at: meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_millis() as u64),
+                name,
+            })
+        })
+        .collect();
+    files.sort_by(|a, b| alphanumeric_sort::compare_str(&a.name, &b.name));
+
+    files
+}
+
+// Sends a file over a TCP stream
+// It is assumed only pcm files will be transmitted and transmissions are only to the partitioner
+// server.
+//
+// # Arguments
+// - `endpoint`: A string in the format `<ip>:<port>` that tells where to send the file to
+// - `file_name`: The name of the file to send
+// - `node_number`: The number of the node this process is running on.
+//   It is necessary to know this due to the way the merger deals with the files
 // pub fn send_file(endpoint: &str, file_name: &str, node_number: u8) {
 //     println!("node {}", node_number as i32);
 //     match TcpStream::connect(endpoint) {
@@ -110,10 +215,10 @@ fn receive_file(mut stream: TcpStream, file_name: &str, counter: &mut i32) {
 /// server.
 ///
 /// # Arguments
-/// - `endpoint`: A string in the format `<ip>:<port>` that tells where to send the file to
+/// - `endpoint`: Where to send the files to
 /// - `node_number`: The number of the node this process is running on.
-/// It is necessary to know this due to the way the merger deals with the files
-pub fn send_all_pcm(endpoint: &String, node_number: u8) {
+///   It is necessary to know this due to the way the merger deals with the files
+pub fn send_all_pcm(endpoint: &Endpoint, node_number: u8) {
     let mut files: Vec<String> = fs::read_dir("./")
         .unwrap()
         .filter(|dir_entry| {
@@ -123,9 +228,7 @@ pub fn send_all_pcm(endpoint: &String, node_number: u8) {
                 .file_name()
                 .into_string()
                 .unwrap();
-            dir_entry.as_ref().unwrap().file_type().unwrap().is_file()
-                && file_name.ends_with(".pcm")
-                && file_name.starts_with("receiver_")
+            dir_entry.as_ref().unwrap().file_type().unwrap().is_file() && is_pcm(&file_name)
         })
         .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
         .collect();
@@ -135,7 +238,7 @@ pub fn send_all_pcm(endpoint: &String, node_number: u8) {
 
     let n_files: u32 = files.len() as u32;
 
-    let f_size = if let Some(f) = files.get(0) {
+    let f_size = if let Some(f) = files.first() {
         fs::File::open(f).unwrap().metadata().unwrap().len() as u32
     } else {
         0
@@ -143,7 +246,12 @@ pub fn send_all_pcm(endpoint: &String, node_number: u8) {
 
     println!("Sending {} files of {} bytes", n_files, f_size);
 
-    match TcpStream::connect(endpoint) {
+    let stream = TcpStream::connect(&endpoint.address).and_then(|stream| match &endpoint.tls {
+        Some(tls) => tls.connect(stream, &endpoint.address),
+        None => Ok(Stream::Plain(stream)),
+    });
+
+    match stream {
         Ok(mut stream) => {
             let _ = stream.write_u8(node_number);
             let _ = stream.write_u32::<BigEndian>(n_files);
@@ -153,10 +261,12 @@ pub fn send_all_pcm(endpoint: &String, node_number: u8) {
 
                 File::open(f).unwrap().read_to_end(&mut buff).unwrap();
 
-                stream.write_all(&*buff).unwrap();
+                stream.write_all(&buff).unwrap();
 
                 println!("Done! {} bytes", buff.len())
             }
+
+            let _ = stream.close();
         }
         Err(e) => {
             println!("Failed to connect: {}", e);
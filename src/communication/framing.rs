@@ -0,0 +1,238 @@
+//! Delimits the messages sent to the room partitioner server on the connection.
+//!
+//! # Framings
+//!
+//! - fixed: every message is padded with NUL bytes to [FIXED_SIZE] bytes. This is what the
+//!   current room partitioner reads, so it is the default. The messages are cut down to what it
+//!   knows, see [compat], and the longer ones are still refused
+//! - length: every message is preceded by its size, as a big endian u32, and by a content type
+//!   byte telling which encoding it uses. The size counts the content type byte
+//! - newline: every message is followed by a `\n`. The JSON messages never contain one
+//!
+//! Only the length framing can carry messages that are not JSON.
+//!
+//! # Content types
+//!
+//! - 1: JSON
+//! - 2: CBOR
+//! - 3: MessagePack
+
+use crate::communication::http_requests::MessageKind;
+use crate::config::{Encoding, Framing};
+use byteorder::{BigEndian, WriteBytesExt};
+use serde_json::{Map, Value};
+use std::io::{self, ErrorKind};
+
+/// The size of every message with the fixed framing
+pub const FIXED_SIZE: usize = 256;
+
+/// The fields of the node updates the current room partitioner reads
+const COMPAT_NODE_FIELDS: [&str; 7] = [
+    "nodeId",
+    "cores",
+    "threads",
+    "cpu",
+    "totalRam",
+    "usedRam",
+    "temperature",
+];
+
+/// The fields of the process updates the current room partitioner reads
+const COMPAT_PROCESS_FIELDS: [&str; 9] = [
+    "pid",
+    "nodeId",
+    "cpu",
+    "ram",
+    "progress",
+    "sendTime",
+    "receiveTime",
+    "delayTime",
+    "scatterTime",
+];
+
+/// The fields added to every message since the current room partitioner was written
+const COMPAT_EXTRA_FIELDS: [&str; 5] = ["schemaVersion", "runId", "seq", "sentAt", "ingestedAt"];
+
+/// Returns the content type byte of an encoding
+///
+/// # Arguments
+///
+/// - `encoding`: The encoding of the message
+pub fn content_type(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Json => 1,
+        Encoding::Cbor => 2,
+        Encoding::MessagePack => 3,
+    }
+}
+
+/// Returns the encoding of a content type byte
+///
+/// # Arguments
+///
+/// - `content_type`: The content type byte of the message
+pub fn encoding(content_type: u8) -> Option<Encoding> {
+    [Encoding::Json, Encoding::Cbor, Encoding::MessagePack]
+        .iter()
+        .copied()
+        .find(|e| self::content_type(*e) == content_type)
+}
+
+/// Returns whether a framing can carry messages with the given encoding
+///
+/// # Arguments
+///
+/// - `framing`: How the messages are delimited
+/// - `encoding`: The encoding of the messages
+pub fn supports(framing: Framing, encoding: Encoding) -> bool {
+    framing == Framing::Length || encoding == Encoding::Json
+}
+
+/// Cuts a message down to the fields the current room partitioner reads, so it fits in
+/// [FIXED_SIZE] bytes
+///
+/// The node and process updates keep the fields they had before the wire format was versioned,
+/// also inside the batches. The other messages lose the version, the ordering fields and
+/// `ingestedAt`.
+///
+/// # Arguments
+///
+/// - `kind`: What the message is about
+/// - `message`: The decoded message
+pub fn compat(kind: MessageKind, message: Value) -> Value {
+    match kind {
+        MessageKind::Node => keep(message, &COMPAT_NODE_FIELDS),
+        MessageKind::Process => keep(message, &COMPAT_PROCESS_FIELDS),
+        MessageKind::Batch => match message {
+            Value::Object(mut batch) => {
+                let node = batch.remove("node").unwrap_or(Value::Null);
+                let processes = match batch.remove("processes") {
+                    Some(Value::Array(p)) => p
+                        .into_iter()
+                        .map(|p| keep(p, &COMPAT_PROCESS_FIELDS))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+
+                let mut compat = Map::new();
+                compat.insert("node".to_owned(), keep(node, &COMPAT_NODE_FIELDS));
+                compat.insert("processes".to_owned(), Value::Array(processes));
+                Value::Object(compat)
+            }
+            other => other,
+        },
+        MessageKind::Event | MessageKind::Stats => match message {
+            Value::Object(mut m) => {
+                for field in COMPAT_EXTRA_FIELDS {
+                    m.remove(field);
+                }
+                Value::Object(m)
+            }
+            other => other,
+        },
+    }
+}
+
+/// Returns an object with only the given fields
+fn keep(message: Value, fields: &[&str]) -> Value {
+    match message {
+        Value::Object(m) => Value::Object(
+            m.into_iter()
+                .filter(|(k, _)| fields.contains(&k.as_str()))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Returns the bytes to write to the server for a message
+///
+/// Fails, without touching the connection, if the message cannot be sent with the framing.
+///
+/// # Arguments
+///
+/// - `framing`: How the messages are delimited
+/// - `content_type`: The content type byte of the message
+/// - `payload`: The message to send
+pub fn encode(framing: Framing, content_type: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if framing != Framing::Length && content_type != self::content_type(Encoding::Json) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "only the length framing can carry messages that are not JSON",
+        ));
+    }
+
+    match framing {
+        Framing::Fixed => {
+            if payload.len() > FIXED_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "message of {} bytes does not fit in {} bytes",
+                        payload.len(),
+                        FIXED_SIZE
+                    ),
+                ));
+            }
+
+            let mut frame = payload.to_vec();
+            frame.resize(FIXED_SIZE, 0);
+            Ok(frame)
+        }
+        Framing::Length => {
+            let mut frame = Vec::with_capacity(5 + payload.len());
+            frame.write_u32::<BigEndian>(payload.len() as u32 + 1)?;
+            frame.push(content_type);
+            frame.extend_from_slice(payload);
+            Ok(frame)
+        }
+        Framing::Newline => {
+            if payload.contains(&b'\n') {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "message contains a newline",
+                ));
+            }
+
+            let mut frame = Vec::with_capacity(payload.len() + 1);
+            frame.extend_from_slice(payload);
+            frame.push(b'\n');
+            Ok(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_every_framing() {
+        let json = content_type(Encoding::Json);
+        let long = vec![b'x'; 1000];
+
+        let fixed = encode(Framing::Fixed, json, b"{}").unwrap();
+        assert_eq!(fixed.len(), FIXED_SIZE);
+        assert_eq!(&fixed[..3], b"{}\0");
+        assert!(encode(Framing::Fixed, json, &long).is_err());
+
+        let length = encode(Framing::Length, json, &long).unwrap();
+        assert_eq!(&length[..5], &[0, 0, 3, 233, json]);
+        assert_eq!(&length[5..], &long[..]);
+
+        assert_eq!(encode(Framing::Newline, json, b"{}").unwrap(), b"{}\n");
+        assert!(encode(Framing::Newline, json, b"{\n}").is_err());
+    }
+
+    #[test]
+    fn only_the_length_framing_carries_binary_encodings() {
+        let cbor = content_type(Encoding::Cbor);
+
+        assert_eq!(
+            encode(Framing::Length, cbor, &[0xa0]).unwrap(),
+            [0, 0, 0, 2, cbor, 0xa0]
+        );
+        assert!(encode(Framing::Fixed, cbor, &[0xa0]).is_err());
+        assert!(!supports(Framing::Newline, Encoding::MessagePack));
+    }
+}
@@ -1,3 +1,495 @@
+//! The messages sent to the room partitioner server, and the sink that posts them when the server
+//! is a web service.
+//!
+//! Every message is an object built by serde from the type's fields, with a `schemaVersion`
+//! field added, and encoded as JSON, CBOR or MessagePack. The field names are the same in every
+//! encoding, are part of the wire format and must not change without bumping [SCHEMA_VERSION].
+//! Values that are not finite numbers, such as a NaN CPU usage, are sent as `null` in JSON.
+//!
+//! # Units
+//!
+//! - CPU usage (`cpu`): percentage, 0 to 100 per core
+//! - RAM and swap (`totalRam`, `usedRam`, `availableRam`, `usedSwap`, `totalSwap`, `ram`): KB
+//! - RAM growth (`ramGrowth`): KB per second
+//! - memory pressure (`memoryPressureSome`, `memoryPressureFull`): percentage of the time
+//! - temperature: ºC, one value per sensor
+//! - progress: percentage of the task completed
+//! - step times (`sendTime`, `receiveTime`, `delayTime`, `scatterTime`): forwarded unchanged from
+//!   the cluster program, in the unit it measures them in
+//! - timestamps (`sentAt`, `ingestedAt`): milliseconds since the UNIX epoch, from the node's clock
+//!
+//! # Ordering
+//!
+//! Every message given to a sink is stamped with:
+//!
+//! - `runId`: a random number picked when the monitor starts
+//! - `seq`: the position of the message among those given to the sink during the run, from 0.
+//!   Every sink counts on its own, after its filter and rate limit, so the receiver of a sink sees
+//!   every number unless a message was lost
+//! - `sentAt`: when the message was given to the sink
+//!
+//! The node and process updates also carry `ingestedAt`: when the message from the cluster
+//! program they are about was received, absent before the first one.
+//!
+//! The receiver of a sink keeps the highest `seq` seen for every `runId`. A message with a `seq`
+//! up to that one is a duplicate, which happens when a message is sent again after a lost
+//! connection or a failed request, and can be dropped. A message with a `seq` more than one past
+//! it means the messages in between were lost, such as when a queue was full. A new `runId`
+//! means the monitor restarted, while the messages of the previous run may still arrive from the
+//! spool. The `nodeId` is not part of the key: the cluster programs send their rank as the ID of
+//! the node, so it changes between the messages of a run. The sequences of two sinks are
+//! unrelated, so a receiver fed by several sinks keeps one highest `seq` per sink.
+//!
+//! With the fixed framing, the messages are not stamped, see
+//! [compat](crate::communication::framing::compat).
+
+use crate::communication::sink::Sink;
+use crate::config::{Encoding, HttpSettings};
+use crate::monitor::self_stats::{ConnectionState, SinkStats};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The version of the wire format, sent in every message as `schemaVersion`
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// What a message is about, which decides where it is posted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    /// A [NodeData](crate::monitor::stats::NodeData) update
+    Node,
+    /// A [ProcData](crate::monitor::stats::ProcData) update
+    Process,
+    /// An [UpdateBatch](crate::monitor::stats::UpdateBatch) with the node and several processes
+    Batch,
+    /// Something that happened on the node, such as an alert
+    Event,
+    /// The monitor's own metrics
+    Stats,
+}
+
+impl MessageKind {
+    /// Returns the byte identifying the kind in the queue of a TCP sink
+    pub fn code(self) -> u8 {
+        match self {
+            MessageKind::Node => 1,
+            MessageKind::Process => 2,
+            MessageKind::Batch => 3,
+            MessageKind::Event => 4,
+            MessageKind::Stats => 5,
+        }
+    }
+
+    /// Returns the kind identified by a byte from [MessageKind::code]
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(MessageKind::Node),
+            2 => Some(MessageKind::Process),
+            3 => Some(MessageKind::Batch),
+            4 => Some(MessageKind::Event),
+            5 => Some(MessageKind::Stats),
+            _ => None,
+        }
+    }
+}
+
 pub trait RequestSerializable {
-    fn serialize(&self) -> String;
+    /// What the message is about
+    fn kind(&self) -> MessageKind;
+
+    /// Encodes the message, with the version of the wire format and the ordering fields
+    ///
+    /// # Arguments
+    ///
+    /// - `encoding`: The encoding to use
+    /// - `stamp`: The ordering fields given by the sink the message is sent to, if any
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8>;
+
+    /// Encodes the message, with the version of the wire format
+    ///
+    /// # Arguments
+    ///
+    /// - `encoding`: The encoding to use
+    fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        self.encode_stamped(encoding, None)
+    }
+
+    /// Returns the message as JSON
+    fn serialize(&self) -> String {
+        String::from_utf8(self.encode(Encoding::Json)).unwrap()
+    }
+}
+
+/// The fields that let the receiver order the messages and find the lost ones, see
+/// [the module](self)
+///
+/// # Properties
+/// -`run_id`: Picked when the monitor starts
+/// -`seq`: The position of the message among those given to the sink
+/// -`sent_at`: When the message was given to the sink
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stamp {
+    pub run_id: u32,
+    pub seq: u64,
+    pub sent_at: u64,
+}
+
+/// A message with the version of the wire format
+#[derive(Serialize)]
+struct Versioned<'a, T: ?Sized> {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(flatten)]
+    stamp: Option<&'a Stamp>,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
+/// Encodes a message, adding the version of the wire format
+///
+/// # Arguments
+///
+/// - `message`: The message to encode
+/// - `encoding`: The encoding to use
+/// - `stamp`: The ordering fields, if any
+pub fn to_bytes<T: Serialize + ?Sized>(
+    message: &T,
+    encoding: Encoding,
+    stamp: Option<&Stamp>,
+) -> Vec<u8> {
+    let message = Versioned {
+        schema_version: SCHEMA_VERSION,
+        stamp,
+        message,
+    };
+
+    encode_raw(&message, encoding)
+}
+
+/// Encodes a value as is, without adding the version of the wire format
+///
+/// # Arguments
+///
+/// - `value`: The value to encode
+/// - `encoding`: The encoding to use
+pub fn encode_raw<T: Serialize + ?Sized>(value: &T, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => serde_json::to_vec(value).unwrap(),
+        Encoding::Cbor => {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(value, &mut buf).unwrap();
+            buf
+        }
+        // with the field names, so the messages look the same as in the other encodings
+        Encoding::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+    }
+}
+
+/// Returns the current time, as sent in the messages
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Decodes a message encoded by [to_bytes] or [encode_raw]
+///
+/// # Arguments
+///
+/// - `payload`: The encoded message
+/// - `encoding`: The encoding of the message
+pub fn decode_raw(payload: &[u8], encoding: Encoding) -> Result<Value, Box<dyn Error>> {
+    Ok(match encoding {
+        Encoding::Json => serde_json::from_slice(payload)?,
+        Encoding::Cbor => ciborium::de::from_reader(payload)?,
+        Encoding::MessagePack => rmp_serde::from_slice(payload)?,
+    })
+}
+
+/// Posts the messages to the endpoints of the room partitioner web service.
+///
+/// The messages are batched by a dedicated thread: a batch is posted once it holds
+/// [HttpSettings::batch_size] messages or its oldest message waited [HttpSettings::batch_ms].
+/// A batch is split where the [MessageKind] changes, and the parts are posted one after the other,
+/// so the messages reach the server in the order they were sent. Every request body is a JSON
+/// array of consecutive messages of the same kind. The connection is kept alive between requests.
+///
+/// While the server is failing, up to [HttpSettings::max_pending] messages wait in the queue and
+/// the new ones are dropped.
+#[derive(Clone)]
+pub struct HttpSink {
+    tx: SyncSender<(MessageKind, Vec<u8>)>,
+    stats: Arc<SinkStats>,
+}
+
+impl HttpSink {
+    /// Starts the thread that posts the messages
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: The endpoints, timeouts, retries and batching of the requests
+    /// - `stats`: Where to report the state of the server
+    pub fn start(settings: HttpSettings, stats: Arc<SinkStats>) -> Self {
+        let (tx, rx) = sync_channel(settings.max_pending);
+
+        let client = Client::builder()
+            .connect_timeout(settings.connect_timeout())
+            .timeout(settings.timeout())
+            .pool_idle_timeout(None)
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        let writer = HttpWriter {
+            client,
+            settings,
+            stats: Arc::clone(&stats),
+        };
+        thread::spawn(move || writer.run(rx));
+
+        HttpSink { tx, stats }
+    }
+}
+
+impl Sink for HttpSink {
+    fn send(&self, request: &dyn RequestSerializable) {
+        // counted before it is sent, so the writer never takes it out of the count first
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+
+        let res = self
+            .tx
+            .try_send((request.kind(), request.encode(Encoding::Json)));
+        if let Err(e) = res {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            if let TrySendError::Full(_) = e {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The state of the thread posting to the server
+struct HttpWriter {
+    client: Client,
+    settings: HttpSettings,
+    stats: Arc<SinkStats>,
+}
+
+impl HttpWriter {
+    /// Posts the batches until every [HttpSink] handle is dropped
+    fn run(self, rx: Receiver<(MessageKind, Vec<u8>)>) {
+        while let Ok(first) = rx.recv() {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + self.settings.batch_interval();
+
+            while batch.len() < self.settings.batch_size.max(1) {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(left) {
+                    Ok(m) => batch.push(m),
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            self.stats
+                .queued
+                .fetch_sub(batch.len() as u64, Ordering::Relaxed);
+
+            // grouping the whole batch by kind would reorder it, and the server would take the
+            // messages arriving after a higher seq for duplicates
+            for run in batch.chunk_by(|a, b| a.0 == b.0) {
+                let messages: Vec<&[u8]> = run.iter().map(|(_, m)| &m[..]).collect();
+                self.post(run[0].0, &messages);
+            }
+        }
+    }
+
+    /// Posts a batch of messages, retrying with a doubling backoff. The batch is dropped once
+    /// every retry failed or if the server rejected it.
+    ///
+    /// # Arguments
+    ///
+    /// - `kind`: What the messages are about
+    /// - `messages`: The messages, each one encoded as JSON
+    fn post(&self, kind: MessageKind, messages: &[&[u8]]) {
+        let url = self.settings.base_url.trim_end_matches('/').to_owned() + self.path(kind);
+        let body = json_array(messages);
+        let mut backoff = self.settings.retry_backoff();
+
+        for attempt in 0..=self.settings.max_retries {
+            if attempt > 0 {
+                println!("Retrying in {:?}", backoff);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+
+            let res = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .body(body.clone())
+                .send();
+
+            match res {
+                Ok(r) if r.status().is_success() => {
+                    self.stats
+                        .sent
+                        .fetch_add(messages.len() as u64, Ordering::Relaxed);
+                    self.stats.set_state(ConnectionState::Connected);
+                    return;
+                }
+                Ok(r) if r.status().is_server_error() || r.status().as_u16() == 429 => {
+                    println!("Server at {} answered {}", url, r.status());
+                }
+                Ok(r) => {
+                    println!("Server at {} rejected the update: {}", url, r.status());
+                    break;
+                }
+                Err(e) => {
+                    println!("Failed to post to {}: {}", url, e);
+                    self.stats.set_state(ConnectionState::Disconnected);
+                }
+            }
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.stats
+            .dropped
+            .fetch_add(messages.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the path the messages of a kind are posted to
+    fn path(&self, kind: MessageKind) -> &str {
+        match kind {
+            MessageKind::Node => &self.settings.node_path,
+            MessageKind::Process => &self.settings.process_path,
+            MessageKind::Batch => &self.settings.batch_path,
+            MessageKind::Event => &self.settings.event_path,
+            MessageKind::Stats => &self.settings.stats_path,
+        }
+    }
+}
+
+/// Joins messages encoded as JSON into a JSON array
+fn json_array(messages: &[&[u8]]) -> Vec<u8> {
+    let mut body = vec![b'['];
+    for (i, m) in messages.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(m);
+    }
+    body.push(b']');
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+    use crate::monitor::source::FakeSource;
+    use crate::monitor::stats::NodeData;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    /// Answers the requests of a single connection with the given statuses, returning the path
+    /// and body of every request
+    fn serve(listener: TcpListener, statuses: Vec<u16>) -> Receiver<(String, String)> {
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+
+            for status in statuses {
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+
+                let path = request_line.split(' ').nth(1).unwrap().to_owned();
+                tx.send((path, String::from_utf8(body).unwrap())).unwrap();
+            }
+        });
+
+        rx
+    }
+
+    #[test]
+    fn posts_batches_and_retries_on_the_same_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = HttpSettings {
+            base_url: format!("http://{}", listener.local_addr().unwrap()),
+            retry_backoff_ms: 10,
+            batch_size: 2,
+            ..Default::default()
+        };
+        let requests = serve(listener, vec![503, 200]);
+        let stats = Arc::new(SinkStats::new("http"));
+
+        let sink = HttpSink::start(settings, Arc::clone(&stats));
+        sink.send(&ProcessEvent::new(1, 10, ProcessEventKind::Appeared));
+        sink.send(&ProcessEvent::new(1, 11, ProcessEventKind::Appeared));
+
+        let timeout = Duration::from_secs(5);
+        let (path, first) = requests.recv_timeout(timeout).unwrap();
+        let (_, second) = requests.recv_timeout(timeout).unwrap();
+        assert_eq!(path, "/events");
+        assert_eq!(first, second);
+
+        let body: serde_json::Value = serde_json::from_str(&second).unwrap();
+        assert_eq!(body[0]["pid"], 10);
+        assert_eq!(body[1]["pid"], 11);
+
+        // the counters are updated after the response is read
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(stats.failures.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.sent.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn posts_a_mixed_batch_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = HttpSettings {
+            base_url: format!("http://{}", listener.local_addr().unwrap()),
+            batch_size: 3,
+            batch_ms: 60_000,
+            ..Default::default()
+        };
+        let requests = serve(listener, vec![200, 200, 200]);
+
+        let sink = HttpSink::start(settings, Arc::new(SinkStats::new("http")));
+        let node = NodeData::new(&mut FakeSource::default());
+        sink.send(&ProcessEvent::new(1, 10, ProcessEventKind::Appeared));
+        sink.send(&node);
+        sink.send(&ProcessEvent::new(1, 10, ProcessEventKind::Finished));
+
+        let timeout = Duration::from_secs(5);
+        let paths: Vec<String> = (0..3)
+            .map(|_| requests.recv_timeout(timeout).unwrap().0)
+            .collect();
+        assert_eq!(paths, vec!["/events", "/node", "/events"]);
+    }
 }
@@ -0,0 +1,91 @@
+//! Writes the [points](crate::communication::points) in the InfluxDB line protocol, one line per
+//! point:
+//!
+//! ```text
+//! monitor_process,node=2,pid=1234,rank=2 cpu=75.5,ram=4096,progress=50.5 1650000000000000000
+//! ```
+//!
+//! The measurement is named after the prefix of the mapping and the point. Every field is a
+//! float, and the timestamp is in nanoseconds, the default precision of InfluxDB and Telegraf.
+
+use crate::communication::points::Point;
+use std::fmt::Write;
+
+/// Returns the lines of the points
+///
+/// # Arguments
+///
+/// - `points`: The points to write
+/// - `prefix`: Starts the name of every measurement
+pub fn format(points: &[Point], prefix: &str) -> Vec<u8> {
+    let mut out = String::new();
+
+    for p in points {
+        out += &escape(&format!("{}_{}", prefix, p.measurement), ", ");
+        for (name, value) in &p.tags {
+            // the line protocol does not allow empty tag values
+            if !value.is_empty() {
+                let _ = write!(out, ",{}={}", escape(name, ",= "), escape(value, ",= "));
+            }
+        }
+
+        for (i, f) in p.fields.iter().enumerate() {
+            let separator = if i == 0 { ' ' } else { ',' };
+            let _ = write!(out, "{}{}={}", separator, escape(&f.name, ",= "), f.value);
+        }
+        let _ = writeln!(out, " {}", p.timestamp_ms * 1_000_000);
+    }
+
+    out.into_bytes()
+}
+
+/// Escapes the special characters of a name or tag value with a backslash
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::points::Field;
+
+    #[test]
+    fn writes_the_line_protocol() {
+        let point = Point {
+            measurement: "node",
+            tags: vec![
+                ("node".to_owned(), "2".to_owned()),
+                ("lab room".to_owned(), "a,b=c".to_owned()),
+                ("empty".to_owned(), String::new()),
+            ]
+            .into_iter()
+            .collect(),
+            fields: vec![
+                Field {
+                    name: "cpu".to_owned(),
+                    value: 12.5,
+                    timer: false,
+                },
+                Field {
+                    name: "used ram".to_owned(),
+                    value: 2048.0,
+                    timer: false,
+                },
+            ],
+            timestamp_ms: 1_650_000_000_000,
+        };
+
+        assert_eq!(
+            String::from_utf8(format(&[point], "dwm")).unwrap(),
+            "dwm_node,lab\\ room=a\\,b\\=c,node=2 cpu=12.5,used\\ ram=2048 1650000000000000000\n"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Streams the updates to the browsers over a WebSocket, as they happen, so a single node can be
+//! watched live without going through the room partitioner server.
+//!
+//! # Protocol
+//!
+//! Every node and process update, batch and event the monitor sends upstream is also sent to
+//! every connected browser, as a text message holding the same JSON, without the ordering fields.
+//! A browser that falls too far behind misses the updates until it catches up.
+//!
+//! A browser can narrow what it receives by sending a subscription, as a text message. Every field
+//! can be omitted, so `{}` subscribes to every message again:
+//!
+//! ```json
+//! {"messages": ["process", "event"], "pids": [1234], "ranks": [0, 1]}
+//! ```
+//!
+//! - `messages`: Only these kinds of messages, as in [SinkSettings::messages](crate::config::SinkSettings::messages)
+//! - `pids`: Only the processes and events with one of these PIDs. The node and the events about
+//!   the whole node are still sent
+//! - `ranks`: Only the processes with one of these ranks
+//!
+//! The filters also apply to the processes of a batch. Every subscription is answered with
+//! `{"subscribed": {...}}`, or with `{"error": "..."}` when it cannot be read, in which case the
+//! previous one is kept.
+
+use crate::communication::http_requests::{MessageKind, RequestSerializable};
+use crate::communication::sink::Sink;
+use crate::config::Encoding;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+/// The number of updates waiting to be sent to a browser before the new ones are dropped
+const QUEUE_SIZE: usize = 1_000;
+
+/// How long to wait for an update before looking for a subscription from the browser
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for a browser to read an update before closing its connection
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a browser wants to receive
+///
+/// # Properties
+/// -`messages`: The kinds of messages to send. Empty for every kind
+/// -`pids`: The PIDs of the processes to send. Empty for every process
+/// -`ranks`: The ranks of the processes to send. Empty for every rank
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Subscription {
+    pub messages: Vec<MessageKind>,
+    pub pids: Vec<i32>,
+    pub ranks: Vec<u8>,
+}
+
+impl Subscription {
+    /// Returns the part of a message the browser wants, if any
+    ///
+    /// # Arguments
+    ///
+    /// - `kind`: What the message is about
+    /// - `message`: The message, as JSON
+    pub fn filter(&self, kind: MessageKind, message: &Value) -> Option<Value> {
+        if !self.messages.is_empty() && !self.messages.contains(&kind) {
+            return None;
+        }
+
+        match kind {
+            MessageKind::Process => Some(message.clone()).filter(|p| self.matches(p)),
+            MessageKind::Batch => {
+                let mut batch = message.clone();
+                if let Some(Value::Array(processes)) = batch.get_mut("processes") {
+                    processes.retain(|p| self.matches(p));
+                }
+                Some(batch)
+            }
+            MessageKind::Event => match message.get("pid").and_then(Value::as_i64) {
+                Some(pid)
+                    if !self.pids.is_empty() && !self.pids.iter().any(|p| *p as i64 == pid) =>
+                {
+                    None
+                }
+                _ => Some(message.clone()),
+            },
+            MessageKind::Node | MessageKind::Stats => Some(message.clone()),
+        }
+    }
+
+    /// Returns whether a process, as JSON, is asked for
+    fn matches(&self, p: &Value) -> bool {
+        let pid = p.get("pid").and_then(Value::as_i64);
+        let rank = p.get("nodeId").and_then(Value::as_i64);
+
+        (self.pids.is_empty() || self.pids.iter().any(|p| Some(*p as i64) == pid))
+            && (self.ranks.is_empty() || self.ranks.iter().any(|r| Some(*r as i64) == rank))
+    }
+}
+
+/// A message sent to every browser
+type Update = Arc<(MessageKind, Value)>;
+
+/// Sends the updates to every connected browser
+///
+/// # Properties
+/// -`browsers`: Where the updates for each connected browser wait to be sent
+#[derive(Default)]
+pub struct LiveStream {
+    browsers: Mutex<Vec<SyncSender<Update>>>,
+}
+
+impl LiveStream {
+    /// Starts the WebSocket server streaming the updates
+    ///
+    /// # Arguments
+    ///
+    /// - `ip`: The ip to start the server on
+    /// - `port`: The port to bind the server to
+    pub fn start(self: Arc<Self>, ip: String, port: u16) {
+        let listener = TcpListener::bind(ip + ":" + &*port.to_string()).unwrap();
+        println!("Streaming the updates on port {}", port);
+
+        self.serve(listener);
+    }
+
+    /// Accepts the browsers connecting to a listener
+    fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let live = Arc::clone(&self);
+                    thread::spawn(move || live.handle_browser(stream));
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Streams the updates to a browser until it disconnects
+    ///
+    /// # Arguments
+    ///
+    /// - `stream`: The browser's TCP stream
+    fn handle_browser(&self, stream: TcpStream) {
+        let peer = stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        let mut socket = match tungstenite::accept(stream) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to open a WebSocket with {}: {}", peer, e);
+                return;
+            }
+        };
+        // the updates are only read from the queue between two polls for a subscription
+        let timeouts = socket
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .and_then(|_| socket.get_ref().set_write_timeout(Some(WRITE_TIMEOUT)));
+        if let Err(e) = timeouts {
+            println!("Failed to stream the updates to {}: {}", peer, e);
+            return;
+        }
+
+        println!("Streaming the updates to {}", peer);
+        let (sender, updates) = sync_channel(QUEUE_SIZE);
+        self.browsers.lock().unwrap().push(sender);
+
+        if let Err(e) = stream_updates(&mut socket, &updates) {
+            println!("Stopped streaming the updates to {}: {}", peer, e);
+        }
+    }
+}
+
+impl Sink for LiveStream {
+    fn send(&self, request: &dyn RequestSerializable) {
+        let mut browsers = self.browsers.lock().unwrap();
+        if browsers.is_empty() {
+            return;
+        }
+
+        let message = serde_json::from_slice(&request.encode(Encoding::Json)).unwrap();
+        let update = Arc::new((request.kind(), message));
+        // the browsers that fell behind miss the update, the ones that left are forgotten
+        browsers.retain(|b| {
+            !matches!(
+                b.try_send(Arc::clone(&update)),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+}
+
+/// Sends the updates a browser subscribed to, and reads its subscriptions, until it disconnects
+///
+/// # Arguments
+///
+/// - `socket`: The browser's WebSocket
+/// - `updates`: Where the updates for the browser wait to be sent
+fn stream_updates(
+    socket: &mut WebSocket<TcpStream>,
+    updates: &Receiver<Update>,
+) -> Result<(), Box<dyn Error>> {
+    let mut subscription = Subscription::default();
+
+    loop {
+        match updates.recv_timeout(POLL_INTERVAL) {
+            Ok(update) => {
+                let mut next = Some(update);
+                while let Some(update) = next {
+                    if let Some(message) = subscription.filter(update.0, &update.1) {
+                        socket.send(Message::Text(message.to_string()))?;
+                    }
+                    next = updates.try_recv().ok();
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                let reply = match serde_json::from_str::<Subscription>(&text) {
+                    Ok(s) => {
+                        let reply = serde_json::json!({ "subscribed": s });
+                        subscription = s;
+                        reply
+                    }
+                    Err(e) => serde_json::json!({ "error": e.to_string() }),
+                };
+                socket.send(Message::Text(reply.to_string()))?;
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::stats::ProcData;
+
+    fn next(socket: &mut WebSocket<TcpStream>) -> Value {
+        loop {
+            if let Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn streams_the_updates_a_browser_subscribed_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let live = Arc::new(LiveStream::default());
+        let server = Arc::clone(&live);
+        thread::spawn(move || server.serve(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let (mut socket, _) = tungstenite::client(format!("ws://{}/", addr), stream).unwrap();
+        socket
+            .send(Message::Text(
+                r#"{"messages": ["process", "event"], "pids": [11]}"#.to_owned(),
+            ))
+            .unwrap();
+        assert_eq!(next(&mut socket)["subscribed"]["pids"][0], 11);
+
+        live.send(&ProcData::new(10, 0, &Snapshot::default()));
+        live.send(&ProcessEvent::new(0, 10, ProcessEventKind::Appeared));
+        live.send(&ProcData::new(11, 1, &Snapshot::default()));
+        live.send(&ProcessEvent::new(0, 11, ProcessEventKind::Finished));
+
+        let process = next(&mut socket);
+        assert_eq!(process["pid"], 11);
+        assert_eq!(process["schemaVersion"], 1);
+        assert_eq!(next(&mut socket)["event"], "processFinished");
+
+        socket
+            .send(Message::Text(r#"{"pid": 10}"#.to_owned()))
+            .unwrap();
+        assert!(next(&mut socket)["error"].is_string());
+    }
+}
@@ -0,0 +1,360 @@
+//! Publishes the updates to an MQTT broker, so any program on the lab network can follow the
+//! nodes by subscribing to their topics.
+//!
+//! # Topics
+//!
+//! With the default [MqttSettings](crate::config::MqttSettings), a node publishes under
+//! `meshotron/<node>/`:
+//!
+//! - `status`: `online` once connected. The broker publishes the last will, `offline`, when the
+//!   monitor stops without closing the connection
+//! - `node`: The node updates
+//! - `proc/<pid>`: The updates of each process. The processes of a batch are published one by
+//!   one, with the node, as if they were sent on their own
+//! - `events`: The events
+//! - `stats`: The monitor's own metrics
+//!
+//! Every message holds the same JSON as sent to the room partitioner server. The status, node
+//! and process messages are retained, so a client subscribing later gets the last state of every
+//! node and process at once. The retained update of a process is cleared when it disappears.
+
+use crate::communication::http_requests::{MessageKind, RequestSerializable};
+use crate::communication::sink::Sink;
+use crate::config::{Encoding, MqttSettings, SinkSettings};
+use crate::monitor::self_stats::{ConnectionState, SinkStats};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{Map, Value};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The number of messages waiting to be published before the new ones are dropped
+const QUEUE_SIZE: usize = 1_000;
+
+/// The time to wait before connecting to the broker again
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// The port of the broker when the address has none
+const DEFAULT_PORT: u16 = 1883;
+
+/// Publishes the updates to an MQTT broker
+///
+/// # Properties
+/// -`client`: Queues the messages for the thread connected to the broker
+/// -`stats`: Where the state of the sink is reported
+/// -`topic`: Starts every topic, as in `meshotron/<node>`
+/// -`qos`: The quality of service of the messages
+/// -`retain`: Whether the node and process updates are retained
+pub struct MqttSink {
+    client: Client,
+    stats: Arc<SinkStats>,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+}
+
+impl MqttSink {
+    /// Connects to the broker, in the background, and keeps connecting again whenever the
+    /// connection is lost
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: The sink, with the address of the broker
+    /// - `node_name`: The name of the node, see [NodeSettings](crate::config::NodeSettings)
+    /// - `stats`: Where to report the state of the sink
+    pub fn start(settings: &SinkSettings, node_name: String, stats: Arc<SinkStats>) -> Self {
+        let mqtt = &settings.mqtt;
+        let node = if mqtt.node.is_empty() {
+            node_name
+        } else {
+            mqtt.node.clone()
+        };
+        let topic = format!("{}/{}", mqtt.topic_prefix, node);
+        let status = format!("{}/status", topic);
+        let qos = qos(mqtt);
+
+        let address = settings.address.as_deref().unwrap_or("127.0.0.1:1883");
+        let broker = address.to_owned();
+        let (host, port) = split_address(address);
+        let client_id = if mqtt.client_id.is_empty() {
+            format!("monitor-{}", node)
+        } else {
+            mqtt.client_id.clone()
+        };
+
+        let mut options = MqttOptions::new(client_id, host, port);
+        options
+            .set_keep_alive(mqtt.keep_alive())
+            .set_last_will(LastWill::new(&status, "offline", qos, mqtt.retain));
+
+        let (client, mut connection) = Client::new(options, QUEUE_SIZE);
+        let online = client.clone();
+        let retain = mqtt.retain;
+        let connection_stats = Arc::clone(&stats);
+        stats.set_state(ConnectionState::Connecting);
+
+        thread::spawn(move || {
+            let stats = connection_stats;
+            // the connection is opened again by the next iteration after an error
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        println!("Connected to the MQTT broker at {}", broker);
+                        stats.connects.fetch_add(1, Ordering::Relaxed);
+                        stats.set_state(ConnectionState::Connected);
+                        if let Err(e) = online.try_publish(&status, qos, retain, "online") {
+                            println!("Failed to publish the status: {}", e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("Lost the MQTT broker at {}: {}", broker, e);
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        stats.set_state(ConnectionState::Disconnected);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        MqttSink {
+            client,
+            stats,
+            topic,
+            qos,
+            retain: mqtt.retain,
+        }
+    }
+
+    /// Queues a message to be published
+    ///
+    /// # Arguments
+    ///
+    /// - `topic`: Where to publish, after the prefix and the node
+    /// - `payload`: The message
+    /// - `retain`: Whether the broker keeps the message for the clients subscribing later
+    fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) {
+        let topic = format!("{}/{}", self.topic, topic);
+        match self.client.try_publish(topic, self.qos, retain, payload) {
+            Ok(_) => self.stats.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+impl Sink for MqttSink {
+    fn send(&self, request: &dyn RequestSerializable) {
+        let payload = request.encode(Encoding::Json);
+
+        match request.kind() {
+            MessageKind::Node => self.publish("node", payload, self.retain),
+            MessageKind::Process => {
+                let message: Value = serde_json::from_slice(&payload).unwrap();
+                self.publish(&process_topic(&message), payload, self.retain);
+            }
+            MessageKind::Batch => {
+                let message = serde_json::from_slice(&payload).unwrap();
+                for (topic, part) in split_batch(message) {
+                    self.publish(&topic, part.to_string().into_bytes(), self.retain);
+                }
+            }
+            MessageKind::Event => {
+                let message: Value = serde_json::from_slice(&payload).unwrap();
+                self.publish("events", payload, false);
+                if self.retain && message["event"] == "processDisappeared" {
+                    // an empty retained message clears the one kept by the broker
+                    self.publish(&process_topic(&message), Vec::new(), true);
+                }
+            }
+            MessageKind::Stats => self.publish("stats", payload, false),
+        }
+    }
+}
+
+/// Returns the topic of a process, after the prefix and the node
+fn process_topic(message: &Value) -> String {
+    format!("proc/{}", message["pid"])
+}
+
+/// Splits a batch into the node and each of its processes, each with the version and the
+/// ordering fields of the batch, along with its topic
+fn split_batch(mut batch: Map<String, Value>) -> Vec<(String, Value)> {
+    let node = batch.remove("node");
+    let processes = match batch.remove("processes") {
+        Some(Value::Array(processes)) => processes,
+        _ => Vec::new(),
+    };
+
+    let with_header = |part: Value| match part {
+        Value::Object(mut fields) => {
+            for (name, value) in &batch {
+                fields.insert(name.clone(), value.clone());
+            }
+            Value::Object(fields)
+        }
+        part => part,
+    };
+
+    node.into_iter()
+        .map(|n| ("node".to_owned(), with_header(n)))
+        .chain(
+            processes
+                .into_iter()
+                .map(|p| (process_topic(&p), with_header(p))),
+        )
+        .collect()
+}
+
+/// Returns the MQTT quality of service of the settings
+fn qos(settings: &MqttSettings) -> QoS {
+    match settings.qos {
+        0 => QoS::AtMostOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+/// Splits a `<host>:<port>` address, the port defaulting to 1883
+fn split_address(address: &str) -> (String, u16) {
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host.to_owned(), port),
+            Err(_) => {
+                println!("Invalid MQTT broker port {}, using {}", port, DEFAULT_PORT);
+                (host.to_owned(), DEFAULT_PORT)
+            }
+        },
+        None => (address.to_owned(), DEFAULT_PORT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MetricMapping, SinkKind};
+    use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::stats::ProcData;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::mpsc::{channel, Sender};
+
+    /// Reads an MQTT packet, returning its first byte and its body
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    /// Accepts a single client, as a broker would, and reports what it publishes as the topic,
+    /// whether it is retained and the payload
+    fn broker(
+        listener: TcpListener,
+        connect: Sender<Vec<u8>>,
+        published: Sender<(String, bool, String)>,
+    ) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let (_, body) = read_packet(&mut stream);
+        connect.send(body).unwrap();
+        stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+
+        loop {
+            let (header, body) = read_packet(&mut stream);
+            match header >> 4 {
+                3 => {
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                    let mut payload = 2 + topic_len;
+                    if (header >> 1) & 3 > 0 {
+                        stream
+                            .write_all(&[0x40, 2, body[payload], body[payload + 1]])
+                            .unwrap();
+                        payload += 2;
+                    }
+                    let payload = String::from_utf8(body[payload..].to_vec()).unwrap();
+                    published.send((topic, header & 1 == 1, payload)).unwrap();
+                }
+                12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn publishes_retained_updates_to_a_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (connect_tx, connect) = channel();
+        let (published_tx, published) = channel();
+        thread::spawn(move || broker(listener, connect_tx, published_tx));
+
+        let settings = SinkSettings {
+            name: "broker".to_owned(),
+            kind: SinkKind::Mqtt,
+            address: Some(address),
+            url: None,
+            path: PathBuf::new(),
+            messages: Vec::new(),
+            max_per_second: 0.0,
+            mapping: MetricMapping::default(),
+            mqtt: MqttSettings::default(),
+        };
+        let stats = Arc::new(SinkStats::new("broker"));
+        let sink = MqttSink::start(&settings, "pi-1".to_owned(), Arc::clone(&stats));
+
+        sink.send(&ProcData::new(10, 2, &Snapshot::default()));
+        sink.send(&ProcessEvent::new(2, 10, ProcessEventKind::Disappeared));
+
+        let connect = String::from_utf8_lossy(&connect.recv().unwrap()).into_owned();
+        assert!(connect.contains("monitor-pi-1"));
+        assert!(connect.contains("meshotron/pi-1/status"));
+        assert!(connect.contains("offline"));
+
+        let timeout = Duration::from_secs(5);
+        let mut messages: Vec<_> = (0..4)
+            .map(|_| published.recv_timeout(timeout).unwrap())
+            .collect();
+        let status = messages
+            .iter()
+            .position(|m| m.0 == "meshotron/pi-1/status")
+            .unwrap();
+        assert_eq!(
+            messages.remove(status),
+            (
+                "meshotron/pi-1/status".to_owned(),
+                true,
+                "online".to_owned()
+            )
+        );
+
+        let process: Value = serde_json::from_str(&messages[0].2).unwrap();
+        assert_eq!(messages[0].0, "meshotron/pi-1/proc/10");
+        assert!(messages[0].1);
+        assert_eq!(process["nodeId"], 2);
+        assert_eq!(messages[1].0, "meshotron/pi-1/events");
+        assert!(!messages[1].1);
+        // the retained update of the process that disappeared is cleared
+        assert_eq!(
+            messages[2],
+            ("meshotron/pi-1/proc/10".to_owned(), true, String::new())
+        );
+        assert_eq!(stats.connects.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.sent.load(Ordering::Relaxed), 3);
+    }
+}
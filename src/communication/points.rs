@@ -0,0 +1,425 @@
+//! Sends the node and process updates as points to the monitoring tools of our collaborators,
+//! InfluxDB or Telegraf in the [line protocol](crate::communication::influx), and StatsD servers
+//! as [gauges and timers](crate::communication::statsd).
+//!
+//! A node update becomes a `node` point, a process update a `process` point, and a batch one of
+//! each. The events and the monitor's own metrics are not sent. The tags and fields of the points
+//! are picked by the [MetricMapping] of the sink. The metrics without a value, such as the memory
+//! pressure when the kernel does not report it, and the values that are not finite numbers, are
+//! left out.
+
+use crate::communication::http_requests::{now_ms, MessageKind, RequestSerializable};
+use crate::communication::sink::Sink;
+use crate::communication::{influx, statsd};
+use crate::config::{Encoding, MetricMapping, SinkKind, SinkSettings, TagSource};
+use crate::monitor::self_stats::{ConnectionState, SinkStats};
+use crate::monitor::stats::{NodeData, ProcData};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The number of updates waiting to be delivered before the new ones are dropped
+const QUEUE_SIZE: usize = 10_000;
+
+/// The measurement, tags and fields of a node or of a process at a point in time
+///
+/// # Properties
+/// -`measurement`: `node` or `process`
+/// -`tags`: The tags, by name
+/// -`fields`: The fields, in the order of the mapping
+/// -`timestamp_ms`: When the update was sent, in milliseconds since the UNIX epoch
+#[derive(Debug, PartialEq)]
+pub struct Point {
+    pub measurement: &'static str,
+    pub tags: BTreeMap<String, String>,
+    pub fields: Vec<Field>,
+    pub timestamp_ms: u64,
+}
+
+/// A value of a point
+///
+/// # Properties
+/// -`name`: The name of the field
+/// -`value`: The value of the metric
+/// -`timer`: Whether the metric is a duration, sent as a StatsD timer
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub value: f32,
+    pub timer: bool,
+}
+
+/// A batch, as it is sent
+#[derive(Deserialize)]
+struct Batch {
+    node: NodeData,
+    processes: Vec<ProcData>,
+}
+
+/// Returns the points of an update, none for the events and the monitor's own metrics
+///
+/// # Arguments
+///
+/// - `request`: The update
+/// - `mapping`: The tags and fields to send
+/// - `node_name`: The value of the [TagSource::Node] tags
+/// - `timestamp_ms`: When the update was sent
+pub fn points(
+    request: &dyn RequestSerializable,
+    mapping: &MetricMapping,
+    node_name: &str,
+    timestamp_ms: u64,
+) -> Vec<Point> {
+    let json = request.encode(Encoding::Json);
+    let node = |n: &NodeData| node_point(n, mapping, node_name, timestamp_ms);
+    let process = |p: &ProcData| process_point(p, mapping, node_name, timestamp_ms);
+
+    let points = match request.kind() {
+        MessageKind::Node => serde_json::from_slice(&json).map(|n| vec![node(&n)]),
+        MessageKind::Process => serde_json::from_slice(&json).map(|p| vec![process(&p)]),
+        MessageKind::Batch => serde_json::from_slice(&json).map(|b: Batch| {
+            std::iter::once(node(&b.node))
+                .chain(b.processes.iter().map(process))
+                .collect()
+        }),
+        MessageKind::Event | MessageKind::Stats => Ok(Vec::new()),
+    };
+
+    match points {
+        // a point needs at least one field
+        Ok(points) => points
+            .into_iter()
+            .filter(|p| !p.fields.is_empty())
+            .collect(),
+        Err(e) => {
+            println!("Failed to read an update as points: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the point of the node
+fn node_point(
+    node: &NodeData,
+    mapping: &MetricMapping,
+    node_name: &str,
+    timestamp_ms: u64,
+) -> Point {
+    Point {
+        measurement: "node",
+        tags: tags(mapping, |source| match source {
+            TagSource::Node => Some(node_name.to_owned()),
+            TagSource::Pid | TagSource::Rank => None,
+        }),
+        fields: fields(&mapping.node_fields, &NodeData::METRICS, mapping, |m| {
+            node.metric(m)
+        }),
+        timestamp_ms,
+    }
+}
+
+/// Returns the point of a process
+fn process_point(
+    p: &ProcData,
+    mapping: &MetricMapping,
+    node_name: &str,
+    timestamp_ms: u64,
+) -> Point {
+    Point {
+        measurement: "process",
+        // the cluster program sends its rank as the ID of the node
+        tags: tags(mapping, |source| match source {
+            TagSource::Node => Some(node_name.to_owned()),
+            TagSource::Rank => Some(p.get_node_id().to_string()),
+            TagSource::Pid => Some(p.get_pid().to_string()),
+        }),
+        fields: fields(&mapping.process_fields, &ProcData::METRICS, mapping, |m| {
+            p.metric(m)
+        }),
+        timestamp_ms,
+    }
+}
+
+/// Returns the static tags and the tags with a value on the point
+fn tags<F: Fn(TagSource) -> Option<String>>(
+    mapping: &MetricMapping,
+    value: F,
+) -> BTreeMap<String, String> {
+    let mut tags = mapping.static_tags.clone();
+    for (name, source) in &mapping.tags {
+        if let Some(v) = value(*source) {
+            tags.insert(name.clone(), v);
+        }
+    }
+
+    tags
+}
+
+/// Returns the fields with a finite value
+///
+/// # Arguments
+///
+/// - `mapped`: The fields of the mapping, by name, with the metric they are read from
+/// - `every`: The name of every metric, used when the mapping has no field
+/// - `mapping`: Which metrics are timers
+/// - `metric`: Reads a metric
+fn fields<F: Fn(&str) -> Option<f32>>(
+    mapped: &BTreeMap<String, String>,
+    every: &[&str],
+    mapping: &MetricMapping,
+    metric: F,
+) -> Vec<Field> {
+    let pairs: Vec<(&str, &str)> = if mapped.is_empty() {
+        every.iter().map(|m| (*m, *m)).collect()
+    } else {
+        mapped
+            .iter()
+            .map(|(n, m)| (n.as_str(), m.as_str()))
+            .collect()
+    };
+
+    pairs
+        .into_iter()
+        .filter_map(|(name, m)| {
+            let value = metric(m).filter(|v| v.is_finite())?;
+
+            Some(Field {
+                name: name.to_owned(),
+                value,
+                timer: mapping.timers.iter().any(|t| t == m),
+            })
+        })
+        .collect()
+}
+
+/// Where the points are delivered
+enum Destination {
+    Udp(String),
+    Http(String),
+}
+
+/// Delivers the points of the updates to an InfluxDB, Telegraf or StatsD server
+///
+/// # Properties
+/// -`tx`: Where the formatted points wait to be delivered
+/// -`stats`: The counters of the sink
+/// -`kind`: Whether the points are formatted for InfluxDB or for StatsD
+/// -`mapping`: The tags and fields to send
+/// -`node_name`: The value of the [TagSource::Node] tags
+pub struct PointSink {
+    tx: SyncSender<Vec<u8>>,
+    stats: Arc<SinkStats>,
+    kind: SinkKind,
+    mapping: MetricMapping,
+    node_name: String,
+}
+
+impl PointSink {
+    /// Starts the thread that delivers the points
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: The sink, of the InfluxDB or StatsD kind
+    /// - `node_name`: The name of the node, see [NodeSettings](crate::config::NodeSettings)
+    /// - `stats`: Where to report the state of the sink
+    pub fn start(settings: &SinkSettings, node_name: String, stats: Arc<SinkStats>) -> Self {
+        let destination = match (settings.kind, &settings.url, &settings.address) {
+            (SinkKind::Influx, Some(url), _) => Destination::Http(url.clone()),
+            (_, _, Some(address)) => Destination::Udp(address.clone()),
+            (SinkKind::Influx, None, None) => Destination::Udp("127.0.0.1:8089".to_owned()),
+            (_, _, None) => Destination::Udp("127.0.0.1:8125".to_owned()),
+        };
+
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let writer_stats = Arc::clone(&stats);
+        thread::spawn(move || deliver(destination, rx, &writer_stats));
+
+        PointSink {
+            tx,
+            stats,
+            kind: settings.kind,
+            mapping: settings.mapping.clone(),
+            node_name,
+        }
+    }
+}
+
+impl Sink for PointSink {
+    fn send(&self, request: &dyn RequestSerializable) {
+        let points = points(request, &self.mapping, &self.node_name, now_ms());
+        if points.is_empty() {
+            return;
+        }
+
+        let payload = match self.kind {
+            SinkKind::Statsd => statsd::format(&points, &self.mapping.prefix),
+            _ => influx::format(&points, &self.mapping.prefix),
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(payload) {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Delivers the points until every [PointSink] handle is dropped
+///
+/// # Arguments
+///
+/// - `destination`: Where to deliver the points
+/// - `rx`: Where the formatted points of every update come from
+/// - `stats`: Where to report the state of the sink
+fn deliver(destination: Destination, rx: Receiver<Vec<u8>>, stats: &SinkStats) {
+    match destination {
+        Destination::Udp(address) => {
+            let socket = match connect_udp(&address) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Failed to send to {}: {}", address, e);
+                    stats.failures.fetch_add(1, Ordering::Relaxed);
+                    // keep draining, so the updates are counted as dropped
+                    for _ in rx {
+                        stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return;
+                }
+            };
+            stats.set_state(ConnectionState::Connected);
+
+            // every update is sent in its own datagram
+            for payload in rx {
+                match socket.send(&payload) {
+                    Ok(_) => {
+                        stats.sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        println!("Failed to send to {}: {}", address, e);
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        Destination::Http(url) => {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap();
+
+            // the updates that waited are posted together
+            while let Ok(first) = rx.recv() {
+                let payloads: Vec<Vec<u8>> = std::iter::once(first).chain(rx.try_iter()).collect();
+                let count = payloads.len() as u64;
+
+                let res = client
+                    .post(&url)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(payloads.concat())
+                    .send();
+
+                match res {
+                    Ok(r) if r.status().is_success() => {
+                        stats.sent.fetch_add(count, Ordering::Relaxed);
+                        stats.set_state(ConnectionState::Connected);
+                        continue;
+                    }
+                    Ok(r) => println!("Server at {} rejected the points: {}", url, r.status()),
+                    Err(e) => {
+                        println!("Failed to post to {}: {}", url, e);
+                        stats.set_state(ConnectionState::Disconnected);
+                    }
+                }
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                stats.dropped.fetch_add(count, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Opens a UDP socket sending to an address
+fn connect_udp(address: &str) -> io::Result<UdpSocket> {
+    let target = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    socket.connect(target)?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SinkSettings;
+    use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+    use crate::monitor::sampler::Snapshot;
+    use std::path::PathBuf;
+
+    #[test]
+    fn sends_the_mapped_points_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut mapping = MetricMapping::default();
+        mapping
+            .static_tags
+            .insert("lab".to_owned(), "acoustics".to_owned());
+        mapping
+            .process_fields
+            .insert("done".to_owned(), "progress".to_owned());
+        mapping
+            .process_fields
+            .insert("send".to_owned(), "send_t".to_owned());
+
+        let settings = SinkSettings {
+            name: "telegraf".to_owned(),
+            kind: SinkKind::Influx,
+            address: Some(server.local_addr().unwrap().to_string()),
+            url: None,
+            path: PathBuf::new(),
+            messages: Vec::new(),
+            max_per_second: 0.0,
+            mapping,
+            mqtt: Default::default(),
+        };
+        let stats = Arc::new(SinkStats::new("telegraf"));
+        let sink = PointSink::start(&settings, "pi-1".to_owned(), Arc::clone(&stats));
+
+        let mut snapshot = Snapshot::default();
+        snapshot.processes.insert(10, Default::default());
+        let mut p = ProcData::new(10, 2, &snapshot);
+        p.update(50.0, 1.5, 0.0, 0.0, 0.0, &snapshot);
+        sink.send(&ProcessEvent::new(2, 10, ProcessEventKind::Appeared));
+        sink.send(&p);
+
+        let mut buf = [0; 1024];
+        let size = server.recv(&mut buf).unwrap();
+        let line = String::from_utf8_lossy(&buf[..size]);
+        // the event has no point
+        assert!(
+            line.starts_with(
+                "monitor_process,lab=acoustics,node=pi-1,pid=10,rank=2 done=50,send=1.5 "
+            ),
+            "{}",
+            line
+        );
+
+        let point = &points(&p, &settings.mapping, "pi-1", 0)[0];
+        assert_eq!(point.fields[1].name, "send");
+        assert!(point.fields[1].timer);
+    }
+}
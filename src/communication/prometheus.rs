@@ -0,0 +1,407 @@
+//! Renders the state of the node and of the processes, and the monitor's own counters, in the
+//! Prometheus text exposition format, served on `GET /metrics` by the
+//! [API](crate::communication::rest).
+//!
+//! # Labels
+//!
+//! - `node`: the name of the node, see [NodeSettings](crate::config::NodeSettings)
+//! - `job`: the name of the processes the monitor tracks. Prometheus renames it `exported_job`
+//!   unless `honor_labels` is set in the scrape configuration
+//! - `pid`, `rank`: the PID of a process and the rank the cluster program reported for it
+//! - `sink`: the name of a sink
+//! - `server`: `ingest` or `file`, the server that turned a client away
+//!
+//! The sizes are in KB, as in the pushed messages.
+
+use crate::monitor::history::History;
+use crate::monitor::self_stats::{ConnectionState, MonitorStats, SinkStats};
+use crate::monitor::stats::{NodeData, ProcData};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Reads a counter of a sink
+type Counter = fn(&SinkStats) -> &AtomicU64;
+
+/// The content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The node metrics, with the name of the [NodeData::metric] they are read from
+const NODE_GAUGES: [(&str, &str, &str); 12] = [
+    (
+        "cores",
+        "monitor_node_cores",
+        "The number of cores of the node",
+    ),
+    (
+        "threads",
+        "monitor_node_threads",
+        "The number of threads of the cluster program",
+    ),
+    (
+        "cpu_usage",
+        "monitor_node_cpu_usage_percent",
+        "The percentage of the CPU used in total",
+    ),
+    (
+        "frequency",
+        "monitor_node_cpu_frequency_megahertz",
+        "The average frequency of the CPU cores",
+    ),
+    (
+        "total_ram",
+        "monitor_node_ram_total_kilobytes",
+        "The RAM of the node",
+    ),
+    (
+        "used_ram",
+        "monitor_node_ram_used_kilobytes",
+        "The RAM used in the node",
+    ),
+    (
+        "available_ram",
+        "monitor_node_ram_available_kilobytes",
+        "The RAM that can still be allocated without swapping",
+    ),
+    (
+        "used_swap",
+        "monitor_node_swap_used_kilobytes",
+        "The swap used in the node",
+    ),
+    (
+        "total_swap",
+        "monitor_node_swap_total_kilobytes",
+        "The swap of the node",
+    ),
+    (
+        "pressure_some",
+        "monitor_node_memory_pressure_some_percent",
+        "The percentage of the time some task stalled on memory",
+    ),
+    (
+        "pressure_full",
+        "monitor_node_memory_pressure_full_percent",
+        "The percentage of the time every task stalled on memory",
+    ),
+    (
+        "oom_risk",
+        "monitor_node_oom_risk",
+        "The risk of running out of memory, from 0 (low) to 3 (critical)",
+    ),
+];
+
+/// The process metrics, with the name of the [ProcData::metric] they are read from
+const PROCESS_GAUGES: [(&str, &str, &str); 4] = [
+    (
+        "cpu",
+        "monitor_process_cpu_usage_percent",
+        "The CPU usage of the process",
+    ),
+    (
+        "ram",
+        "monitor_process_ram_kilobytes",
+        "The RAM consumed by the process",
+    ),
+    (
+        "ram_growth",
+        "monitor_process_ram_growth_kilobytes_per_second",
+        "How fast the RAM consumed by the process grows",
+    ),
+    (
+        "progress",
+        "monitor_process_progress_percent",
+        "The percentage of the task completed",
+    ),
+];
+
+/// The step times, with the name of the [ProcData::metric] they are read from
+const STEPS: [(&str, &str); 4] = [
+    ("send_t", "send"),
+    ("recv_t", "receive"),
+    ("delay_t", "delay"),
+    ("scatter_t", "scatter"),
+];
+
+/// Returns every metric in the text exposition format
+///
+/// # Arguments
+///
+/// - `job`: The name of the processes the monitor tracks
+/// - `node_name`: The name of the node in the labels, see
+///   [NodeSettings](crate::config::NodeSettings)
+/// - `node`: The node's object
+/// - `procs`: The tracked processes
+/// - `history`: The recent samples, to estimate when the processes finish
+/// - `stats`: The monitor's own counters
+/// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+pub fn render(
+    job: &str,
+    node_name: &str,
+    node: &NodeData,
+    procs: &HashMap<i32, ProcData>,
+    history: &History,
+    stats: &MonitorStats,
+    now_ms: u64,
+) -> String {
+    let mut out = String::new();
+    let node_labels = format!("node=\"{}\",job=\"{}\"", escape(node_name), escape(job));
+
+    for (metric, name, help) in NODE_GAUGES.iter() {
+        family(
+            &mut out,
+            name,
+            "gauge",
+            help,
+            node.metric(metric)
+                .map(|v| (node_labels.clone(), format_value(v))),
+        );
+    }
+    family(
+        &mut out,
+        "monitor_node_temperature_celsius",
+        "gauge",
+        "The temperature of each sensor",
+        node.get_temperature().iter().enumerate().map(|(i, t)| {
+            (
+                format!("{},sensor=\"{}\"", node_labels, i),
+                format_value(*t),
+            )
+        }),
+    );
+
+    let mut processes: Vec<&ProcData> = procs.values().collect();
+    processes.sort_unstable_by_key(|p| p.get_pid());
+    let labels: Vec<String> = processes
+        .iter()
+        .map(|p| {
+            format!(
+                "{},pid=\"{}\",rank=\"{}\"",
+                node_labels,
+                p.get_pid(),
+                p.get_node_id()
+            )
+        })
+        .collect();
+
+    for (metric, name, help) in PROCESS_GAUGES.iter() {
+        family(
+            &mut out,
+            name,
+            "gauge",
+            help,
+            processes
+                .iter()
+                .zip(&labels)
+                .filter_map(|(p, l)| Some((l.clone(), format_value(p.metric(metric)?)))),
+        );
+    }
+    family(
+        &mut out,
+        "monitor_process_step_time",
+        "gauge",
+        "The time of the latest step, in the unit the cluster program measures it in",
+        processes.iter().zip(&labels).flat_map(|(p, l)| {
+            STEPS.iter().filter_map(move |(metric, step)| {
+                let value = format_value(p.metric(metric)?);
+                Some((format!("{},step=\"{}\"", l, step), value))
+            })
+        }),
+    );
+    family(
+        &mut out,
+        "monitor_process_eta_seconds",
+        "gauge",
+        "The estimated time until the process completes its task",
+        processes
+            .iter()
+            .zip(&labels)
+            .filter_map(|(p, l)| Some((l.clone(), format_value(history.eta_s(p, now_ms)?)))),
+    );
+
+    let sinks = stats.sinks();
+    let sink_labels: Vec<String> = sinks
+        .iter()
+        .map(|s| format!("{},sink=\"{}\"", node_labels, escape(&s.name)))
+        .collect();
+    let counters: [(&str, &str, Counter); 5] = [
+        ("monitor_sink_sent_total", "The messages delivered", |s| {
+            &s.sent
+        }),
+        (
+            "monitor_sink_dropped_total",
+            "The messages dropped because the queue was full or delivery failed",
+            |s| &s.dropped,
+        ),
+        (
+            "monitor_sink_rate_limited_total",
+            "The messages dropped by the rate limit of the sink",
+            |s| &s.rate_limited,
+        ),
+        (
+            "monitor_sink_failures_total",
+            "The failed connection attempts, broken connections and failed requests",
+            |s| &s.failures,
+        ),
+        (
+            "monitor_sink_connects_total",
+            "The times the connection to the server was established",
+            |s| &s.connects,
+        ),
+    ];
+    for (name, help, counter) in counters.iter() {
+        family(
+            &mut out,
+            name,
+            "counter",
+            help,
+            sinks
+                .iter()
+                .zip(&sink_labels)
+                .map(|(s, l)| (l.clone(), counter(s).load(Ordering::Relaxed).to_string())),
+        );
+    }
+    family(
+        &mut out,
+        "monitor_sink_queued",
+        "gauge",
+        "The messages waiting to be sent",
+        sinks
+            .iter()
+            .zip(&sink_labels)
+            .map(|(s, l)| (l.clone(), s.queued.load(Ordering::Relaxed).to_string())),
+    );
+    family(
+        &mut out,
+        "monitor_sink_up",
+        "gauge",
+        "Whether the sink is connected to its server",
+        sinks.iter().zip(&sink_labels).map(|(s, l)| {
+            let up = s.state() == ConnectionState::Connected;
+            (l.clone(), if up { "1" } else { "0" }.to_owned())
+        }),
+    );
+    family(
+        &mut out,
+        "monitor_auth_rejected_total",
+        "counter",
+        "The clients turned away for a missing or wrong token",
+        [
+            ("ingest", &stats.rejected_ingest),
+            ("file", &stats.rejected_files),
+        ]
+        .iter()
+        .map(|(server, counter)| {
+            (
+                format!("{},server=\"{}\"", node_labels, server),
+                counter.load(Ordering::Relaxed).to_string(),
+            )
+        }),
+    );
+
+    out
+}
+
+/// Writes a metric family, if it has any sample
+///
+/// # Arguments
+///
+/// - `out`: Where to write to
+/// - `name`: The name of the metric
+/// - `kind`: `gauge` or `counter`
+/// - `help`: What the metric is
+/// - `samples`: The labels and value of every sample
+fn family<I: IntoIterator<Item = (String, String)>>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: I,
+) {
+    let mut samples = samples.into_iter().peekable();
+    if samples.peek().is_none() {
+        return;
+    }
+
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Writes a value as Prometheus expects it
+fn format_value(value: f32) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value == f32::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f32::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HistorySettings;
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+
+    #[test]
+    fn renders_the_text_exposition_format() {
+        let node = NodeData::new(&mut FakeSource::new(NodeInfo {
+            cores: 4,
+            ..Default::default()
+        }));
+        let mut snapshot = Snapshot::default();
+        snapshot.processes.insert(10, Default::default());
+
+        let mut procs = HashMap::new();
+        procs.insert(10, ProcData::new(10, 3, &snapshot));
+        let mut history = History::new(HistorySettings { max_age_s: 60 });
+        history.record(0, &node, &procs);
+        procs
+            .get_mut(&10)
+            .unwrap()
+            .update(50.0, 1.5, 0.0, 0.0, 0.0, &snapshot);
+
+        let stats = MonitorStats::default();
+        stats
+            .register_sink("partitioner")
+            .sent
+            .store(7, Ordering::Relaxed);
+
+        let text = render("d\"wm", "pi-1", &node, &procs, &history, &stats, 10_000);
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"# TYPE monitor_node_cores gauge"));
+        assert!(lines.contains(&"monitor_node_cores{node=\"pi-1\",job=\"d\\\"wm\"} 4"));
+        assert!(lines.contains(
+            &"monitor_process_progress_percent{node=\"pi-1\",job=\"d\\\"wm\",pid=\"10\",rank=\"3\"} 50"
+        ));
+        assert!(lines.contains(
+            &"monitor_process_step_time{node=\"pi-1\",job=\"d\\\"wm\",pid=\"10\",rank=\"3\",step=\"send\"} 1.5"
+        ));
+        // 50% in 10 seconds, so 10 more seconds
+        assert!(lines.contains(
+            &"monitor_process_eta_seconds{node=\"pi-1\",job=\"d\\\"wm\",pid=\"10\",rank=\"3\"} 10"
+        ));
+        assert!(lines.contains(
+            &"monitor_sink_sent_total{node=\"pi-1\",job=\"d\\\"wm\",sink=\"partitioner\"} 7"
+        ));
+        assert!(lines
+            .contains(&"monitor_sink_up{node=\"pi-1\",job=\"d\\\"wm\",sink=\"partitioner\"} 0"));
+        // no memory pressure reported
+        assert!(!text.contains("monitor_node_memory_pressure_some_percent"));
+    }
+}
@@ -0,0 +1,271 @@
+//! Answers the tools that ask the monitor for its current state, rather than waiting for the
+//! updates it pushes.
+//!
+//! # Protocol
+//!
+//! Every request is a line of JSON, answered by a line of JSON on the same connection. A
+//! connection can carry any number of requests. Every field of the request can be omitted, so an
+//! empty line asks for the node and every process, without history:
+//!
+//! ```json
+//! {"pids": [1234], "ranks": [0, 1], "historyS": 60}
+//! ```
+//!
+//! - `pids`: Only the processes with one of these PIDs
+//! - `ranks`: Only the processes with one of these ranks, which the cluster program sends as the
+//!   `nodeId` of its messages
+//! - `historyS`: Also returns the samples of the last seconds, up to the
+//!   [HistorySettings::max_age_s](crate::config::HistorySettings::max_age_s) kept
+//!
+//! The answer holds the node and the processes as they are pushed, with the same fields:
+//!
+//! ```json
+//! {"schemaVersion": 1, "takenAt": 1650000000000, "node": {...}, "processes": [{...}],
+//!  "history": [{"takenAt": 1649999999000, "node": {...}, "processes": [{...}]}]}
+//! ```
+//!
+//! A request that cannot be read is answered with `{"schemaVersion": 1, "error": "..."}`.
+
+use crate::communication::http_requests::{now_ms, to_bytes};
+use crate::config::Encoding;
+use crate::monitor::history::History;
+use crate::monitor::stats::{NodeData, ProcData};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// What a tool asks for
+///
+/// # Properties
+/// -`pids`: The PIDs of the processes to return. Empty for every process
+/// -`ranks`: The ranks of the processes to return. Empty for every rank
+/// -`history_s`: How many seconds of history to return
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Query {
+    pub pids: Vec<i32>,
+    pub ranks: Vec<u8>,
+    pub history_s: u64,
+}
+
+impl Query {
+    /// Returns whether a process is asked for
+    pub fn matches(&self, p: &ProcData) -> bool {
+        (self.pids.is_empty() || self.pids.contains(&p.get_pid()))
+            && (self.ranks.is_empty() || self.ranks.contains(&p.get_node_id()))
+    }
+}
+
+/// The state of the node and of the processes asked for, at a point in time
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct State<'a> {
+    taken_at: u64,
+    node: &'a NodeData,
+    processes: Vec<&'a ProcData>,
+}
+
+/// The answer to a query
+#[derive(Serialize)]
+struct Answer<'a> {
+    #[serde(flatten)]
+    now: State<'a>,
+    history: Vec<State<'a>>,
+}
+
+/// The answer to a request that could not be read
+#[derive(Serialize)]
+struct Error {
+    error: String,
+}
+
+/// Starts the server answering the queries
+///
+/// # Arguments
+///
+/// - `ip`: The ip to start the server on
+/// - `port`: The port to bind the server to
+/// - `procs`: The processes' object's list
+/// - `node`: The node's object
+/// - `history`: The recent samples
+pub fn start_query_server(
+    ip: String,
+    port: u16,
+    procs: Arc<Mutex<HashMap<i32, ProcData>>>,
+    node: Arc<Mutex<NodeData>>,
+    history: Arc<Mutex<History>>,
+) {
+    let listener = TcpListener::bind(ip + ":" + &*port.to_string()).unwrap();
+    println!("Answering queries on port {}", port);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let procs_handle = Arc::clone(&procs);
+                let node_handle = Arc::clone(&node);
+                let history_handle = Arc::clone(&history);
+
+                thread::spawn(move || {
+                    handle_queries(stream, &procs_handle, &node_handle, &history_handle)
+                });
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+            }
+        }
+    }
+}
+
+/// Answers the queries of a connection until it is closed
+///
+/// # Arguments
+///
+/// - `stream`: The tool's TCP stream
+/// - `procs`: The processes' object's list
+/// - `node`: The node's object
+/// - `history`: The recent samples
+fn handle_queries(
+    stream: TcpStream,
+    procs: &Mutex<HashMap<i32, ProcData>>,
+    node: &Mutex<NodeData>,
+    history: &Mutex<History>,
+) {
+    let mut out = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Failed to answer queries: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+
+        let mut reply = match parse(&line) {
+            Ok(query) => answer(
+                &query,
+                &procs.lock().unwrap(),
+                &node.lock().unwrap(),
+                &history.lock().unwrap(),
+                now_ms(),
+            ),
+            Err(e) => to_bytes(
+                &Error {
+                    error: e.to_string(),
+                },
+                Encoding::Json,
+                None,
+            ),
+        };
+        reply.push(b'\n');
+
+        if out.write_all(&reply).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads a request, an empty line being a query with every field omitted
+fn parse(line: &str) -> serde_json::Result<Query> {
+    if line.trim().is_empty() {
+        Ok(Query::default())
+    } else {
+        serde_json::from_str(line)
+    }
+}
+
+/// Returns the answer to a query, as JSON
+///
+/// # Arguments
+///
+/// - `query`: What the tool asks for
+/// - `procs`: The tracked processes
+/// - `node`: The node's object
+/// - `history`: The recent samples
+/// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+pub fn answer(
+    query: &Query,
+    procs: &HashMap<i32, ProcData>,
+    node: &NodeData,
+    history: &History,
+    now_ms: u64,
+) -> Vec<u8> {
+    let mut processes: Vec<&ProcData> = procs.values().filter(|p| query.matches(p)).collect();
+    processes.sort_unstable_by_key(|p| p.get_pid());
+
+    let history = if query.history_s == 0 {
+        Vec::new()
+    } else {
+        history
+            .since(now_ms.saturating_sub(query.history_s.saturating_mul(1000)))
+            .map(|s| State {
+                taken_at: s.taken_at_ms,
+                node: &s.node,
+                processes: s.processes.iter().filter(|p| query.matches(p)).collect(),
+            })
+            .collect()
+    };
+
+    let answer = Answer {
+        now: State {
+            taken_at: now_ms,
+            node,
+            processes,
+        },
+        history,
+    };
+
+    to_bytes(&answer, Encoding::Json, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HistorySettings;
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+    use serde_json::Value;
+
+    #[test]
+    fn answers_with_the_processes_and_history_asked_for() {
+        let node = NodeData::new(&mut FakeSource::new(NodeInfo::default()));
+        let mut procs = HashMap::new();
+        procs.insert(10, ProcData::new(10, 0, &Snapshot::default()));
+        procs.insert(11, ProcData::new(11, 1, &Snapshot::default()));
+        procs.insert(12, ProcData::new(12, 1, &Snapshot::default()));
+
+        let mut history = History::new(HistorySettings { max_age_s: 60 });
+        history.record(1_000, &node, &procs);
+        history.record(9_000, &node, &procs);
+
+        let everything: Value = serde_json::from_slice(&answer(
+            &parse("").unwrap(),
+            &procs,
+            &node,
+            &history,
+            10_000,
+        ))
+        .unwrap();
+        assert_eq!(everything["schemaVersion"], 1);
+        assert_eq!(everything["takenAt"], 10_000);
+        assert_eq!(everything["processes"].as_array().unwrap().len(), 3);
+        assert!(everything["history"].as_array().unwrap().is_empty());
+
+        let query = parse(r#"{"ranks": [1], "pids": [10, 12], "historyS": 5}"#).unwrap();
+        let filtered: Value =
+            serde_json::from_slice(&answer(&query, &procs, &node, &history, 10_000)).unwrap();
+        assert_eq!(filtered["processes"][0]["pid"], 12);
+        assert_eq!(filtered["processes"].as_array().unwrap().len(), 1);
+        assert_eq!(filtered["history"][0]["takenAt"], 9_000);
+        assert_eq!(filtered["history"][0]["processes"][0]["pid"], 12);
+        assert_eq!(filtered["history"].as_array().unwrap().len(), 1);
+
+        assert!(parse(r#"{"pid": 10}"#).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! A small HTTP API to inspect a running monitor, such as with `curl`.
+//!
+//! # Endpoints
+//!
+//! - `GET /node`: the node, as pushed to the server, with the average `frequency` of the CPU
+//!   cores in MHz when it can be read
+//! - `GET /processes`: every tracked process, as pushed to the server, sorted by PID
+//! - `GET /processes/{pid}`: a single process, or 404 if it is not tracked
+//! - `GET /history?metric=…&pid=…&since_s=…`: the values a metric took in the kept history.
+//!   The metric is named as in the alert rules, an unknown one answers 400. Without `pid`, the
+//!   metric is read on the node. `since_s` limits the history to the last seconds
+//! - `GET /health`: whether the sampler is still running, with the counters of every sink.
+//!   Answers 503 when the last sample is too old
+//! - `GET /files`: the room description files received and the pcm files waiting to be sent
+//! - `GET /metrics`: every metric, for Prometheus, see [prometheus](crate::communication::prometheus)
+//!
+//! Every other answer is JSON. The errors are `{"error": "..."}`.
+
+use crate::communication::file_transfer::list_files;
+use crate::communication::http_requests::{now_ms, to_bytes, RequestSerializable};
+use crate::communication::prometheus;
+use crate::config::Encoding;
+use crate::monitor::history::History;
+use crate::monitor::sampler::Snapshot;
+use crate::monitor::self_stats::MonitorStats;
+use crate::monitor::stats::{NodeData, ProcData};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+
+/// The shared state of the monitor the API reads from
+///
+/// # Properties
+/// -`procs`: The processes' object's list
+/// -`node`: The node's object
+/// -`snapshot`: The latest sample of the system usage
+/// -`history`: The recent samples
+/// -`stats`: The monitor's own counters
+/// -`sample_interval`: The time between two samples, to tell when the sampler stopped
+/// -`started_at_ms`: When the monitor started, in milliseconds since the UNIX epoch
+/// -`job`: The name of the processes the monitor tracks
+/// -`node_name`: The name of the node in the metrics
+#[derive(Clone)]
+pub struct ApiState {
+    pub procs: Arc<Mutex<HashMap<i32, ProcData>>>,
+    pub node: Arc<Mutex<NodeData>>,
+    pub snapshot: Arc<Mutex<Snapshot>>,
+    pub history: Arc<Mutex<History>>,
+    pub stats: Arc<MonitorStats>,
+    pub sample_interval: Duration,
+    pub started_at_ms: u64,
+    pub job: String,
+    pub node_name: String,
+}
+
+/// The answer to a request
+///
+/// # Properties
+/// -`status`: The HTTP status code
+/// -`content_type`: What the body is
+/// -`body`: The body
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn ok(body: Vec<u8>) -> Self {
+        Reply {
+            status: 200,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        Reply {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Reply::json(status, &serde_json::json!({ "error": message }))
+    }
+}
+
+/// Starts the HTTP server of the API
+///
+/// # Arguments
+///
+/// - `ip`: The ip to start the server on
+/// - `port`: The port to bind the server to
+/// - `state`: What the API reads from
+pub fn start_api_server(ip: String, port: u16, state: ApiState) {
+    let server = Server::http(ip + ":" + &*port.to_string()).unwrap();
+    println!("API listening on port {}", port);
+
+    for request in server.incoming_requests() {
+        let reply = if *request.method() == tiny_http::Method::Get {
+            handle(request.url(), &state, now_ms())
+        } else {
+            Reply::error(405, "only GET is supported")
+        };
+
+        let response = Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(Header::from_bytes("Content-Type", reply.content_type).unwrap());
+        if let Err(e) = request.respond(response) {
+            println!("Failed to answer an API request: {}", e);
+        }
+    }
+}
+
+/// Answers a GET request
+///
+/// # Arguments
+///
+/// - `url`: The path and query string of the request
+/// - `state`: What the API reads from
+/// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+pub fn handle(url: &str, state: &ApiState, now_ms: u64) -> Reply {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, params(query)),
+        None => (url, HashMap::new()),
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments[..] {
+        ["node"] => node(&state.node.lock().unwrap()),
+        ["processes"] => {
+            let procs = state.procs.lock().unwrap();
+            let mut processes: Vec<&ProcData> = procs.values().collect();
+            processes.sort_unstable_by_key(|p| p.get_pid());
+
+            Reply::ok(json_array(&processes))
+        }
+        ["processes", pid] => match pid.parse() {
+            Ok(pid) => match state.procs.lock().unwrap().get(&pid) {
+                Some(p) => Reply::ok(RequestSerializable::serialize(p).into()),
+                None => Reply::error(404, "the process is not tracked"),
+            },
+            Err(_) => Reply::error(400, "the PID is not a number"),
+        },
+        ["history"] => history(&query, &state.history.lock().unwrap(), now_ms),
+        ["health"] => health(state, now_ms),
+        ["files"] => Reply::json(200, &list_files()),
+        ["metrics"] => {
+            let procs = state.procs.lock().unwrap();
+            let node = state.node.lock().unwrap();
+            let text = prometheus::render(
+                &state.job,
+                &state.node_name,
+                &node,
+                &procs,
+                &state.history.lock().unwrap(),
+                &state.stats,
+                now_ms,
+            );
+
+            Reply {
+                status: 200,
+                content_type: prometheus::CONTENT_TYPE,
+                body: text.into_bytes(),
+            }
+        }
+        _ => Reply::error(404, "unknown endpoint"),
+    }
+}
+
+/// Answers `GET /node`
+fn node(node: &NodeData) -> Reply {
+    // the messages leave the frequency out
+    #[derive(Serialize)]
+    struct Node<'a> {
+        #[serde(flatten)]
+        node: &'a NodeData,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frequency: Option<u64>,
+    }
+
+    let node = Node {
+        node,
+        frequency: node.get_frequency(),
+    };
+    Reply::ok(to_bytes(&node, Encoding::Json, None))
+}
+
+/// Answers `GET /history`
+fn history(query: &HashMap<&str, &str>, history: &History, now_ms: u64) -> Reply {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Point {
+        taken_at: u64,
+        value: f32,
+    }
+
+    let metric = match query.get("metric") {
+        Some(m) => *m,
+        None => return Reply::error(400, "the metric is missing"),
+    };
+    let pid: Option<i32> = match query.get("pid").map(|p| p.parse()) {
+        Some(Ok(pid)) => Some(pid),
+        Some(Err(_)) => return Reply::error(400, "the PID is not a number"),
+        None => None,
+    };
+    let known = match pid {
+        Some(_) => ProcData::METRICS.contains(&metric),
+        None => NodeData::METRICS.contains(&metric),
+    };
+    if !known {
+        return Reply::error(400, "unknown metric");
+    }
+    let from_ms = match query.get("since_s").map(|s| s.parse::<u64>()) {
+        Some(Ok(s)) => now_ms.saturating_sub(s.saturating_mul(1000)),
+        Some(Err(_)) => return Reply::error(400, "since_s is not a number"),
+        None => 0,
+    };
+
+    let points: Vec<Point> = history
+        .since(from_ms)
+        .filter_map(|s| {
+            let value = match pid {
+                Some(pid) => s
+                    .processes
+                    .iter()
+                    .find(|p| p.get_pid() == pid)?
+                    .metric(metric),
+                None => s.node.metric(metric),
+            };
+
+            Some(Point {
+                taken_at: s.taken_at_ms,
+                value: value?,
+            })
+        })
+        .collect();
+
+    Reply::json(
+        200,
+        &serde_json::json!({ "metric": metric, "pid": pid, "points": points }),
+    )
+}
+
+/// Answers `GET /health`
+fn health(state: &ApiState, now_ms: u64) -> Reply {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Health<'a> {
+        status: &'static str,
+        uptime_s: u64,
+        last_sample_at: u64,
+        processes: usize,
+        #[serde(flatten)]
+        stats: &'a MonitorStats,
+    }
+
+    let last_sample_at = state.snapshot.lock().unwrap().taken_at_ms;
+    // a few samples may be late on a busy node
+    let stale =
+        now_ms.saturating_sub(last_sample_at) > 3 * state.sample_interval.as_millis() as u64;
+
+    Reply::json(
+        if stale { 503 } else { 200 },
+        &Health {
+            status: if stale { "stale" } else { "ok" },
+            uptime_s: now_ms.saturating_sub(state.started_at_ms) / 1000,
+            last_sample_at,
+            processes: state.procs.lock().unwrap().len(),
+            stats: &state.stats,
+        },
+    )
+}
+
+/// Reads the parameters of a query string
+fn params(query: &str) -> HashMap<&str, &str> {
+    query.split('&').filter_map(|p| p.split_once('=')).collect()
+}
+
+/// Returns a JSON array of messages, as they are pushed to the server
+fn json_array(messages: &[&ProcData]) -> Vec<u8> {
+    let mut body = b"[".to_vec();
+    for (i, m) in messages.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        body.extend(RequestSerializable::serialize(*m).into_bytes());
+    }
+    body.push(b']');
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HistorySettings;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+    use serde_json::Value;
+
+    fn state() -> ApiState {
+        let mut node = NodeData::new(&mut FakeSource::new(NodeInfo::default()));
+        node.update(&Snapshot {
+            frequency: Some(2_400),
+            ..Default::default()
+        });
+        let mut procs = HashMap::new();
+        procs.insert(10, ProcData::new(10, 0, &Snapshot::default()));
+        procs.insert(11, ProcData::new(11, 0, &Snapshot::default()));
+
+        let mut history = History::new(HistorySettings { max_age_s: 60 });
+        history.record(1_000, &node, &procs);
+        history.record(2_000, &node, &procs);
+
+        ApiState {
+            procs: Arc::new(Mutex::new(procs)),
+            node: Arc::new(Mutex::new(node)),
+            snapshot: Arc::new(Mutex::new(Snapshot {
+                taken_at_ms: 2_000,
+                ..Default::default()
+            })),
+            history: Arc::new(Mutex::new(history)),
+            stats: Arc::new(MonitorStats::default()),
+            sample_interval: Duration::from_secs(1),
+            started_at_ms: 0,
+            job: "dwm".to_owned(),
+            node_name: "pi-1".to_owned(),
+        }
+    }
+
+    fn get(url: &str, state: &ApiState, now_ms: u64) -> (u16, Value) {
+        let reply = handle(url, state, now_ms);
+        (reply.status, serde_json::from_slice(&reply.body).unwrap())
+    }
+
+    #[test]
+    fn serves_the_state_of_the_monitor() {
+        let state = state();
+
+        let (status, node) = get("/node", &state, 2_500);
+        assert_eq!(status, 200);
+        assert_eq!(node["schemaVersion"], 1);
+        assert_eq!(node["frequency"], 2_400);
+        let pushed = RequestSerializable::serialize(&*state.node.lock().unwrap());
+        assert!(!pushed.contains("frequency"), "{}", pushed);
+
+        let (_, processes) = get("/processes", &state, 2_500);
+        assert_eq!(processes[0]["pid"], 10);
+        assert_eq!(processes[1]["pid"], 11);
+
+        assert_eq!(get("/processes/11", &state, 2_500).1["pid"], 11);
+        assert_eq!(get("/processes/12", &state, 2_500).0, 404);
+        assert_eq!(get("/processes/x", &state, 2_500).0, 400);
+
+        let (_, history) = get("/history?metric=cores", &state, 2_500);
+        assert_eq!(history["points"].as_array().unwrap().len(), 2);
+        let (_, history) = get("/history?metric=progress&pid=10&since_s=1", &state, 2_500);
+        assert_eq!(history["points"][0]["takenAt"], 2_000);
+        assert_eq!(history["points"][0]["value"], 0.0);
+        assert_eq!(get("/history", &state, 2_500).0, 400);
+        assert_eq!(get("/history?metric=cpu_usge", &state, 2_500).0, 400);
+        assert_eq!(get("/history?metric=cores&pid=10", &state, 2_500).0, 400);
+        let since = format!("/history?metric=cores&since_s={}", u64::MAX);
+        assert_eq!(get(&since, &state, 2_500).1["points"][0]["takenAt"], 1_000);
+
+        let (status, health) = get("/health", &state, 2_500);
+        assert_eq!(status, 200);
+        assert_eq!(health["processes"], 2);
+        assert!(health["sinks"].as_array().unwrap().is_empty());
+        assert_eq!(get("/health", &state, 10_000).0, 503);
+
+        let metrics = handle("/metrics", &state, 2_500);
+        assert_eq!(metrics.content_type, prometheus::CONTENT_TYPE);
+        assert!(String::from_utf8(metrics.body).unwrap().contains(
+            "monitor_process_ram_kilobytes{node=\"pi-1\",job=\"dwm\",pid=\"11\",rank=\"0\"} 0"
+        ));
+
+        assert_eq!(get("/nothing", &state, 2_500).0, 404);
+    }
+}
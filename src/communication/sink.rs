@@ -0,0 +1,520 @@
+//! Where the updates for the room partitioner server go.
+//!
+//! The updates can go to any number of sinks, each one with its own filter on the kinds of
+//! messages and its own rate limit. Every sink delivers from its own thread and queue, so a slow
+//! or broken sink never holds up the others or the ingest of the progress updates.
+//!
+//! Every sink stamps the messages it accepts with its own sequence numbers, see
+//! [http_requests](crate::communication::http_requests).
+
+use crate::communication::http_requests::{
+    now_ms, HttpSink, MessageKind, RequestSerializable, Stamp,
+};
+use crate::communication::mqtt::MqttSink;
+use crate::communication::points::PointSink;
+use crate::communication::tls::Endpoint;
+use crate::communication::upstream::Upstream;
+use crate::config::{
+    Encoding, HttpSettings, MetricMapping, MqttSettings, Settings, SinkKind, SinkSettings,
+    SpoolSettings, Transport,
+};
+use crate::monitor::self_stats::{MonitorStats, SinkStats};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// The number of messages waiting to be written by a file sink before the new ones are dropped
+const FILE_QUEUE_SIZE: usize = 10_000;
+
+/// Delivers the messages to the server, without making the caller wait on the network
+pub trait Sink: Send + Sync {
+    /// Queues a message to be delivered to the server
+    ///
+    /// # Arguments
+    ///
+    /// - `request`: The `RequestSerializable` to be sent to the server
+    fn send(&self, request: &dyn RequestSerializable);
+}
+
+/// Starts every sink from the settings
+///
+/// # Arguments
+///
+/// - `server`: The room partitioner server. Its address is used by the TCP sinks without one, and
+///   every TCP sink encrypts its connection the same way
+/// - `settings`: The sinks and the tuning of each transport
+/// - `stats`: Where to report the state of every sink
+/// - `taps`: Where every message also goes as it is, without filter, rate limit or sequence
+///   number, such as the [live stream](crate::communication::live)
+pub fn start(
+    server: &Endpoint,
+    settings: &Settings,
+    stats: &MonitorStats,
+    taps: Vec<Arc<dyn Sink>>,
+) -> Arc<dyn Sink> {
+    let sinks = if settings.sinks.is_empty() {
+        vec![SinkSettings {
+            name: "partitioner".to_owned(),
+            kind: match settings.upstream.transport {
+                Transport::Tcp => SinkKind::Tcp,
+                Transport::Http => SinkKind::Http,
+            },
+            address: None,
+            url: None,
+            path: PathBuf::new(),
+            messages: Vec::new(),
+            max_per_second: 0.0,
+            mapping: MetricMapping::default(),
+            mqtt: MqttSettings::default(),
+        }]
+    } else {
+        settings.sinks.clone()
+    };
+
+    let run_id = rand::random();
+    println!("Starting run {}", run_id);
+    let node_name = settings.node.name();
+
+    let routes = sinks
+        .into_iter()
+        .map(|s| {
+            let sink_stats = stats.register_sink(&s.name);
+            let sink = start_sink(&s, server, &node_name, settings, Arc::clone(&sink_stats));
+            println!("Sending updates to the {:?} sink {}", s.kind, s.name);
+
+            Route::new(s, sink, sink_stats, run_id)
+        })
+        .collect();
+
+    Arc::new(FanOut { routes, taps })
+}
+
+/// Starts the thread of a single sink
+///
+/// # Arguments
+///
+/// - `sink`: The sink to start
+/// - `server`: The room partitioner server
+/// - `node_name`: The name of the node outside the room partitioner
+/// - `settings`: The tuning of each transport
+/// - `stats`: Where to report the state of the sink
+fn start_sink(
+    sink: &SinkSettings,
+    server: &Endpoint,
+    node_name: &str,
+    settings: &Settings,
+    stats: Arc<SinkStats>,
+) -> Box<dyn Sink> {
+    match sink.kind {
+        SinkKind::Tcp => Box::new(Upstream::start(
+            sink.address
+                .clone()
+                .unwrap_or_else(|| server.address.clone()),
+            settings.upstream.clone(),
+            // each TCP sink needs its own spool
+            SpoolSettings {
+                dir: settings.spool.dir.join(&sink.name),
+                ..settings.spool.clone()
+            },
+            server.tls.clone(),
+            stats,
+        )),
+        SinkKind::Http => Box::new(HttpSink::start(
+            HttpSettings {
+                base_url: sink
+                    .url
+                    .clone()
+                    .unwrap_or_else(|| settings.http.base_url.clone()),
+                ..settings.http.clone()
+            },
+            stats,
+        )),
+        SinkKind::File => Box::new(FileSink::start(sink.path.clone(), stats)),
+        SinkKind::Influx | SinkKind::Statsd => {
+            Box::new(PointSink::start(sink, node_name.to_owned(), stats))
+        }
+        SinkKind::Mqtt => Box::new(MqttSink::start(sink, node_name.to_owned(), stats)),
+    }
+}
+
+/// Sends every message to the sinks that accept it, and to every tap
+struct FanOut {
+    routes: Vec<Route>,
+    taps: Vec<Arc<dyn Sink>>,
+}
+
+impl Sink for FanOut {
+    fn send(&self, request: &dyn RequestSerializable) {
+        for route in &self.routes {
+            route.send(request);
+        }
+        for tap in &self.taps {
+            tap.send(request);
+        }
+    }
+}
+
+/// A sink with its filter and rate limit
+///
+/// # Properties
+/// -`messages`: The kinds of messages the sink receives. Empty for every kind
+/// -`limiter`: The rate limit of the node and process updates
+/// -`sink`: Where the accepted messages go
+/// -`stats`: The counters of the sink
+/// -`run_id`: Picked when the monitor started
+/// -`seq`: The number of messages the sink accepted. It stays locked while a message is given to
+///   the sink, so the messages reach it in the order of their `seq`
+struct Route {
+    messages: Vec<MessageKind>,
+    limiter: Mutex<RateLimiter>,
+    sink: Box<dyn Sink>,
+    stats: Arc<SinkStats>,
+    run_id: u32,
+    seq: Mutex<u64>,
+}
+
+impl Route {
+    fn new(
+        settings: SinkSettings,
+        sink: Box<dyn Sink>,
+        stats: Arc<SinkStats>,
+        run_id: u32,
+    ) -> Self {
+        Route {
+            messages: settings.messages,
+            limiter: Mutex::new(RateLimiter::new(settings.max_per_second)),
+            sink,
+            stats,
+            run_id,
+            seq: Mutex::new(0),
+        }
+    }
+
+    fn send(&self, request: &dyn RequestSerializable) {
+        let kind = request.kind();
+
+        if !self.messages.is_empty() && !self.messages.contains(&kind) {
+            return;
+        }
+
+        let limited = matches!(
+            kind,
+            MessageKind::Node | MessageKind::Process | MessageKind::Batch
+        );
+        if limited && !self.limiter.lock().unwrap().allow(Instant::now()) {
+            self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut seq = self.seq.lock().unwrap();
+        let stamp = Stamp {
+            run_id: self.run_id,
+            seq: *seq,
+            sent_at: now_ms(),
+        };
+        *seq += 1;
+        self.sink.send(&Stamped {
+            request,
+            stamp: &stamp,
+        });
+    }
+}
+
+/// A message with the ordering fields given by a sink
+struct Stamped<'a> {
+    request: &'a dyn RequestSerializable,
+    stamp: &'a Stamp,
+}
+
+impl RequestSerializable for Stamped<'_> {
+    fn kind(&self) -> MessageKind {
+        self.request.kind()
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, _: Option<&Stamp>) -> Vec<u8> {
+        self.request.encode_stamped(encoding, Some(self.stamp))
+    }
+}
+
+/// A token bucket allowing a number of messages per second, with bursts of up to one second
+///
+/// # Properties
+/// -`rate`: The messages allowed per second. 0 for no limit
+/// -`tokens`: The messages that can be sent right now
+/// -`last`: When the tokens were last refilled
+struct RateLimiter {
+    rate: f32,
+    tokens: f32,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f32) -> Self {
+        RateLimiter {
+            rate,
+            tokens: rate.max(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    /// Returns whether a message can be sent now, and takes a token if it can
+    fn allow(&mut self, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Appends the messages to a local file, one JSON message per line
+struct FileSink {
+    tx: SyncSender<Vec<u8>>,
+    stats: Arc<SinkStats>,
+}
+
+impl FileSink {
+    /// Starts the thread that writes to the file
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The file to append to, created if needed
+    /// - `stats`: Where to report the state of the sink
+    fn start(path: PathBuf, stats: Arc<SinkStats>) -> Self {
+        let (tx, rx) = sync_channel(FILE_QUEUE_SIZE);
+
+        let writer_stats = Arc::clone(&stats);
+        thread::spawn(move || write_file(path, rx, &writer_stats));
+
+        FileSink { tx, stats }
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&self, request: &dyn RequestSerializable) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(request.encode(Encoding::Json)) {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes the messages to the file until every [FileSink] handle is dropped
+///
+/// # Arguments
+///
+/// - `path`: The file to append to
+/// - `rx`: Where the messages come from
+/// - `stats`: Where to report the state of the sink
+fn write_file(path: PathBuf, rx: Receiver<Vec<u8>>, stats: &SinkStats) {
+    let file = match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Failed to open {}: {}", path.display(), e);
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+            // keep draining, so the messages are counted as dropped
+            for _ in rx {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+    };
+    let mut out = BufWriter::new(file);
+
+    while let Ok(first) = rx.recv() {
+        let mut written = 0;
+        let mut res = Ok(());
+
+        for m in std::iter::once(first).chain(rx.try_iter()) {
+            res = res
+                .and_then(|_| out.write_all(&m))
+                .and_then(|_| out.write_all(b"\n"));
+            written += 1;
+        }
+        res = res.and_then(|_| out.flush());
+
+        match res {
+            Ok(()) => {
+                stats.sent.fetch_add(written, Ordering::Relaxed);
+            }
+            Err(e) => {
+                println!("Failed to write to {}: {}", path.display(), e);
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                stats.dropped.fetch_add(written, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Remembers the messages it receives, for the tests of the code sending them
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<Recorded>>>);
+
+/// A message received by a [Recorder]
+///
+/// # Properties
+/// -`kind`: The kind of the message
+/// -`json`: The message, as JSON
+/// -`at`: When the message was received
+#[cfg(test)]
+#[derive(Clone)]
+pub struct Recorded {
+    pub kind: MessageKind,
+    pub json: serde_json::Value,
+    pub at: Instant,
+}
+
+#[cfg(test)]
+impl Recorder {
+    /// Returns the messages received so far, oldest first
+    pub fn messages(&self) -> Vec<Recorded> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Returns the kinds of the messages received so far, oldest first
+    pub fn kinds(&self) -> Vec<MessageKind> {
+        self.0.lock().unwrap().iter().map(|m| m.kind).collect()
+    }
+}
+
+#[cfg(test)]
+impl Sink for Recorder {
+    fn send(&self, request: &dyn RequestSerializable) {
+        let json = serde_json::from_str(&request.serialize()).unwrap();
+        self.0.lock().unwrap().push(Recorded {
+            kind: request.kind(),
+            json,
+            at: Instant::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::stats::ProcData;
+    use std::time::Duration;
+
+    fn route(messages: Vec<MessageKind>, max_per_second: f32, sink: &Recorder) -> Route {
+        let settings = SinkSettings {
+            name: "test".to_owned(),
+            kind: SinkKind::File,
+            address: None,
+            url: None,
+            path: PathBuf::new(),
+            messages,
+            max_per_second,
+            mapping: MetricMapping::default(),
+            mqtt: MqttSettings::default(),
+        };
+
+        Route::new(
+            settings,
+            Box::new(sink.clone()),
+            Arc::new(SinkStats::new("test")),
+            7,
+        )
+    }
+
+    #[test]
+    fn every_sink_gets_the_messages_it_accepts() {
+        let all = Recorder::default();
+        let events = Recorder::default();
+        let limited = Recorder::default();
+        let tap = Recorder::default();
+        let fan_out = FanOut {
+            routes: vec![
+                route(Vec::new(), 0.0, &all),
+                route(vec![MessageKind::Event], 0.0, &events),
+                route(Vec::new(), 1.0, &limited),
+            ],
+            taps: vec![Arc::new(tap.clone())],
+        };
+
+        let p = ProcData::new(10, 0, &Snapshot::default());
+        let e = ProcessEvent::new(0, 10, ProcessEventKind::Appeared);
+        for _ in 0..3 {
+            fan_out.send(&p);
+        }
+        fan_out.send(&e);
+
+        assert_eq!(all.kinds().len(), 4);
+        assert_eq!(events.kinds(), vec![MessageKind::Event]);
+        // a single process update fits in the limit, the event is never limited
+        assert_eq!(
+            limited.kinds(),
+            vec![MessageKind::Process, MessageKind::Event]
+        );
+        assert_eq!(
+            fan_out.routes[2].stats.rate_limited.load(Ordering::Relaxed),
+            2
+        );
+        assert_eq!(tap.kinds().len(), 4);
+    }
+
+    #[test]
+    fn every_sink_numbers_the_messages_it_accepts() {
+        let sink = Recorder::default();
+        let events = route(vec![MessageKind::Event], 0.0, &sink);
+
+        let p = ProcData::new(10, 0, &Snapshot::default());
+        let e = ProcessEvent::new(0, 10, ProcessEventKind::Appeared);
+        events.send(&e);
+        events.send(&p);
+        events.send(&e);
+
+        let sent = sink.messages();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].json["seq"], 0);
+        assert_eq!(sent[1].json["seq"], 1);
+        assert_eq!(sent[1].json["runId"], 7);
+        assert!(sent[1].json["sentAt"].as_u64().unwrap() > 0);
+        // only the messages given to a sink are stamped
+        assert!(!p.serialize().contains("\"seq\""));
+
+        // the messages sent at the same time reach the sink in the order of their seq
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        events.send(&e);
+                    }
+                });
+            }
+        });
+        let seqs: Vec<u64> = sink
+            .messages()
+            .iter()
+            .map(|m| m.json["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, (0..402).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn rate_limiter_refills_over_time() {
+        let mut limiter = RateLimiter::new(2.0);
+        let start = limiter.last;
+
+        assert!(limiter.allow(start));
+        assert!(limiter.allow(start));
+        assert!(!limiter.allow(start));
+        assert!(limiter.allow(start + Duration::from_millis(500)));
+        assert!(!limiter.allow(start + Duration::from_millis(600)));
+    }
+}
@@ -0,0 +1,486 @@
+//! A bounded, append-only log on disk holding the messages not yet delivered to the server.
+//!
+//! The log is split in segment files named after their index (`00000000000000000042.seg`).
+//! Messages are appended to the newest segment and read back in order from the oldest one.
+//! A segment is deleted once every message in it was delivered. The position of the next message
+//! to deliver is kept in the `cursor` file, so the messages survive a restart of the monitor.
+//!
+//! # Segment format
+//!
+//! Every segment starts with a header:
+//!
+//! 1. magic: the 4 bytes `MSPL`
+//! 1. version: u8, [VERSION]
+//!
+//! followed by the records:
+//!
+//! 1. length: u32, big endian, the size of the payload
+//! 1. time: u64, big endian, when the message was queued, in milliseconds since the UNIX epoch
+//! 1. payload: the message itself, as queued by the [Upstream](crate::communication::upstream)
+//!    writer: its content type byte, its kind code, then the encoded message
+//!
+//! The version changes whenever the layout of the records or of their payload does. The segments
+//! of another version, or written before the segments had a header, cannot be read back and are
+//! discarded when the log is opened, along with the cursor.
+
+use crate::communication::http_requests::now_ms;
+use crate::config::{DropPolicy, SpoolSettings};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The size of a record without its payload
+const HEADER_SIZE: u64 = 4 + 8;
+
+/// Starts every segment file
+const MAGIC: &[u8; 4] = b"MSPL";
+
+/// The version of the layout of the segments written by this monitor
+const VERSION: u8 = 1;
+
+/// The size of the header of a segment
+const SEGMENT_HEADER_SIZE: u64 = MAGIC.len() as u64 + 1;
+
+/// The name of the file holding the position of the next message to deliver
+const CURSOR_FILE: &str = "cursor";
+
+/// A segment of the log
+///
+/// # Properties
+/// -`index`: The position of the segment in the log
+/// -`size`: The size of the segment file, in bytes, header included
+/// -`records`: The number of records in the segment
+struct Segment {
+    index: u64,
+    size: u64,
+    records: u64,
+}
+
+/// The log of the messages waiting to be delivered
+///
+/// # Properties
+/// -`settings`: Where the log is kept and how big it can get
+/// -`segments`: Every segment of the log, oldest first. The last one is the one written to
+/// -`writer`: The file of the last segment
+/// -`read_offset`: The position of the next message to deliver in the first segment
+/// -`read_records`: The number of records of the first segment that were already delivered
+/// -`dropped`: The number of messages dropped because the log was full or they were too old
+pub struct Spool {
+    settings: SpoolSettings,
+    segments: Vec<Segment>,
+    writer: File,
+    read_offset: u64,
+    read_records: u64,
+    dropped: u64,
+}
+
+impl Spool {
+    /// Opens the log in the directory from the settings, creating it if needed
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: Where the log is kept and how big it can get
+    pub fn open(settings: SpoolSettings) -> io::Result<Self> {
+        fs::create_dir_all(&settings.dir)?;
+
+        let mut indexes: Vec<u64> = fs::read_dir(&settings.dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".seg")?.parse().ok()
+            })
+            .collect();
+        indexes.sort_unstable();
+
+        let mut segments = Vec::new();
+        let mut discarded = false;
+        for index in &indexes {
+            let path = segment_path(&settings.dir, *index);
+            match scan(&path)? {
+                Some((size, records)) => segments.push(Segment {
+                    index: *index,
+                    size,
+                    records,
+                }),
+                None => {
+                    println!(
+                        "Discarding {}, not a version {} spool segment",
+                        path.display(),
+                        VERSION
+                    );
+                    fs::remove_file(&path)?;
+                    discarded = true;
+                }
+            }
+        }
+        if discarded {
+            // the cursor may point into a discarded segment
+            match fs::remove_file(settings.dir.join(CURSOR_FILE)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        let writer = match segments.last() {
+            Some(last) => {
+                let writer = open_for_append(&segment_path(&settings.dir, last.index))?;
+                // drop anything after the last complete record, left by a crash in the middle of
+                // a write
+                writer.set_len(last.size)?;
+                writer
+            }
+            None => {
+                // the indexes of the discarded segments are not reused, so an old cursor can
+                // never point into a new segment
+                let index = indexes.last().map_or(0, |i| i + 1);
+                let writer = create_segment(&settings.dir, index)?;
+                segments.push(Segment {
+                    index,
+                    size: SEGMENT_HEADER_SIZE,
+                    records: 0,
+                });
+                writer
+            }
+        };
+
+        let mut spool = Spool {
+            settings,
+            segments,
+            writer,
+            read_offset: SEGMENT_HEADER_SIZE,
+            read_records: 0,
+            dropped: 0,
+        };
+        spool.restore_cursor()?;
+
+        Ok(spool)
+    }
+
+    /// The number of messages waiting to be delivered
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|s| s.records).sum::<u64>() - self.read_records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of messages dropped since the log was opened
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends a message to the log, applying the drop policy if the log is full
+    ///
+    /// Returns `false` if the message itself was dropped.
+    ///
+    /// # Arguments
+    ///
+    /// - `payload`: The message to append
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<bool> {
+        let record_size = HEADER_SIZE + payload.len() as u64;
+
+        while self.size() + record_size > self.settings.max_bytes {
+            match self.settings.drop_policy {
+                DropPolicy::Newest => {
+                    self.dropped += 1;
+                    return Ok(false);
+                }
+                DropPolicy::Oldest => {
+                    if self.segments.len() == 1 {
+                        if self.is_empty() {
+                            // the message does not fit even in an empty log
+                            self.dropped += 1;
+                            return Ok(false);
+                        }
+                        self.roll()?;
+                    }
+                    self.dropped += self.segments[0].records - self.read_records;
+                    self.remove_first()?;
+                }
+            }
+        }
+
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.write_u32::<BigEndian>(payload.len() as u32)?;
+        record.write_u64::<BigEndian>(now_ms())?;
+        record.extend_from_slice(payload);
+        self.writer.write_all(&record)?;
+
+        let last = self.segments.last_mut().unwrap();
+        last.size += record_size;
+        last.records += 1;
+
+        if last.size >= self.settings.segment_bytes {
+            self.roll()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the next message to deliver, without removing it from the log.
+    ///
+    /// Messages older than the retention time are dropped.
+    pub fn peek(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let first = &self.segments[0];
+
+            if self.read_offset >= first.size {
+                if self.segments.len() == 1 {
+                    return Ok(None);
+                }
+                self.remove_first()?;
+                continue;
+            }
+
+            let mut f = File::open(segment_path(&self.settings.dir, first.index))?;
+            f.seek(SeekFrom::Start(self.read_offset))?;
+            let len = f.read_u32::<BigEndian>()?;
+            let time = f.read_u64::<BigEndian>()?;
+
+            let max_age_ms = self.settings.max_age_s * 1000;
+            if max_age_ms > 0 && now_ms().saturating_sub(time) > max_age_ms {
+                self.dropped += 1;
+                self.ack()?;
+                continue;
+            }
+
+            let mut payload = vec![0; len as usize];
+            f.read_exact(&mut payload)?;
+
+            return Ok(Some(payload));
+        }
+    }
+
+    /// Removes the message returned by [Spool::peek] from the log
+    pub fn ack(&mut self) -> io::Result<()> {
+        let mut f = File::open(segment_path(&self.settings.dir, self.segments[0].index))?;
+        f.seek(SeekFrom::Start(self.read_offset))?;
+        let len = f.read_u32::<BigEndian>()?;
+
+        self.read_offset += HEADER_SIZE + len as u64;
+        self.read_records += 1;
+
+        if self.read_offset >= self.segments[0].size && self.segments.len() > 1 {
+            self.remove_first()?;
+        }
+
+        self.save_cursor()
+    }
+
+    /// The size of the log, in bytes
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum()
+    }
+
+    /// Starts writing to a new segment
+    fn roll(&mut self) -> io::Result<()> {
+        let index = self.segments.last().unwrap().index + 1;
+
+        self.writer = create_segment(&self.settings.dir, index)?;
+        self.segments.push(Segment {
+            index,
+            size: SEGMENT_HEADER_SIZE,
+            records: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Deletes the oldest segment and moves the cursor to the start of the next one
+    fn remove_first(&mut self) -> io::Result<()> {
+        let first = self.segments.remove(0);
+        fs::remove_file(segment_path(&self.settings.dir, first.index))?;
+
+        self.read_offset = SEGMENT_HEADER_SIZE;
+        self.read_records = 0;
+
+        self.save_cursor()
+    }
+
+    /// Writes the position of the next message to deliver to the cursor file
+    fn save_cursor(&self) -> io::Result<()> {
+        let mut cursor = Vec::with_capacity(24);
+        cursor.write_u64::<BigEndian>(self.segments[0].index)?;
+        cursor.write_u64::<BigEndian>(self.read_offset)?;
+        cursor.write_u64::<BigEndian>(self.read_records)?;
+
+        fs::write(self.settings.dir.join(CURSOR_FILE), cursor)
+    }
+
+    /// Reads the position of the next message to deliver from the cursor file, if any
+    fn restore_cursor(&mut self) -> io::Result<()> {
+        let mut f = match File::open(self.settings.dir.join(CURSOR_FILE)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let index = f.read_u64::<BigEndian>()?;
+        let offset = f.read_u64::<BigEndian>()?;
+        let records = f.read_u64::<BigEndian>()?;
+
+        // the segments before the cursor were delivered
+        while self.segments.len() > 1 && self.segments[0].index < index {
+            self.remove_first()?;
+        }
+        if self.segments[0].index == index
+            && (SEGMENT_HEADER_SIZE..=self.segments[0].size).contains(&offset)
+        {
+            self.read_offset = offset;
+            self.read_records = records.min(self.segments[0].records);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the path of a segment file
+fn segment_path(dir: &std::path::Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.seg", index))
+}
+
+fn open_for_append(path: &std::path::Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Creates a segment file holding only its header
+fn create_segment(dir: &std::path::Path, index: u64) -> io::Result<File> {
+    let mut writer = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(segment_path(dir, index))?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+
+    Ok(writer)
+}
+
+/// Returns the size of the header and of the complete records in a segment file, and the number
+/// of records. Returns `None` if the segment is not of the current [VERSION]
+fn scan(path: &std::path::Path) -> io::Result<Option<(u64, u64)>> {
+    let total = fs::metadata(path)?.len();
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) if header[..MAGIC.len()] == MAGIC[..] && header[MAGIC.len()] == VERSION => {}
+        Ok(()) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut size = SEGMENT_HEADER_SIZE;
+    let mut records = 0;
+
+    while size + HEADER_SIZE <= total {
+        let len = reader.read_u32::<BigEndian>()? as u64;
+        if size + HEADER_SIZE + len > total {
+            break;
+        }
+        reader.seek_relative(8 + len as i64)?;
+
+        size += HEADER_SIZE + len;
+        records += 1;
+    }
+
+    Ok(Some((size, records)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str, max_bytes: u64, drop_policy: DropPolicy) -> SpoolSettings {
+        let dir =
+            std::env::temp_dir().join(format!("monitor-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        SpoolSettings {
+            enabled: true,
+            dir,
+            segment_bytes: SEGMENT_HEADER_SIZE + 64,
+            max_bytes,
+            max_age_s: 0,
+            drop_policy,
+        }
+    }
+
+    fn drain(spool: &mut Spool) -> Vec<String> {
+        let mut out = Vec::new();
+        while let Some(m) = spool.peek().unwrap() {
+            out.push(String::from_utf8(m).unwrap());
+            spool.ack().unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn replays_in_order_after_a_restart() {
+        let s = settings("replay", 1 << 20, DropPolicy::Oldest);
+        let mut spool = Spool::open(s.clone()).unwrap();
+
+        for i in 0..10 {
+            spool.append(format!("message {}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(spool.peek().unwrap().unwrap(), b"message 0");
+        spool.ack().unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(s.clone()).unwrap();
+        assert_eq!(spool.len(), 9);
+        let expected: Vec<String> = (1..10).map(|i| format!("message {}", i)).collect();
+        assert_eq!(drain(&mut spool), expected);
+        assert!(spool.is_empty());
+
+        let _ = fs::remove_dir_all(&s.dir);
+    }
+
+    #[test]
+    fn applies_the_drop_policy_when_full() {
+        // every record takes 12 + 9 bytes and every segment 5 more, so a segment is full after 4
+        // records and the log after 6
+        let s = settings("oldest", 140, DropPolicy::Oldest);
+        let mut spool = Spool::open(s.clone()).unwrap();
+        for i in 0..9 {
+            assert!(spool.append(format!("message {}", i).as_bytes()).unwrap());
+        }
+        assert_eq!(spool.dropped(), 4);
+        assert_eq!(drain(&mut spool).first().unwrap(), "message 4");
+        let _ = fs::remove_dir_all(&s.dir);
+
+        let s = settings("newest", 140, DropPolicy::Newest);
+        let mut spool = Spool::open(s.clone()).unwrap();
+        for i in 0..9 {
+            spool.append(format!("message {}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(spool.dropped(), 3);
+        assert_eq!(drain(&mut spool).last().unwrap(), "message 5");
+        let _ = fs::remove_dir_all(&s.dir);
+    }
+
+    #[test]
+    fn discards_the_segments_of_another_version() {
+        let s = settings("version", 1 << 20, DropPolicy::Oldest);
+        fs::create_dir_all(&s.dir).unwrap();
+        // a record of a monitor writing segments without a header
+        let mut old = Vec::new();
+        old.write_u32::<BigEndian>(9).unwrap();
+        old.write_u64::<BigEndian>(now_ms()).unwrap();
+        old.extend_from_slice(b"message 0");
+        fs::write(segment_path(&s.dir, 0), &old).unwrap();
+        fs::write(s.dir.join(CURSOR_FILE), [0; 24]).unwrap();
+
+        let mut spool = Spool::open(s.clone()).unwrap();
+        assert!(spool.is_empty());
+        assert!(!segment_path(&s.dir, 0).exists());
+        spool.append(b"message 1").unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(s.clone()).unwrap();
+        assert_eq!(drain(&mut spool), vec!["message 1"]);
+
+        let _ = fs::remove_dir_all(&s.dir);
+    }
+}
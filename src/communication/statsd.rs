@@ -0,0 +1,104 @@
+//! Writes the [points](crate::communication::points) as StatsD metrics, one line per field:
+//!
+//! ```text
+//! monitor.process.progress,node=2,pid=1234,rank=2:50.5|g
+//! monitor.process.send_t,node=2,pid=1234,rank=2:1.5|ms
+//! ```
+//!
+//! The fields are gauges, except the metrics of [MetricMapping::timers](crate::config::MetricMapping::timers),
+//! which are timers. The tags follow the name as in the StatsD input of Telegraf. A plain StatsD
+//! server needs a mapping without tags.
+//!
+//! A negative gauge is sent as `0` first, as StatsD reads a signed value as a change of the
+//! current one.
+
+use crate::communication::points::Point;
+use std::fmt::Write;
+
+/// Returns the metrics of the points
+///
+/// # Arguments
+///
+/// - `points`: The points to write
+/// - `prefix`: Starts the name of every metric
+pub fn format(points: &[Point], prefix: &str) -> Vec<u8> {
+    let mut out = String::new();
+
+    for p in points {
+        let tags: String = p
+            .tags
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| format!(",{}={}", sanitize(name), sanitize(value)))
+            .collect();
+
+        for f in &p.fields {
+            let name = format!(
+                "{}.{}.{}{}",
+                sanitize(prefix),
+                p.measurement,
+                sanitize(&f.name),
+                tags
+            );
+
+            if f.timer {
+                let _ = writeln!(out, "{}:{}|ms", name, f.value);
+            } else {
+                if f.value < 0.0 {
+                    let _ = writeln!(out, "{}:0|g", name);
+                }
+                let _ = writeln!(out, "{}:{}|g", name, f.value);
+            }
+        }
+    }
+
+    out.into_bytes()
+}
+
+/// Replaces the characters StatsD uses as separators with `_`
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | ',' | '=' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::points::Field;
+
+    #[test]
+    fn writes_gauges_and_timers() {
+        let point = Point {
+            measurement: "process",
+            tags: vec![("pid".to_owned(), "10".to_owned())]
+                .into_iter()
+                .collect(),
+            fields: vec![
+                Field {
+                    name: "ram growth".to_owned(),
+                    value: -8.5,
+                    timer: false,
+                },
+                Field {
+                    name: "send_t".to_owned(),
+                    value: 1.5,
+                    timer: true,
+                },
+            ],
+            timestamp_ms: 0,
+        };
+
+        assert_eq!(
+            String::from_utf8(format(&[point], "monitor")).unwrap(),
+            "monitor.process.ram_growth,pid=10:0|g\n\
+             monitor.process.ram_growth,pid=10:-8.5|g\n\
+             monitor.process.send_t,pid=10:1.5|ms\n"
+        );
+    }
+}
@@ -6,16 +6,32 @@
 //!
 //! With help from [ThatsNoMoon](https://gist.github.com/ThatsNoMoon/edc16ab072d470d3a7f9d996c8fc9dec)
 
+use crate::communication::auth::{Authenticator, Purpose, AUTH_TIMEOUT};
+use crate::communication::coalesce::Coalescer;
 use crate::communication::file_transfer::send_all_pcm;
-use crate::communication::http_requests::RequestSerializable;
+use crate::communication::http_requests::{now_ms, RequestSerializable};
+use crate::communication::live::LiveStream;
+use crate::communication::query::start_query_server;
+use crate::communication::rest::{start_api_server, ApiState};
+use crate::communication::sink::{self, Sink};
+use crate::communication::tls::Endpoint;
+use crate::config::Settings;
+use crate::monitor::alerts::AlertEngine;
+use crate::monitor::discovery::discover;
+use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+use crate::monitor::history::History;
+use crate::monitor::memory::OomWatch;
+use crate::monitor::sampler::{start_sampler, Snapshot};
+use crate::monitor::self_stats::MonitorStats;
+use crate::monitor::source::SysinfoSource;
 use crate::monitor::stats::{NodeData, ProcData};
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use sysinfo::{System, SystemExt};
+use std::time::Duration;
 
 /// Starts the TCP server that communicates usage and progress data to the server
 ///
@@ -24,8 +40,11 @@ use sysinfo::{System, SystemExt};
 /// - `ip`: The ip to start the server on
 /// - `port`: The port to bind the server to
 /// - `proc_name`: The name of the processes to gather usage data on
-/// - `server_addr`: The address of the room partitioner server
+/// - `server`: The room partitioner server
 /// - `pcm_endpoint`: The endpoint to send the pcm files to
+/// - `settings`: The tuning of the sampler, of the discovery and of the heartbeat, where the
+///   queries, the API and the live stream are served, and the secret the clients must know
+/// - `stats`: The monitor's own counters
 ///
 /// # Acknowledgements
 /// Based on <https://riptutorial.com/rust/example/4404/a-simple-tcp-client-and-server-application--echo>
@@ -33,19 +52,137 @@ pub fn start_server(
     ip: String,
     port: usize,
     proc_name: String,
-    server_addr: String,
-    pcm_endpoint: String,
+    server: Endpoint,
+    pcm_endpoint: Endpoint,
+    settings: Settings,
+    stats: Arc<MonitorStats>,
 ) {
-    let mut sys = System::new_all();
-    let node = NodeData::new();
+    let started_at_ms = now_ms();
+    let mut source = SysinfoSource::new();
+    let node = NodeData::new(&mut source);
 
     let procs = Arc::new(Mutex::new(ProcData::fetch_all(
         &proc_name,
         node.get_id(),
-        &mut sys,
+        &mut source,
     )));
-    let sys = Arc::new(Mutex::new(sys));
     let node = Arc::new(Mutex::new(node));
+    let snapshot = Arc::new(Mutex::new(Snapshot::default()));
+    let history = Arc::new(Mutex::new(History::new(settings.history.clone())));
+    let mut taps: Vec<Arc<dyn Sink>> = Vec::new();
+    if settings.live.port != 0 {
+        let live = Arc::new(LiveStream::default());
+        taps.push(Arc::clone(&live) as Arc<dyn Sink>);
+
+        let ip = ip.clone();
+        let port = settings.live.port;
+        thread::spawn(move || live.start(ip, port));
+    }
+    let upstream = sink::start(&server, &settings, &stats, taps);
+    let coalescer = Arc::new(Coalescer::new(settings.coalesce.window()));
+    let auth =
+        Authenticator::new(&settings.auth, Purpose::Ingest, Arc::clone(&stats)).map(Arc::new);
+    let pcm_endpoint = Arc::new(pcm_endpoint);
+
+    if !settings.coalesce.window().is_zero() {
+        let procs_handle = Arc::clone(&procs);
+        let node_handle = Arc::clone(&node);
+        let up = Arc::clone(&upstream);
+        let co = Arc::clone(&coalescer);
+        thread::spawn(move || co.run(&procs_handle, &node_handle, &*up));
+    }
+
+    let procs_handle = Arc::clone(&procs);
+    let node_handle = Arc::clone(&node);
+    let snapshot_handle = Arc::clone(&snapshot);
+    let history_handle = Arc::clone(&history);
+    let up = Arc::clone(&upstream);
+    let interval = settings.sampler.interval();
+    let mut oom_watch = OomWatch::new(settings.memory.clone());
+    let mut alerts = AlertEngine::new(settings.alerts.clone());
+    thread::spawn(move || {
+        start_sampler(
+            SysinfoSource::new(),
+            &procs_handle,
+            &snapshot_handle,
+            interval,
+            |s| {
+                let mut procs = procs_handle.lock().unwrap();
+                let mut node = node_handle.lock().unwrap();
+
+                if let Some(w) = oom_watch.observe(s, node.get_id()) {
+                    println!("EVENT: {}", w.serialize());
+                    up.send(&w);
+                }
+
+                node.update(s);
+                for p in procs.values_mut() {
+                    p.update_usage(s);
+                }
+
+                for e in alerts.evaluate(&node, &procs, s.taken_at_ms) {
+                    println!("EVENT: {}", e.serialize());
+                    up.send(&e);
+                }
+
+                history_handle
+                    .lock()
+                    .unwrap()
+                    .record(s.taken_at_ms, &node, &procs);
+            },
+        )
+    });
+
+    let job = proc_name.clone();
+    let procs_handle = Arc::clone(&procs);
+    let node_handle = Arc::clone(&node);
+    let up = Arc::clone(&upstream);
+    let interval = settings.discovery.interval();
+    thread::spawn(move || {
+        start_discovery(
+            &proc_name,
+            &procs_handle,
+            &node_handle,
+            source,
+            &*up,
+            interval,
+        )
+    });
+
+    let interval = settings.heartbeat.interval();
+    if !interval.is_zero() {
+        let node_handle = Arc::clone(&node);
+        let up = Arc::clone(&upstream);
+        thread::spawn(move || start_heartbeat(&node_handle, &*up, interval));
+    }
+
+    if settings.query.port != 0 {
+        let procs_handle = Arc::clone(&procs);
+        let node_handle = Arc::clone(&node);
+        let history_handle = Arc::clone(&history);
+        let ip = ip.clone();
+        let port = settings.query.port;
+        thread::spawn(move || {
+            start_query_server(ip, port, procs_handle, node_handle, history_handle)
+        });
+    }
+
+    if settings.api.port != 0 {
+        let state = ApiState {
+            procs: Arc::clone(&procs),
+            node: Arc::clone(&node),
+            snapshot: Arc::clone(&snapshot),
+            history: Arc::clone(&history),
+            stats: Arc::clone(&stats),
+            sample_interval: settings.sampler.interval(),
+            started_at_ms,
+            job,
+            node_name: settings.node.name(),
+        };
+        let ip = ip.clone();
+        let port = settings.api.port;
+        thread::spawn(move || start_api_server(ip, port, state));
+    }
 
     let listener = TcpListener::bind(ip + ":" + &*port.to_string()).unwrap();
     // accept connections and process them, spawning a new thread for each one
@@ -53,21 +190,37 @@ pub fn start_server(
 
     for stream in listener.incoming() {
         match stream {
-            Ok(stream) => {
+            Ok(mut stream) => {
                 println!("New connection: {}", stream.peer_addr().unwrap());
 
                 let procs_handle = Arc::clone(&procs);
                 let node_handle = Arc::clone(&node);
-                let sys_handle = Arc::clone(&sys);
+                let snapshot_handle = Arc::clone(&snapshot);
 
-                let t = server_addr.clone();
-                let pe = pcm_endpoint.clone();
+                let up = Arc::clone(&upstream);
+                let co = Arc::clone(&coalescer);
+                let pe = Arc::clone(&pcm_endpoint);
+                let au = auth.clone();
                 thread::spawn(move || {
-                    let mut ph = procs_handle.lock().unwrap();
-                    let mut nh = node_handle.lock().unwrap();
-                    let mut sh = sys_handle.lock().unwrap();
+                    if let Some(auth) = au {
+                        let peer = stream.peer_addr().unwrap().to_string();
+                        let _ = stream.set_read_timeout(Some(AUTH_TIMEOUT));
+                        if !auth.admit(&mut stream, &peer) {
+                            let _ = stream.shutdown(Shutdown::Both);
+                            return;
+                        }
+                        let _ = stream.set_read_timeout(None);
+                    }
 
-                    handle_client(stream, &mut *ph, &mut *nh, &mut *sh, t, pe);
+                    handle_client(
+                        stream,
+                        &procs_handle,
+                        &node_handle,
+                        &snapshot_handle,
+                        &*up,
+                        &co,
+                        &pe,
+                    );
                 });
             }
             Err(e) => {
@@ -77,16 +230,69 @@ pub fn start_server(
     }
 }
 
+/// Periodically looks for processes that started or exited and notifies the server
+///
+/// # Arguments
+/// -`proc_name`: The name of the processes to gather usage data on
+/// -`procs`: The processes' object's list
+/// -`node`: The node's object
+/// -`source`: Where to read the running processes from
+/// - `upstream`: Where the updates for the room partitioner server go
+/// - `interval`: The time between two discovery passes
+fn start_discovery(
+    proc_name: &str,
+    procs: &Mutex<HashMap<i32, ProcData>>,
+    node: &Mutex<NodeData>,
+    mut source: SysinfoSource,
+    upstream: &dyn Sink,
+    interval: Duration,
+) {
+    loop {
+        thread::sleep(interval);
+
+        let node_id = node.lock().unwrap().get_id();
+        let events = discover(proc_name, node_id, &mut procs.lock().unwrap(), &mut source);
+
+        for e in &events {
+            println!("EVENT: {}", e.serialize());
+            upstream.send(e);
+        }
+    }
+}
+
+/// Periodically sends the node to the server, so it keeps hearing from an idle node
+///
+/// The node is kept up to date by the sampler, so the update holds the latest system usage even
+/// when no process reported since the previous one.
+///
+/// # Arguments
+/// -`node`: The node's object
+/// - `upstream`: Where the updates for the room partitioner server go
+/// - `interval`: The time between two updates
+fn start_heartbeat(node: &Mutex<NodeData>, upstream: &dyn Sink, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        upstream.send(&*node.lock().unwrap());
+    }
+}
+
 /// Handles a client connection
 ///
 /// # Arguments
 /// -`stream`: The client's TCP stream
 /// -`procs`: The processes' object's list
 /// -`node`: The node's object
-/// -`sys`: [Sys] instance to fetch process data from
-/// - `server_addr`: The address of the room partitioner server
+/// -`snapshot`: The latest sample of the system usage
+/// - `upstream`: Where the updates for the room partitioner server go
+/// - `coalescer`: Where the updates wait for the next batch
 /// - `pcm_endpoint`: The endpoint to send the pcm files
 ///
+/// The shared state is only locked while a message is being handled, so long lived connections
+/// do not starve the other clients or the process discovery.
+///
+/// The end of a run, and a connection lost before it, are sent to the server right away.
+///
 /// # Protocol
 /// To see details on the protocol refer to [process_input]
 ///
@@ -94,15 +300,18 @@ pub fn start_server(
 /// Based on <https://riptutorial.com/rust/example/4404/a-simple-tcp-client-and-server-application--echo>
 fn handle_client(
     mut stream: TcpStream,
-    procs: &mut HashMap<i32, ProcData>,
-    node: &mut NodeData,
-    sys: &mut System,
-    server_addr: String,
-    pcm_endpoint: String,
+    procs: &Mutex<HashMap<i32, ProcData>>,
+    node: &Mutex<NodeData>,
+    snapshot: &Mutex<Snapshot>,
+    upstream: &dyn Sink,
+    coalescer: &Coalescer,
+    pcm_endpoint: &Endpoint,
 ) {
     // let mut data = [0; 5 + 1 + 7 + 1]; // using 50 byte buffer
     let mut data = [0; 6 * 4]; // PID: i32, percentage: f32, send_t, recv_t, delay_t, scatter_t
                                // let server_addr = String::from("127.0.0.1:8888");
+    let mut last_pid = None;
+    let mut finished = false;
 
     loop {
         match stream.read(&mut data) {
@@ -110,7 +319,14 @@ fn handle_client(
                 if size == 0 {
                     break;
                 }
-                node.update(sys);
+                let ingested_at = now_ms();
+
+                let mut procs = procs.lock().unwrap();
+                let mut node = node.lock().unwrap();
+                let snapshot = snapshot.lock().unwrap();
+
+                node.update(&snapshot);
+                node.set_ingested_at(ingested_at);
                 // send_update(node, &server_addr);
                 println!("{:?}", node);
 
@@ -119,38 +335,40 @@ fn handle_client(
                     process_input(&data[0..size]);
                 println!("Post processing: {pid} @ {progress}% (send {send_t}, recv {recv_t}, delay {delay_t}, scatter {scatter_t})");
                 node.set_id(pid as u8);
-                send_update(node, &server_addr);
+                last_pid = Some(pid);
 
                 if progress == -1.0 {
                     // signals the end of the transmission
+                    finished = true;
+                    coalescer.flush(&node, procs.get(&pid), upstream);
+                    upstream.send(&ProcessEvent::new(
+                        node.get_id(),
+                        pid,
+                        ProcessEventKind::Finished,
+                    ));
 
-                    let i = 1;
-                    let mut name = format!("receiver_{}.pcm", i);
-
-                    println!("Would've send to {}", server_addr);
-                    send_all_pcm(&pcm_endpoint, node.get_id());
-
-                    // while File::open(&name).is_ok() {
-                    //     println!("Found {}", name);
-                    //     send_file("127.0.0.1:5000", &name, node.get_id());
-                    //
-                    //     name = format!("receiver_{}.pcm", i);
-                    //     i += 1;
-                    // }
+                    // the upload takes a while, the other clients must not wait for it
+                    let node_id = node.get_id();
+                    drop(snapshot);
+                    drop(node);
+                    drop(procs);
+                    send_all_pcm(pcm_endpoint, node_id);
                 } else if let Some(p) = procs.get_mut(&pid) {
                     // the process is valid
 
-                    p.update(progress, send_t, recv_t, delay_t, scatter_t, &mut *sys);
+                    p.update(progress, send_t, recv_t, delay_t, scatter_t, &snapshot);
+                    p.set_ingested_at(ingested_at);
 
                     println!("SEND: {}", &p.serialize());
 
-                    send_update(p, &server_addr);
+                    coalescer.update(&node, p, upstream);
                 } else {
-                    let p = ProcData::new(pid, node.get_id(), sys);
+                    let mut p = ProcData::new(pid, node.get_id(), &snapshot);
+                    p.set_ingested_at(ingested_at);
 
                     println!("SEND: {}", &p.serialize());
 
-                    send_update(&p, &server_addr);
+                    coalescer.update(&node, &p, upstream);
                     procs.insert(pid, p);
                 }
 
@@ -168,6 +386,12 @@ fn handle_client(
             }
         }
     }
+
+    if let (Some(pid), false) = (last_pid, finished) {
+        println!("Process {} disconnected before the end of its run", pid);
+        let node_id = node.lock().unwrap().get_id();
+        upstream.send(&ProcessEvent::new(node_id, pid, ProcessEventKind::Failed));
+    }
 }
 
 /// Converts a byte array into an i32
@@ -211,35 +435,32 @@ fn process_input(input: &[u8]) -> (i32, f32, f32, f32, f32, f32) {
     )
 }
 
-/// Sends the data to the server
-///
-/// # Arguments
-///
-/// - `request`: The `RequestSerializable` to be sent to the server
-/// - `endpoint`: A string in the form `<ip>:<port>` that contains the ip and port to send the data to
-fn send_update(request: &dyn RequestSerializable, endpoint: &str) {
-    match TcpStream::connect(endpoint) {
-        Ok(mut stream) => {
-            println!("Successfully connected to server at {}", endpoint);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::sink::Recorder;
+    use crate::monitor::source::FakeSource;
+    use std::time::Instant;
 
-            let mut a = [0; 256];
-            fetch_message(&mut a, &request.serialize());
+    #[test]
+    fn heartbeat_sends_the_node_without_ingest_traffic() {
+        let mut node = NodeData::new(&mut FakeSource::default());
+        node.set_id(3);
+        let node = Arc::new(Mutex::new(node));
+        let sink = Recorder::default();
+        let interval = Duration::from_millis(50);
 
-            stream.write_all(&a).unwrap();
-        }
-        Err(e) => {
-            println!("Failed to connect: {}", e);
+        let start = Instant::now();
+        let (node_handle, up) = (Arc::clone(&node), sink.clone());
+        thread::spawn(move || start_heartbeat(&node_handle, &up, interval));
+        thread::sleep(interval * 5 + interval / 2);
+
+        let sent = sink.messages();
+        assert!(sent.len() >= 3, "{} updates", sent.len());
+        assert!(sent[0].at >= start + interval);
+        for pair in sent.windows(2) {
+            assert!(pair[1].at - pair[0].at >= interval);
         }
+        assert!(sent.iter().all(|m| m.json["nodeId"] == 3));
     }
-    println!("Terminated.");
-}
-
-/// Converts a string into a byte array
-///
-/// # Arguments
-///
-/// - `a`: The byte array to write to
-/// - `data`: The string to convert into bytes
-fn fetch_message(mut a: &mut [u8], data: &str) {
-    write!(a, "{}", data).unwrap();
 }
@@ -0,0 +1,362 @@
+//! Encrypts the connections to and from the room partitioner with TLS, when enabled in the
+//! [TlsSettings], so the updates and the files cannot be read or forged on a shared network.
+//!
+//! The certificates are read from PEM files. The monitor checks the certificates of the servers
+//! it connects to against the configured certificate authorities, and presents its own
+//! certificate when it has one. The file server can also require a certificate from its clients,
+//! for mutual authentication.
+//!
+//! The handshake is done as soon as the connection is open, so a peer without a valid
+//! certificate is turned away before anything is read or written.
+
+use crate::config::TlsSettings;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The time to wait for the peer during the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection, encrypted or not
+pub enum Stream {
+    Plain(TcpStream),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The TCP connection under the encryption
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Client(s) => &s.sock,
+            Stream::Server(s) => &s.sock,
+        }
+    }
+
+    /// Tells the peer nothing more will be written and closes the connection
+    pub fn close(mut self) -> io::Result<()> {
+        match &mut self {
+            Stream::Plain(_) => {}
+            Stream::Client(s) => s.conn.send_close_notify(),
+            Stream::Server(s) => s.conn.send_close_notify(),
+        }
+        self.flush()?;
+
+        self.tcp().shutdown(Shutdown::Write)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Client(s) => s.read(buf),
+            Stream::Server(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Client(s) => s.write(buf),
+            Stream::Server(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Client(s) => s.flush(),
+            Stream::Server(s) => s.flush(),
+        }
+    }
+}
+
+/// A server the monitor connects to
+///
+/// # Properties
+/// -`address`: A string in the format `<ip>:<port>` that tells where the server is
+/// -`tls`: How to encrypt the connections, if they are
+pub struct Endpoint {
+    pub address: String,
+    pub tls: Option<TlsClient>,
+}
+
+/// Opens TLS connections to the servers
+///
+/// # Properties
+/// -`config`: The certificate authorities, and the certificate of the monitor, if any
+/// -`server_name`: The name the certificates of the servers must be issued to, if not their host
+#[derive(Clone)]
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl TlsClient {
+    /// Reads the certificates of the settings
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: Where the certificates are
+    pub fn load(settings: &TlsSettings) -> Result<Self, Box<dyn Error>> {
+        let roots = load_roots(settings.ca.as_deref())?;
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+
+        let config = match (&settings.cert, &settings.key) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsClient {
+            config: Arc::new(config),
+            server_name: Some(settings.server_name.clone()).filter(|n| !n.is_empty()),
+        })
+    }
+
+    /// Does the handshake over an open connection
+    ///
+    /// # Arguments
+    ///
+    /// - `tcp`: The connection to the server
+    /// - `endpoint`: The `<host>:<port>` connected to
+    pub fn connect(&self, mut tcp: TcpStream, endpoint: &str) -> io::Result<Stream> {
+        let host = self
+            .server_name
+            .as_deref()
+            .unwrap_or_else(|| host_of(endpoint));
+        let name = ServerName::try_from(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut conn = ClientConnection::new(Arc::clone(&self.config), name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        handshake(&mut conn, &mut tcp)?;
+
+        Ok(Stream::Client(Box::new(StreamOwned::new(conn, tcp))))
+    }
+}
+
+/// Accepts TLS connections from the clients
+///
+/// # Properties
+/// -`config`: The certificate of the monitor, and the certificate authorities of the clients when
+///   they must authenticate
+pub struct TlsServer {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServer {
+    /// Reads the certificates of the settings
+    ///
+    /// # Arguments
+    ///
+    /// - `settings`: Where the certificates are
+    pub fn load(settings: &TlsSettings) -> Result<Self, Box<dyn Error>> {
+        let (cert, key) = match (&settings.cert, &settings.key) {
+            (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+            _ => return Err("a TLS server needs tls.cert and tls.key".into()),
+        };
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if settings.client_auth {
+            let roots = load_roots(settings.ca.as_deref())?;
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        Ok(TlsServer {
+            config: Arc::new(builder.with_single_cert(cert, key)?),
+        })
+    }
+
+    /// Does the handshake over a connection from a client
+    ///
+    /// # Arguments
+    ///
+    /// - `tcp`: The connection from the client
+    pub fn accept(&self, mut tcp: TcpStream) -> io::Result<Stream> {
+        let mut conn = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        handshake(&mut conn, &mut tcp)?;
+
+        Ok(Stream::Server(Box::new(StreamOwned::new(conn, tcp))))
+    }
+}
+
+/// Exchanges the handshake messages until the connection is encrypted, or the peer is rejected
+fn handshake<C, D>(conn: &mut C, tcp: &mut TcpStream) -> io::Result<()>
+where
+    C: std::ops::DerefMut<Target = rustls::ConnectionCommon<D>>,
+{
+    let timeout = tcp.read_timeout()?;
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(tcp) {
+            // tell the peer why it was rejected, if possible
+            let _ = conn.write_tls(tcp);
+            return Err(e);
+        }
+    }
+
+    tcp.set_read_timeout(timeout)
+}
+
+/// Returns the host of a `<host>:<port>` address
+fn host_of(endpoint: &str) -> &str {
+    let host = endpoint.rsplit_once(':').map_or(endpoint, |(host, _)| host);
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Opens a PEM file, with its path in the errors
+fn open(path: &Path) -> Result<BufReader<File>, Box<dyn Error>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e).into())
+}
+
+/// Reads the certificate authorities
+fn load_roots(path: Option<&Path>) -> Result<RootCertStore, Box<dyn Error>> {
+    let path = path.ok_or("the TLS peers are checked against tls.ca, which is not set")?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+
+    Ok(roots)
+}
+
+/// Reads a certificate chain
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()).into());
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first private key of a file
+fn load_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = open(path)?;
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    Err(format!("no private key in {}", path.display()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    /// Writes a certificate signed by the CA, and its key, returning their paths
+    fn issue(dir: &Path, name: &str, ca: &rcgen::Certificate) -> (PathBuf, PathBuf) {
+        let cert =
+            rcgen::Certificate::from_params(CertificateParams::new(vec![name.to_owned()])).unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn only_accepts_clients_with_a_certificate_of_the_ca() {
+        let dir = std::env::temp_dir().join(format!("monitor-tls-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let ca_path = dir.join("ca.pem");
+        fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+        let (server_cert, server_key) = issue(&dir, "localhost", &ca);
+        let (client_cert, client_key) = issue(&dir, "node-1", &ca);
+
+        let server = TlsServer::load(&TlsSettings {
+            ca: Some(ca_path.clone()),
+            cert: Some(server_cert),
+            key: Some(server_key),
+            client_auth: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (received_tx, received) = channel();
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let res = server.accept(tcp.unwrap()).and_then(|mut stream| {
+                    let mut text = String::new();
+                    stream.read_to_string(&mut text).map(|_| text)
+                });
+                received_tx.send(res.ok()).unwrap();
+            }
+        });
+
+        let client = TlsClient::load(&TlsSettings {
+            ca: Some(ca_path.clone()),
+            cert: Some(client_cert),
+            key: Some(client_key),
+            server_name: "localhost".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut stream = client
+            .connect(TcpStream::connect(&address).unwrap(), &address)
+            .unwrap();
+        stream.write_all(b"receiver_0.pcm").unwrap();
+        stream.close().unwrap();
+        assert_eq!(received.recv().unwrap().unwrap(), "receiver_0.pcm");
+
+        // without a certificate, the server turns the client away
+        let anonymous = TlsClient::load(&TlsSettings {
+            ca: Some(ca_path),
+            server_name: "localhost".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        if let Ok(mut stream) = anonymous.connect(TcpStream::connect(&address).unwrap(), &address) {
+            let _ = stream.write_all(b"forged");
+            let _ = stream.close();
+        }
+        assert_eq!(received.recv().unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+//! Keeps a single long-lived connection to the room partitioner server.
+//!
+//! Updates are queued and written by a dedicated thread, so the callers never wait on the
+//! network. When the server is unreachable, the thread reconnects with an exponential backoff
+//! with jitter, keeping the most recent updates in the queue until they can be delivered.
+//!
+//! The queue is kept in memory, or on disk in a [Spool] when it is enabled in the settings, in
+//! which case the updates also survive a restart of the monitor.
+//!
+//! When [UpstreamSettings::delta] is set, the node and process updates only hold what changed
+//! since the previous update, see [delta](crate::communication::delta). The changes are found
+//! when the updates are written, so they are always relative to what was sent on the current
+//! connection.
+
+use crate::communication::delta::{DeltaEncoder, RESYNC};
+use crate::communication::framing;
+use crate::communication::http_requests::{
+    decode_raw, encode_raw, MessageKind, RequestSerializable,
+};
+use crate::communication::sink::Sink;
+use crate::communication::spool::Spool;
+use crate::communication::tls::{Stream, TlsClient};
+use crate::config::{Encoding, Framing, SpoolSettings, UpstreamSettings};
+use crate::monitor::self_stats::{ConnectionState, SinkStats};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A handle to the connection to the server. Cloning it is cheap and every clone writes to the
+/// same connection.
+#[derive(Clone)]
+pub struct Upstream {
+    tx: Sender<Vec<u8>>,
+    encoding: Encoding,
+}
+
+impl Upstream {
+    /// Starts the thread that connects and writes to the server
+    ///
+    /// # Arguments
+    ///
+    /// - `endpoint`: A string in the form `<ip>:<port>` that contains the ip and port to send the
+    ///   data to
+    /// - `settings`: The timeouts, backoff and queue size of the connection
+    /// - `spool`: Where to keep the queue on disk, if enabled
+    /// - `tls`: How to encrypt the connection, if it is
+    /// - `stats`: Where to report the state of the connection
+    pub fn start(
+        endpoint: String,
+        settings: UpstreamSettings,
+        spool: SpoolSettings,
+        tls: Option<TlsClient>,
+        stats: Arc<SinkStats>,
+    ) -> Self {
+        let (tx, rx) = channel();
+
+        let mut encoding = settings.encoding;
+        if !framing::supports(settings.framing, encoding) {
+            println!(
+                "The {:?} framing cannot carry {:?}, sending JSON instead",
+                settings.framing, encoding
+            );
+            encoding = Encoding::Json;
+        }
+
+        thread::spawn(move || Writer::new(endpoint, settings, encoding, spool, tls, stats).run(rx));
+
+        Upstream { tx, encoding }
+    }
+}
+
+impl Sink for Upstream {
+    fn send(&self, request: &dyn RequestSerializable) {
+        // the content type is queued with the message, so a message spooled before a change of
+        // the encoding is still framed with its own
+        let mut msg = vec![framing::content_type(self.encoding), request.kind().code()];
+        msg.extend(request.encode(self.encoding));
+
+        let _ = self.tx.send(msg);
+    }
+}
+
+/// Where the messages wait to be written. Every message starts with its content type byte and
+/// its [MessageKind::code]. The messages outlive the monitor in the spool, so a change of this
+/// layout needs a new version of the spool segments
+enum Queue {
+    Memory(VecDeque<Vec<u8>>),
+    Disk(Spool),
+}
+
+/// The state of the thread writing to the server
+///
+/// # Properties
+/// -`encoding`: The encoding of the messages the writer sends on its own, such as the snapshots
+/// -`delta`: What the server knows, when only the changes are sent
+/// -`resync`: Whether the full state has to be sent before the next message
+/// -`tls`: How the connection is encrypted, if it is
+struct Writer {
+    endpoint: String,
+    settings: UpstreamSettings,
+    encoding: Encoding,
+    stats: Arc<SinkStats>,
+    pending: Queue,
+    tls: Option<TlsClient>,
+    stream: Option<Stream>,
+    backoff: Duration,
+    delta: Option<DeltaEncoder>,
+    resync: bool,
+}
+
+impl Writer {
+    fn new(
+        endpoint: String,
+        settings: UpstreamSettings,
+        encoding: Encoding,
+        spool: SpoolSettings,
+        tls: Option<TlsClient>,
+        stats: Arc<SinkStats>,
+    ) -> Self {
+        let backoff = settings.initial_backoff();
+
+        let pending = if spool.enabled {
+            match Spool::open(spool.clone()) {
+                Ok(s) => {
+                    println!("Spooling updates to {}", spool.dir.display());
+                    Queue::Disk(s)
+                }
+                Err(e) => {
+                    println!("Failed to open the spool at {}: {}", spool.dir.display(), e);
+                    Queue::Memory(VecDeque::new())
+                }
+            }
+        } else {
+            Queue::Memory(VecDeque::new())
+        };
+
+        let delta = if settings.delta {
+            Some(DeltaEncoder::default())
+        } else {
+            None
+        };
+
+        let writer = Writer {
+            endpoint,
+            settings,
+            encoding,
+            stats,
+            pending,
+            tls,
+            stream: None,
+            backoff,
+            delta,
+            resync: false,
+        };
+        writer.update_queued();
+
+        writer
+    }
+
+    /// Writes the queued messages until every [Upstream] handle is dropped
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        loop {
+            if self.queued() == 0 {
+                match rx.recv() {
+                    Ok(m) => self.push(m),
+                    Err(_) => return,
+                }
+            }
+            while let Ok(m) = rx.try_recv() {
+                self.push(m);
+            }
+
+            if self.stream.is_none() && !self.connect() {
+                let delay = self.next_backoff();
+                println!("Retrying in {:?}", delay);
+                self.wait(&rx, delay);
+                continue;
+            }
+
+            let msg = match self.front() {
+                Some(m) => m,
+                None => continue,
+            };
+
+            // a write to a connection closed by the other end usually succeeds, so the message
+            // would be lost
+            match read_requests(self.stream.as_mut().unwrap()) {
+                Ok(true) if self.delta.is_some() => {
+                    println!("The server at {} asked for a resync", self.endpoint);
+                    self.resync = true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.lost(e);
+                    continue;
+                }
+            }
+            if self.resync {
+                if let Err(e) = self.send_snapshot() {
+                    self.lost(e);
+                    continue;
+                }
+            }
+
+            let frame = match self.frame(&msg) {
+                Ok(f) => f,
+                Err(e) => {
+                    println!("Dropping update: {}", e);
+                    self.pop();
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.update_queued();
+                    // the server may be missing a change that will not be sent again
+                    self.resync = self.delta.is_some();
+                    continue;
+                }
+            };
+            match self.stream.as_mut().unwrap().write_all(&frame) {
+                Ok(()) => {
+                    self.pop();
+                    self.stats.sent.fetch_add(1, Ordering::Relaxed);
+                    self.update_queued();
+                }
+                Err(e) => self.lost(e),
+            }
+        }
+    }
+
+    /// Returns the bytes to write to the server for a queued message
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The queued message, with its content type and kind
+    fn frame(&mut self, msg: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (content_type, kind, payload) = match msg {
+            [content_type, kind, payload @ ..] => (*content_type, *kind, payload),
+            _ => return Err(io::Error::from(ErrorKind::InvalidData).into()),
+        };
+
+        let fixed = self.settings.framing == Framing::Fixed;
+        match framing::encoding(content_type) {
+            Some(encoding) if fixed || self.delta.is_some() => {
+                let kind = MessageKind::from_code(kind)
+                    .ok_or_else(|| io::Error::from(ErrorKind::InvalidData))?;
+                let mut message = decode_raw(payload, encoding)?;
+                if fixed {
+                    message = framing::compat(kind, message);
+                }
+                if let Some(delta) = &mut self.delta {
+                    message = delta.encode(kind, message);
+                }
+                let payload = encode_raw(&message, encoding);
+
+                Ok(framing::encode(
+                    self.settings.framing,
+                    content_type,
+                    &payload,
+                )?)
+            }
+            _ => Ok(framing::encode(
+                self.settings.framing,
+                content_type,
+                payload,
+            )?),
+        }
+    }
+
+    /// Sends the full state of the node and of every known process, when only the changes are
+    /// sent
+    fn send_snapshot(&mut self) -> io::Result<()> {
+        self.resync = false;
+        let delta = match &mut self.delta {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+
+        for (_, message) in delta.snapshot() {
+            let payload = encode_raw(&message, self.encoding);
+            let frame = framing::encode(
+                self.settings.framing,
+                framing::content_type(self.encoding),
+                &payload,
+            );
+
+            match frame {
+                Ok(f) => {
+                    self.stream.as_mut().unwrap().write_all(&f)?;
+                    self.stats.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => println!("Leaving an update out of the snapshot: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the connection after it failed
+    fn lost(&mut self, e: io::Error) {
+        println!("Lost connection to server at {}: {}", self.endpoint, e);
+        self.stream = None;
+        self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        self.stats.set_state(ConnectionState::Disconnected);
+    }
+
+    /// The number of messages waiting to be written
+    fn queued(&self) -> u64 {
+        match &self.pending {
+            Queue::Memory(q) => q.len() as u64,
+            Queue::Disk(s) => s.len(),
+        }
+    }
+
+    fn update_queued(&self) {
+        self.stats.queued.store(self.queued(), Ordering::Relaxed);
+    }
+
+    /// Queues a message. When the queue is full, the oldest message is dropped, or the one
+    /// chosen by the drop policy of the spool
+    fn push(&mut self, msg: Vec<u8>) {
+        match &mut self.pending {
+            Queue::Memory(q) => {
+                if q.len() >= self.settings.max_pending {
+                    q.pop_front();
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                q.push_back(msg);
+            }
+            Queue::Disk(s) => {
+                let dropped = s.dropped();
+                let res = s.append(&msg);
+                let dropped = s.dropped() - dropped;
+                self.stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+
+                if let Err(e) = res {
+                    self.spool_failed(e);
+                    self.push(msg);
+                }
+            }
+        }
+        self.update_queued();
+    }
+
+    /// Returns the next message to write, without removing it from the queue
+    fn front(&mut self) -> Option<Vec<u8>> {
+        match &mut self.pending {
+            Queue::Memory(q) => q.front().cloned(),
+            Queue::Disk(s) => {
+                let dropped = s.dropped();
+                let res = s.peek();
+                let dropped = s.dropped() - dropped;
+                self.stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+
+                match res {
+                    Ok(m) => m,
+                    Err(e) => {
+                        self.spool_failed(e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the message returned by [Writer::front] from the queue
+    fn pop(&mut self) {
+        match &mut self.pending {
+            Queue::Memory(q) => {
+                q.pop_front();
+            }
+            Queue::Disk(s) => {
+                if let Err(e) = s.ack() {
+                    self.spool_failed(e);
+                }
+            }
+        }
+    }
+
+    /// Gives up on the spool and keeps the next messages in memory. The messages still in the
+    /// spool are left on disk and are sent after the monitor restarts.
+    fn spool_failed(&mut self, e: std::io::Error) {
+        println!("Spool failed, keeping the updates in memory: {}", e);
+        self.pending = Queue::Memory(VecDeque::new());
+        self.update_queued();
+    }
+
+    /// Tries to connect to the server once
+    fn connect(&mut self) -> bool {
+        self.stats.set_state(ConnectionState::Connecting);
+
+        let res = self
+            .endpoint
+            .to_socket_addrs()
+            .and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| ErrorKind::AddrNotAvailable.into())
+            })
+            .and_then(|addr| TcpStream::connect_timeout(&addr, self.settings.connect_timeout()))
+            .and_then(|stream| {
+                let _ = stream.set_write_timeout(Some(self.settings.write_timeout()));
+                let _ = stream.set_nodelay(true);
+
+                match &self.tls {
+                    Some(tls) => tls.connect(stream, &self.endpoint),
+                    None => Ok(Stream::Plain(stream)),
+                }
+            });
+
+        match res {
+            Ok(stream) => {
+                println!("Successfully connected to server at {}", self.endpoint);
+
+                self.stream = Some(stream);
+                self.backoff = self.settings.initial_backoff();
+                self.resync = self.delta.is_some();
+                self.stats.connects.fetch_add(1, Ordering::Relaxed);
+                self.stats.set_state(ConnectionState::Connected);
+                true
+            }
+            Err(e) => {
+                println!("Failed to connect: {}", e);
+                self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                self.stats.set_state(ConnectionState::Disconnected);
+                false
+            }
+        }
+    }
+
+    /// Returns the time to wait before the next connection attempt and doubles the backoff.
+    /// The wait is randomly picked between half and all of the backoff, so that every node does
+    /// not reconnect at the same time after the server restarts.
+    fn next_backoff(&mut self) -> Duration {
+        let half = self.backoff / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+
+        self.backoff = (self.backoff * 2).min(self.settings.max_backoff());
+
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Waits for the given time, queueing the messages received meanwhile
+    fn wait(&mut self, rx: &Receiver<Vec<u8>>, delay: Duration) {
+        let deadline = Instant::now() + delay;
+
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(m) => self.push(m),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(left);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Reads what the server wrote on the connection, without blocking. Returns whether the server
+/// asked for a resync, or fails if it closed the connection.
+///
+/// # Arguments
+///
+/// - `stream`: The connection to the server
+fn read_requests(stream: &mut Stream) -> io::Result<bool> {
+    let mut buf = [0u8; 64];
+    let mut resync = false;
+
+    stream.tcp().set_nonblocking(true)?;
+    let res = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Err(ErrorKind::ConnectionReset.into()),
+            Ok(n) => resync |= buf[..n].contains(&RESYNC),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(resync),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = stream.tcp().set_nonblocking(false);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::http_requests::{now_ms, Stamp};
+    use crate::monitor::memory::MemoryPressure;
+    use crate::monitor::sampler::{ProcSample, Snapshot};
+    use crate::monitor::source::{FakeSource, NodeInfo};
+    use crate::monitor::stats::{NodeData, ProcData};
+
+    /// Returns a message as [Upstream] queues it, stamped by a sink late in a long run
+    fn queued(request: &dyn RequestSerializable) -> Vec<u8> {
+        let stamp = Stamp {
+            run_id: u32::MAX,
+            seq: 123_456_789_012,
+            sent_at: now_ms(),
+        };
+        let mut msg = vec![framing::content_type(Encoding::Json), request.kind().code()];
+        msg.extend(request.encode_stamped(Encoding::Json, Some(&stamp)));
+
+        msg
+    }
+
+    #[test]
+    fn stamped_updates_fit_in_the_default_framing() {
+        let mut snapshot = Snapshot {
+            taken_at_ms: now_ms(),
+            cpu_usage: 57.345_68,
+            used_ram: 123_456_789,
+            available_ram: 140_464_611,
+            used_swap: 1_048_576,
+            total_swap: 8_388_608,
+            pressure: Some(MemoryPressure {
+                some: 12.34,
+                full: 5.67,
+            }),
+            temperature: vec![55.0, 56.5, 60.25, 48.0, 71.125, 52.75, 49.5, 66.0],
+            frequency: Some(3_499),
+            ..Default::default()
+        };
+        snapshot.processes.insert(
+            4_194_303,
+            ProcSample {
+                cpu: 99.876_54,
+                ram: 12_345_678,
+                ram_growth: 1_234.567_9,
+            },
+        );
+        let mut source = FakeSource::new(NodeInfo {
+            cores: 64,
+            threads: 128,
+            total_ram: 263_921_400,
+        });
+        source.push_snapshot(snapshot.clone());
+
+        let mut node = NodeData::new(&mut source);
+        node.update(&snapshot);
+        node.set_id(255);
+        node.set_ingested_at(now_ms());
+        let mut p = ProcData::new(4_194_303, 255, &snapshot);
+        p.update(
+            57.142_857,
+            1.234_567_8,
+            2.345_678_9,
+            3.456_789,
+            4.567_89,
+            &snapshot,
+        );
+        p.set_ingested_at(now_ms());
+
+        let mut writer = Writer::new(
+            "127.0.0.1:0".to_owned(),
+            UpstreamSettings::default(),
+            Encoding::Json,
+            SpoolSettings::default(),
+            None,
+            Arc::new(SinkStats::new("upstream")),
+        );
+        for request in [&node as &dyn RequestSerializable, &p] {
+            let frame = writer.frame(&queued(request)).unwrap();
+            assert_eq!(frame.len(), framing::FIXED_SIZE);
+
+            let end = frame.iter().position(|b| *b == 0).unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&frame[..end]).unwrap();
+            assert_eq!(json["nodeId"], 255);
+            assert!(json.get("seq").is_none());
+        }
+    }
+}
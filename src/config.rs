@@ -0,0 +1,1058 @@
+//! Optional tuning of the monitor, read from a TOML file.
+//!
+//! Every section and every value can be omitted, in which case the default is used.
+//!
+//! ```toml
+//! [node]
+//! name = "pi-1"
+//!
+//! [sampler]
+//! interval_ms = 1000
+//!
+//! [discovery]
+//! interval_ms = 2000
+//!
+//! [heartbeat]
+//! interval_ms = 5000
+//!
+//! [history]
+//! max_age_s = 300
+//!
+//! [query]
+//! port = 49154
+//!
+//! [api]
+//! port = 8080
+//!
+//! [live]
+//! port = 8081
+//!
+//! [upstream]
+//! connect_timeout_ms = 2000
+//! write_timeout_ms = 2000
+//! initial_backoff_ms = 200
+//! max_backoff_ms = 30000
+//! max_pending = 10000
+//! transport = "tcp"
+//! framing = "length"
+//! encoding = "cbor"
+//! delta = true
+//!
+//! [coalesce]
+//! window_ms = 1000
+//!
+//! [[sinks]]
+//! name = "partitioner"
+//! kind = "tcp"
+//!
+//! [[sinks]]
+//! name = "dashboard"
+//! kind = "http"
+//! url = "http://dashboard:8080"
+//! messages = ["node", "event"]
+//! max_per_second = 1.0
+//!
+//! [[sinks]]
+//! name = "log"
+//! kind = "file"
+//! path = "updates.jsonl"
+//!
+//! [[sinks]]
+//! name = "influx"
+//! kind = "influx"
+//! url = "http://influx:8086/write?db=monitor"
+//!
+//! [[sinks]]
+//! name = "telegraf"
+//! kind = "statsd"
+//! address = "127.0.0.1:8125"
+//! messages = ["process"]
+//!
+//! [sinks.mapping]
+//! prefix = "dwm"
+//! tags = { host = "node", rank = "rank" }
+//! static_tags = { lab = "acoustics" }
+//! process_fields = { progress = "progress", send = "send_t", receive = "recv_t" }
+//! timers = ["send_t", "recv_t"]
+//!
+//! [[sinks]]
+//! name = "broker"
+//! kind = "mqtt"
+//! address = "broker.lab:1883"
+//!
+//! [sinks.mqtt]
+//! topic_prefix = "meshotron"
+//! node = "pi-1"
+//! qos = 1
+//! retain = true
+//! keep_alive_s = 30
+//!
+//! [http]
+//! base_url = "http://partitioner:8080"
+//! node_path = "/node"
+//! process_path = "/process"
+//! batch_path = "/batch"
+//! event_path = "/events"
+//! stats_path = "/stats"
+//! connect_timeout_ms = 2000
+//! timeout_ms = 5000
+//! max_retries = 3
+//! retry_backoff_ms = 500
+//! batch_size = 50
+//! batch_ms = 1000
+//! max_pending = 10000
+//!
+//! [spool]
+//! enabled = true
+//! dir = "spool"
+//! segment_bytes = 1048576
+//! max_bytes = 67108864
+//! max_age_s = 3600
+//! drop_policy = "oldest"
+//!
+//! [tls]
+//! upstream = true
+//! file_server = true
+//! pcm = true
+//! ca = "certs/ca.pem"
+//! cert = "certs/node.pem"
+//! key = "certs/node.key"
+//! client_auth = true
+//!
+//! [auth]
+//! secret = "change me"
+//! max_skew_s = 30
+//!
+//! [memory]
+//! warn_seconds = 120
+//! critical_seconds = 30
+//! some_pressure = 10.0
+//! full_pressure = 5.0
+//!
+//! [[alerts]]
+//! name = "overheating"
+//! target = "node"
+//! metric = "temperature"
+//! condition = "above"
+//! threshold = 80.0
+//! clear = 75.0
+//! for_s = 30
+//! severity = "critical"
+//!
+//! [[alerts]]
+//! name = "stalled"
+//! target = "process"
+//! metric = "progress"
+//! condition = "unchanged"
+//! for_s = 120
+//! ```
+
+use crate::communication::http_requests::MessageKind;
+use crate::monitor::source::host_name;
+use crate::monitor::stats::{NodeData, ProcData};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// All the settings that are not given on the command line
+///
+/// # Properties
+/// -`node`: How the node is named outside the room partitioner
+/// -`sampler`: How the system usage is sampled
+/// -`discovery`: How new and exited processes are found
+/// -`heartbeat`: How often the node is sent to the server, even when no process reports
+/// -`history`: How much of the past states of the node and processes is kept
+/// -`query`: Where the tools can ask for the current state of the node
+/// -`api`: Where the HTTP API to inspect the monitor is served
+/// -`live`: Where the updates are streamed to the browsers as they happen
+/// -`upstream`: How the connection to the server is kept
+/// -`coalesce`: How the node and process updates are batched
+/// -`sinks`: Where the updates go. When empty, they only go to the room partitioner server, with
+///   the transport of [UpstreamSettings::transport]
+/// -`http`: How the updates are posted when the server is a web service
+/// -`spool`: Where the messages are kept on disk while the server is unreachable
+/// -`tls`: Which connections are encrypted, and with which certificates
+/// -`auth`: The secret the clients of the ingest and file servers prove they know
+/// -`memory`: When to warn the server the node is about to run out of memory
+/// -`alerts`: The rules that raise alerts on the node and process metrics
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub node: NodeSettings,
+    pub sampler: SamplerSettings,
+    pub discovery: DiscoverySettings,
+    pub heartbeat: HeartbeatSettings,
+    pub history: HistorySettings,
+    pub query: QuerySettings,
+    pub api: ApiSettings,
+    pub live: LiveSettings,
+    pub upstream: UpstreamSettings,
+    pub coalesce: CoalesceSettings,
+    pub sinks: Vec<SinkSettings>,
+    pub http: HttpSettings,
+    pub spool: SpoolSettings,
+    pub tls: TlsSettings,
+    pub auth: AuthSettings,
+    pub memory: MemorySettings,
+    pub alerts: Vec<AlertRule>,
+}
+
+impl Settings {
+    /// Reads the settings from a TOML file
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The path of the file to read
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let settings: Settings = toml::from_str(&contents)?;
+        settings.check_intervals()?;
+        settings.check_metrics()?;
+
+        Ok(settings)
+    }
+
+    /// Makes sure the background loops wait between two passes, so they do not keep the locks
+    /// of the node and of the processes away from the ingest server
+    fn check_intervals(&self) -> Result<(), Box<dyn Error>> {
+        if self.sampler.interval_ms == 0 {
+            return Err("sampler.interval_ms must be above 0".into());
+        }
+        if self.discovery.interval_ms == 0 {
+            return Err("discovery.interval_ms must be above 0".into());
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure every metric named by the alert rules and the sink mappings exists, so a typo
+    /// is not silently never evaluated
+    fn check_metrics(&self) -> Result<(), Box<dyn Error>> {
+        for rule in &self.alerts {
+            let (metrics, target): (&[&str], _) = match rule.target {
+                AlertTarget::Node => (&NodeData::METRICS, "node"),
+                AlertTarget::Process => (&ProcData::METRICS, "process"),
+            };
+            if !metrics.contains(&rule.metric.as_str()) {
+                return Err(format!(
+                    "alert `{}` watches `{}`, which is not a {} metric",
+                    rule.name, rule.metric, target
+                )
+                .into());
+            }
+        }
+
+        for sink in &self.sinks {
+            let mapping = &sink.mapping;
+            let fields = mapping
+                .node_fields
+                .iter()
+                .map(|(f, m)| (f, m, &NodeData::METRICS[..], "node"))
+                .chain(
+                    mapping
+                        .process_fields
+                        .iter()
+                        .map(|(f, m)| (f, m, &ProcData::METRICS[..], "process")),
+                );
+            for (field, metric, metrics, target) in fields {
+                if !metrics.contains(&metric.as_str()) {
+                    return Err(format!(
+                        "sink `{}` reads the field `{}` from `{}`, which is not a {} metric",
+                        sink.name, field, metric, target
+                    )
+                    .into());
+                }
+            }
+
+            for timer in &mapping.timers {
+                if !NodeData::METRICS.contains(&timer.as_str())
+                    && !ProcData::METRICS.contains(&timer.as_str())
+                {
+                    return Err(format!(
+                        "sink `{}` sends `{}` as a timer, which is not a metric",
+                        sink.name, timer
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// # Properties
+/// -`name`: The name of the node in the Prometheus labels, the InfluxDB and StatsD tags and the
+///   MQTT topics. Empty for the host name. Unlike the ID of the node, which is the rank the last
+///   cluster program reported, it does not change while the monitor runs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NodeSettings {
+    pub name: String,
+}
+
+impl NodeSettings {
+    pub fn name(&self) -> String {
+        if self.name.is_empty() {
+            host_name()
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// # Properties
+/// -`interval_ms`: The time between two samples of the system usage, in milliseconds. Above 0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SamplerSettings {
+    pub interval_ms: u64,
+}
+
+impl SamplerSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        SamplerSettings { interval_ms: 1000 }
+    }
+}
+
+/// # Properties
+/// -`interval_ms`: The time between two discovery passes, in milliseconds. Above 0
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    pub interval_ms: u64,
+}
+
+impl DiscoverySettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        DiscoverySettings { interval_ms: 2000 }
+    }
+}
+
+/// # Properties
+/// -`interval_ms`: The time between two updates of the node sent on their own, in milliseconds.
+///   The server can consider the node dead once a few intervals went by without any update, and
+///   idle when the `ingestedAt` of the updates stops changing. 0 only sends the node when a
+///   process reports
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatSettings {
+    pub interval_ms: u64,
+}
+
+impl HeartbeatSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        HeartbeatSettings { interval_ms: 5000 }
+    }
+}
+
+/// # Properties
+/// -`max_age_s`: The time, in seconds, a sample of the node and processes is kept for
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistorySettings {
+    pub max_age_s: u64,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings { max_age_s: 300 }
+    }
+}
+
+/// # Properties
+/// -`port`: The port the queries are answered on, see
+///   [query](crate::communication::query). 0 does not answer queries
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QuerySettings {
+    pub port: u16,
+}
+
+/// # Properties
+/// -`port`: The port the HTTP API is served on, see [rest](crate::communication::rest). 0 does
+///   not serve it
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiSettings {
+    pub port: u16,
+}
+
+/// # Properties
+/// -`port`: The port the WebSocket stream of the updates is served on, see
+///   [live](crate::communication::live). 0 does not serve it
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LiveSettings {
+    pub port: u16,
+}
+
+/// # Properties
+/// -`connect_timeout_ms`: The time to wait for the server to accept the connection
+/// -`write_timeout_ms`: The time to wait for a write before considering the connection broken
+/// -`initial_backoff_ms`: The time to wait before reconnecting the first time
+/// -`max_backoff_ms`: The longest time to wait between two connection attempts
+/// -`transport`: Whether the updates are written to a TCP connection or posted over HTTP, when
+///   no [Settings::sinks] are configured
+/// -`max_pending`: The number of messages kept while the server is unreachable. Once full, the
+///   oldest messages are dropped
+/// -`framing`: How the messages are delimited on the connection
+/// -`encoding`: How the messages are encoded. Only the length framing can carry other encodings
+///   than JSON
+/// -`delta`: Whether the node and process updates only hold the fields that changed since the
+///   previous update, after a full snapshot on every connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpstreamSettings {
+    pub connect_timeout_ms: u64,
+    pub write_timeout_ms: u64,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub transport: Transport,
+    pub max_pending: usize,
+    pub framing: Framing,
+    pub encoding: Encoding,
+    pub delta: bool,
+}
+
+impl UpstreamSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_millis(self.write_timeout_ms)
+    }
+
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        UpstreamSettings {
+            connect_timeout_ms: 2000,
+            write_timeout_ms: 2000,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 30_000,
+            transport: Transport::Tcp,
+            max_pending: 10_000,
+            framing: Framing::Fixed,
+            encoding: Encoding::Json,
+            delta: false,
+        }
+    }
+}
+
+/// # Properties
+/// -`window_ms`: The time during which the updates are coalesced: only the latest state of the node
+///   and of every process that reported is sent, in a single batch, once per window. 0 sends every
+///   update as it comes, which is what the fixed framing needs as the batches do not fit in it
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CoalesceSettings {
+    pub window_ms: u64,
+}
+
+impl CoalesceSettings {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+}
+
+/// How the updates reach the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Written to a single TCP connection, see [UpstreamSettings]
+    Tcp,
+    /// Posted in batches to the endpoints of a web service, see [HttpSettings]
+    Http,
+}
+
+/// What a sink delivers the updates to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    /// A TCP server, see [UpstreamSettings]
+    Tcp,
+    /// A web service, see [HttpSettings]
+    Http,
+    /// A local file, one JSON message per line
+    File,
+    /// An InfluxDB or Telegraf server, in the InfluxDB line protocol, over UDP or HTTP
+    Influx,
+    /// A StatsD server, over UDP
+    Statsd,
+    /// An MQTT broker, see [MqttSettings]
+    Mqtt,
+}
+
+/// A destination of the updates
+///
+/// # Properties
+/// -`name`: The name of the sink, as reported in the monitor's own metrics
+/// -`kind`: What the updates are delivered to
+/// -`address`: The `<ip>:<port>` of a TCP sink, the UDP `<ip>:<port>` of an InfluxDB or StatsD
+///   sink, or the `<host>:<port>` of an MQTT broker. Defaults to the server address given on the
+///   command line for a TCP sink, `127.0.0.1:8089` for an InfluxDB sink, `127.0.0.1:8125` for a
+///   StatsD sink and `127.0.0.1:1883` for an MQTT sink
+/// -`url`: The base URL of an HTTP sink, or the write URL of an InfluxDB sink, such as
+///   `http://influx:8086/write?db=monitor`. Defaults to [HttpSettings::base_url] for an HTTP sink.
+///   An InfluxDB sink with a URL posts over HTTP rather than sending over UDP
+/// -`path`: The file a file sink appends to
+/// -`messages`: The kinds of messages the sink receives. Empty for every kind
+/// -`max_per_second`: The most node, process and batch updates the sink receives per second, the
+///   rest being dropped. Events are never dropped. 0 for no limit
+/// -`mapping`: How an InfluxDB or StatsD sink turns the updates into points
+/// -`mqtt`: The topics and delivery of an MQTT sink
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkSettings {
+    pub name: String,
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_sink_path")]
+    pub path: PathBuf,
+    #[serde(default)]
+    pub messages: Vec<MessageKind>,
+    #[serde(default)]
+    pub max_per_second: f32,
+    #[serde(default)]
+    pub mapping: MetricMapping,
+    #[serde(default)]
+    pub mqtt: MqttSettings,
+}
+
+fn default_sink_path() -> PathBuf {
+    PathBuf::from("updates.jsonl")
+}
+
+/// Where the value of a tag of the InfluxDB and StatsD points comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagSource {
+    /// The name of the node, see [NodeSettings]
+    Node,
+    /// The PID of the process, only on the process points
+    Pid,
+    /// The rank the cluster program reported for the process, only on the process points
+    Rank,
+}
+
+/// How the node and process updates are turned into InfluxDB and StatsD points, see
+/// [points](crate::communication::points)
+///
+/// # Properties
+/// -`prefix`: Starts the name of every measurement, as in `monitor_node` for InfluxDB and
+///   `monitor.node.cpu_usage` for StatsD
+/// -`tags`: The tags of every point, by name, with where their value comes from
+/// -`static_tags`: Tags with the same value on every point, such as the lab the node is in
+/// -`node_fields`: The fields of the node points, by name, with the
+///   [NodeData](crate::monitor::stats::NodeData) metric they are read from. Empty for every metric,
+///   under its own name
+/// -`process_fields`: The fields of the process points, by name, with the
+///   [ProcData](crate::monitor::stats::ProcData) metric they are read from. Empty for every
+///   metric, under its own name
+/// -`timers`: The metrics sent as StatsD timers rather than gauges
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricMapping {
+    pub prefix: String,
+    pub tags: BTreeMap<String, TagSource>,
+    pub static_tags: BTreeMap<String, String>,
+    pub node_fields: BTreeMap<String, String>,
+    pub process_fields: BTreeMap<String, String>,
+    pub timers: Vec<String>,
+}
+
+impl Default for MetricMapping {
+    fn default() -> Self {
+        MetricMapping {
+            prefix: "monitor".to_owned(),
+            tags: vec![
+                ("node".to_owned(), TagSource::Node),
+                ("pid".to_owned(), TagSource::Pid),
+                ("rank".to_owned(), TagSource::Rank),
+            ]
+            .into_iter()
+            .collect(),
+            static_tags: BTreeMap::new(),
+            node_fields: BTreeMap::new(),
+            process_fields: BTreeMap::new(),
+            timers: vec![
+                "send_t".to_owned(),
+                "recv_t".to_owned(),
+                "delay_t".to_owned(),
+                "scatter_t".to_owned(),
+            ],
+        }
+    }
+}
+
+/// How an MQTT sink publishes the updates, see [mqtt](crate::communication::mqtt)
+///
+/// # Properties
+/// -`topic_prefix`: Starts every topic, as in `meshotron/<node>/proc/<pid>`
+/// -`node`: The name of the node in the topics. Empty for the one of [NodeSettings]
+/// -`client_id`: The ID the broker knows the monitor by. Empty for `monitor-<node>`
+/// -`qos`: The MQTT quality of service of the messages, 0 or 1
+/// -`retain`: Whether the broker keeps the last node and process updates, and the status, for the
+///   clients that subscribe later
+/// -`keep_alive_s`: The time, in seconds, after which the broker publishes the last will of a
+///   silent monitor
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub topic_prefix: String,
+    pub node: String,
+    pub client_id: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub keep_alive_s: u64,
+}
+
+impl MqttSettings {
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_s)
+    }
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            topic_prefix: "meshotron".to_owned(),
+            node: String::new(),
+            client_id: String::new(),
+            qos: 1,
+            retain: true,
+            keep_alive_s: 30,
+        }
+    }
+}
+
+/// # Properties
+/// -`base_url`: The URL of the room partitioner web service
+/// -`node_path`: Where the node updates are posted
+/// -`process_path`: Where the process updates are posted
+/// -`batch_path`: Where the coalesced updates are posted
+/// -`event_path`: Where the events are posted
+/// -`stats_path`: Where the monitor's own metrics are posted
+/// -`connect_timeout_ms`: The time to wait for the server to accept the connection
+/// -`timeout_ms`: The time to wait for the whole request
+/// -`max_retries`: The number of times a batch is posted again before it is dropped
+/// -`retry_backoff_ms`: The time to wait before the first retry, doubled on every retry
+/// -`batch_size`: The most messages posted in a single request
+/// -`batch_ms`: The longest time a message waits for the batch to fill up
+/// -`max_pending`: The number of messages kept while the server is unreachable. Once full, the
+///   new messages are dropped
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub base_url: String,
+    pub node_path: String,
+    pub process_path: String,
+    pub batch_path: String,
+    pub event_path: String,
+    pub stats_path: String,
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    pub batch_size: usize,
+    pub batch_ms: u64,
+    pub max_pending: usize,
+}
+
+impl HttpSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms)
+    }
+
+    pub fn batch_interval(&self) -> Duration {
+        Duration::from_millis(self.batch_ms)
+    }
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            base_url: "http://127.0.0.1:8080".to_owned(),
+            node_path: "/node".to_owned(),
+            process_path: "/process".to_owned(),
+            batch_path: "/batch".to_owned(),
+            event_path: "/events".to_owned(),
+            stats_path: "/stats".to_owned(),
+            connect_timeout_ms: 2000,
+            timeout_ms: 5000,
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            batch_size: 50,
+            batch_ms: 1000,
+            max_pending: 10_000,
+        }
+    }
+}
+
+/// How the messages sent to the server are delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// Every message is padded with NUL bytes to 256 bytes, as expected by the current room
+    /// partitioner. The messages only keep the fields it knows, without the ordering fields, and
+    /// longer messages cannot be sent
+    Fixed,
+    /// Every message is preceded by its length, as a big endian u32, and its content type
+    Length,
+    /// Every message is followed by a `\n`
+    Newline,
+}
+
+/// How the messages sent to the server are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+/// What to drop when the spool is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    /// Drop the oldest messages to make room for the new one
+    Oldest,
+    /// Drop the new message
+    Newest,
+}
+
+/// # Properties
+/// -`enabled`: Whether the messages are kept on disk. When disabled, up to
+///   [UpstreamSettings::max_pending] messages are kept in memory instead. Only used by the TCP
+///   transport
+/// -`dir`: The directory the spool is kept in. Every TCP sink keeps its own spool in a
+///   subdirectory named after the sink
+/// -`segment_bytes`: The size of a segment file before a new one is started
+/// -`max_bytes`: The size of the spool before messages are dropped
+/// -`max_age_s`: The time, in seconds, after which undelivered messages are dropped. 0 keeps them
+///   until they are delivered
+/// -`drop_policy`: What to drop when the spool is full
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpoolSettings {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub segment_bytes: u64,
+    pub max_bytes: u64,
+    pub max_age_s: u64,
+    pub drop_policy: DropPolicy,
+}
+
+impl Default for SpoolSettings {
+    fn default() -> Self {
+        SpoolSettings {
+            enabled: false,
+            dir: PathBuf::from("spool"),
+            segment_bytes: 1 << 20,
+            max_bytes: 64 << 20,
+            max_age_s: 0,
+            drop_policy: DropPolicy::Oldest,
+        }
+    }
+}
+
+/// The TLS of the connections to and from the room partitioner, see
+/// [tls](crate::communication::tls)
+///
+/// # Properties
+/// -`upstream`: Whether the TCP sinks connect to their server over TLS
+/// -`file_server`: Whether the file server only accepts TLS connections
+/// -`pcm`: Whether the pcm files are sent over TLS
+/// -`ca`: The PEM file of the certificate authorities the certificates of the peers must be issued
+///   by. Required by the TLS clients and by [TlsSettings::client_auth]
+/// -`cert`: The PEM file of the certificate chain of the monitor. Required by the file server, and
+///   presented to the servers when set, for them to authenticate the monitor
+/// -`key`: The PEM file of the private key of [TlsSettings::cert]
+/// -`server_name`: The name the certificates of the servers must be issued to. Empty for the host
+///   of the address connected to
+/// -`client_auth`: Whether the file server only accepts the clients with a certificate issued by
+///   one of the certificate authorities, for mutual authentication
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub upstream: bool,
+    pub file_server: bool,
+    pub pcm: bool,
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub server_name: String,
+    pub client_auth: bool,
+}
+
+/// The authentication of the clients of the ingest and file servers, see
+/// [auth](crate::communication::auth)
+///
+/// # Properties
+/// -`secret`: The secret shared with the cluster programs, which read it from `MONITOR_SECRET`,
+///   and the room partitioner. Empty to accept every client
+/// -`max_skew_s`: The time, in seconds, a token stays valid, and how far the clocks of the
+///   clients can be ahead of the node
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    pub secret: String,
+    pub max_skew_s: u64,
+}
+
+impl AuthSettings {
+    pub fn max_skew(&self) -> Duration {
+        Duration::from_secs(self.max_skew_s)
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            secret: String::new(),
+            max_skew_s: 30,
+        }
+    }
+}
+
+/// The thresholds of the out of memory risk levels
+///
+/// # Properties
+/// -`warn_seconds`: The risk is high when the memory runs out in less than this many seconds
+/// -`critical_seconds`: The risk is critical when the memory runs out in less than this many
+///   seconds
+/// -`some_pressure`: The risk is high when some task stalls on memory this % of the time
+/// -`full_pressure`: The risk is critical when all tasks stall on memory this % of the time
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemorySettings {
+    pub warn_seconds: u64,
+    pub critical_seconds: u64,
+    pub some_pressure: f32,
+    pub full_pressure: f32,
+}
+
+impl Default for MemorySettings {
+    fn default() -> Self {
+        MemorySettings {
+            warn_seconds: 120,
+            critical_seconds: 30,
+            some_pressure: 10.0,
+            full_pressure: 5.0,
+        }
+    }
+}
+
+/// What an alert rule is evaluated on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertTarget {
+    /// The [NodeData](crate::monitor::stats::NodeData) of this node
+    Node,
+    /// Each [ProcData](crate::monitor::stats::ProcData) tracked, separately
+    Process,
+}
+
+/// When the metric of an alert rule is considered wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertCondition {
+    /// The value is greater than the threshold
+    Above,
+    /// The value is lower than the threshold
+    Below,
+    /// The value did not change
+    Unchanged,
+}
+
+/// How serious an alert is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// A rule that raises an alert when a metric stays wrong for long enough
+///
+/// # Properties
+/// -`name`: The name of the alert
+/// -`target`: Whether the rule applies to the node or to each process
+/// -`metric`: The name of the [NodeData](crate::monitor::stats::NodeData) or
+///   [ProcData](crate::monitor::stats::ProcData) metric to watch. The settings are refused when it
+///   is not one of their `METRICS`
+/// -`condition`: When the value is considered wrong
+/// -`threshold`: The value the metric is compared to. Unused by [AlertCondition::Unchanged]
+/// -`clear`: The value the metric has to go back past for the alert to resolve. Defaults to the
+///   threshold, a value a bit below (or above) it avoids flapping
+/// -`for_s`: How long, in seconds, the condition has to hold before the alert fires
+/// -`severity`: How serious the alert is
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub target: AlertTarget,
+    pub metric: String,
+    pub condition: AlertCondition,
+    #[serde(default)]
+    pub threshold: f32,
+    #[serde(default)]
+    pub clear: Option<f32>,
+    #[serde(default)]
+    pub for_s: u64,
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+}
+
+fn default_severity() -> Severity {
+    Severity::Warning
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_partial_settings() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [sampler]
+            interval_ms = 500
+
+            [[alerts]]
+            name = "stalled"
+            target = "process"
+            metric = "progress"
+            condition = "unchanged"
+            for_s = 120
+
+            [[sinks]]
+            name = "dashboard"
+            kind = "http"
+            messages = ["node", "event"]
+
+            [[sinks]]
+            name = "telegraf"
+            kind = "statsd"
+
+            [sinks.mapping]
+            tags = { host = "node" }
+
+            [[sinks]]
+            name = "broker"
+            kind = "mqtt"
+
+            [sinks.mqtt]
+            qos = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.sampler.interval(), Duration::from_millis(500));
+        assert_eq!(settings.discovery.interval_ms, 2000);
+        assert_eq!(settings.heartbeat.interval(), Duration::from_secs(5));
+        assert_eq!(settings.alerts.len(), 1);
+        assert_eq!(settings.alerts[0].condition, AlertCondition::Unchanged);
+        assert_eq!(settings.alerts[0].severity, Severity::Warning);
+        assert_eq!(settings.sinks[0].kind, SinkKind::Http);
+        assert_eq!(
+            settings.sinks[0].messages,
+            vec![MessageKind::Node, MessageKind::Event]
+        );
+        assert_eq!(settings.sinks[0].mapping.tags.len(), 3);
+        assert_eq!(settings.sinks[1].mapping.tags["host"], TagSource::Node);
+        assert_eq!(settings.sinks[1].mapping.prefix, "monitor");
+        assert_eq!(settings.sinks[2].mqtt.qos, 0);
+        assert_eq!(settings.sinks[2].mqtt.topic_prefix, "meshotron");
+        assert!(settings.check_metrics().is_ok());
+        assert!(settings.check_intervals().is_ok());
+    }
+
+    #[test]
+    fn rejects_busy_loops() {
+        let sampler: Settings = toml::from_str("[sampler]\ninterval_ms = 0").unwrap();
+        let e = sampler.check_intervals().unwrap_err().to_string();
+        assert!(e.contains("sampler.interval_ms"), "{}", e);
+
+        let discovery: Settings = toml::from_str("[discovery]\ninterval_ms = 0").unwrap();
+        let e = discovery.check_intervals().unwrap_err().to_string();
+        assert!(e.contains("discovery.interval_ms"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_unknown_metrics() {
+        let alert: Settings = toml::from_str(
+            r#"
+            [[alerts]]
+            name = "hot"
+            target = "node"
+            metric = "temprature"
+            condition = "above"
+            threshold = 90
+            "#,
+        )
+        .unwrap();
+        let e = alert.check_metrics().unwrap_err().to_string();
+        assert!(e.contains("`hot`") && e.contains("`temprature`"), "{}", e);
+
+        let mapping: Settings = toml::from_str(
+            r#"
+            [[sinks]]
+            name = "telegraf"
+            kind = "statsd"
+
+            [sinks.mapping]
+            process_fields = { usage = "cpu_usage" }
+            "#,
+        )
+        .unwrap();
+        let e = mapping.check_metrics().unwrap_err().to_string();
+        assert!(
+            e.contains("`telegraf`") && e.contains("`cpu_usage`"),
+            "{}",
+            e
+        );
+    }
+}
@@ -6,12 +6,23 @@
 //! Also deals with file transfer of the partitions from the server to the node and the excitation
 //! sound files from the node to the server.
 
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
 use std::thread;
 
 // use crate::monitor::stats::{NodeData, ProcData};
 // use sysinfo::{System, SystemExt};
+use crate::communication::auth::{Authenticator, Purpose};
 use crate::communication::file_transfer::start_file_server;
+use crate::communication::http_requests::now_ms;
 use crate::communication::tcp::start_server;
+use crate::communication::tls::{Endpoint, TlsClient, TlsServer};
+use crate::config::Settings;
+use crate::monitor::self_stats::MonitorStats;
+use crate::tui::Feed;
+
+pub mod config;
 
 /// Holds all communication interfaces
 ///
@@ -19,13 +30,40 @@ use crate::communication::tcp::start_server;
 /// - HTTP requests
 /// - TCP communication
 mod communication {
+    pub mod auth;
+    pub mod coalesce;
+    pub mod delta;
     pub mod file_transfer;
+    pub mod framing;
     pub mod http_requests;
+    pub mod influx;
+    pub mod live;
+    pub mod mqtt;
+    pub mod points;
+    pub mod prometheus;
+    pub mod query;
+    pub mod rest;
+    pub mod sink;
+    pub mod spool;
+    pub mod statsd;
     pub mod tcp;
+    pub mod tls;
+    pub mod upstream;
 }
 
+/// The dashboard of the node in the terminal
+mod tui;
+
 /// The code that gathers information on processes
 mod monitor {
+    pub mod alerts;
+    pub mod discovery;
+    pub mod events;
+    pub mod history;
+    pub mod memory;
+    pub mod sampler;
+    pub mod self_stats;
+    pub mod source;
     pub mod stats;
 }
 
@@ -38,7 +76,10 @@ mod monitor {
 /// - `file_transfer_port`: The port to bint the file transfer server to
 /// - `proc_name`: The process name to gather usage data on
 /// - `server_addr`: The address of the room partitioner server
-/// - `server_addr`: The endpoint to send the pcm files to
+/// - `pcm_endpoint`: The endpoint to send the pcm files to
+/// - `settings`: The optional tuning read from the configuration file
+///
+/// Fails before starting anything if the certificates of a TLS connection cannot be loaded.
 pub fn run(
     ip: String,
     cluster_port: usize,
@@ -46,15 +87,84 @@ pub fn run(
     proc_name: String,
     server_addr: String,
     pcm_endpoint: String,
-) {
+    settings: Settings,
+) -> Result<(), Box<dyn Error>> {
     // communication::http_requests::test();
+    let tls = &settings.tls;
+    let client_tls = if tls.upstream || tls.pcm {
+        Some(TlsClient::load(tls).map_err(|e| format!("cannot load the TLS client: {}", e))?)
+    } else {
+        None
+    };
+    let file_tls = if tls.file_server {
+        Some(TlsServer::load(tls).map_err(|e| format!("cannot load the TLS file server: {}", e))?)
+    } else {
+        None
+    };
+    let server = Endpoint {
+        address: server_addr,
+        tls: client_tls.clone().filter(|_| tls.upstream),
+    };
+    let pcm_endpoint = Endpoint {
+        address: pcm_endpoint,
+        tls: client_tls.filter(|_| tls.pcm),
+    };
+    let stats = Arc::new(MonitorStats::default());
+    let file_auth = Authenticator::new(&settings.auth, Purpose::File, Arc::clone(&stats));
+
     let ip1 = ip.clone();
     let node_server_handle = thread::spawn(move || {
-        start_server(ip1, cluster_port, proc_name, server_addr, pcm_endpoint)
+        start_server(
+            ip1,
+            cluster_port,
+            proc_name,
+            server,
+            pcm_endpoint,
+            settings,
+            stats,
+        )
+    });
+    let file_server_handle = thread::spawn(move || {
+        start_file_server(ip, file_transfer_port, "received", file_tls, file_auth)
     });
-    let file_server_handle =
-        thread::spawn(move || start_file_server(ip, file_transfer_port, "received"));
 
     let _ = node_server_handle.join();
     let _ = file_server_handle.join();
+
+    Ok(())
+}
+
+/// Shows a dashboard of the node in the terminal, until the user quits
+///
+/// # Arguments
+///
+/// - `target`: The URL of the API of a monitor running on the node, such as
+///   `http://127.0.0.1:8080`, or the name of the processes to track without a running monitor
+/// - `settings`: The optional tuning read from the configuration file
+pub fn dashboard(target: String, settings: Settings) -> io::Result<()> {
+    let feed = if target.starts_with("http://") || target.starts_with("https://") {
+        Feed::api(&target)
+    } else {
+        Feed::local(target, &settings)
+    };
+
+    tui::run(feed, settings.sampler.interval())
+}
+
+/// Returns a new token for a client of the ingest or file server, in hex, see
+/// [auth](crate::communication::auth)
+///
+/// # Arguments
+///
+/// - `purpose`: `ingest` or `file`, the server the token is for
+/// - `settings`: The settings holding the secret
+pub fn token(purpose: &str, settings: &Settings) -> Result<String, Box<dyn Error>> {
+    let purpose = Purpose::from_name(purpose).ok_or("the token is for ingest or file")?;
+    if settings.auth.secret.is_empty() {
+        return Err("no auth.secret in the settings".into());
+    }
+
+    let token = communication::auth::token(&settings.auth, purpose, now_ms());
+
+    Ok(token.iter().map(|b| format!("{:02x}", b)).collect())
 }
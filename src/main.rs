@@ -1,5 +1,6 @@
+use monitor::config::Settings;
+use std::fmt::Display;
 use std::str::FromStr;
-use std::usize;
 
 pub struct Config {
     ip: String,
@@ -8,6 +9,7 @@ pub struct Config {
     proc_name: String,
     server_addr: String,
     pcm_endpoint: String,
+    settings: Settings,
 }
 
 impl Config {
@@ -18,6 +20,7 @@ impl Config {
         proc_name: String,
         server_addr: String,
         pcm_endpoint: String,
+        settings: Settings,
     ) -> Self {
         // TODO: Add checks
 
@@ -28,6 +31,7 @@ impl Config {
             proc_name,          // test_client
             server_addr,        // 127.0.0.1:8888
             pcm_endpoint,       // 127.0.0.1:5000
+            settings,
         }
     }
 }
@@ -40,12 +44,45 @@ fn main() {
 
     let args: Vec<String> = std::env::args().collect();
 
+    // monitor tui <api url | process name> [settings]
+    if args[1] == "tui" {
+        let target = match args.get(2) {
+            Some(target) => target.clone(),
+            None => exit_with("Usage: monitor tui <api url | process name> [settings]"),
+        };
+        let settings = match args.get(3) {
+            Some(path) => load_settings(path),
+            None => Settings::default(),
+        };
+        if let Err(e) = monitor::dashboard(target, settings) {
+            exit_with(format!("The dashboard failed: {}", e));
+        }
+        return;
+    }
+
+    // monitor token <ingest | file> <settings>
+    if args[1] == "token" {
+        let (purpose, path) = match (args.get(2), args.get(3)) {
+            (Some(purpose), Some(path)) => (purpose, path),
+            _ => exit_with("Usage: monitor token <ingest | file> <settings>"),
+        };
+        match monitor::token(purpose, &load_settings(path)) {
+            Ok(token) => println!("{}", token),
+            Err(e) => exit_with(format!("Cannot make a token: {}", e)),
+        }
+        return;
+    }
+
     let ip = args[1].clone();
     let cluster_port = usize::from_str(args[2].as_str()).unwrap();
     let file_transfer_port = usize::from_str(args[3].as_str()).unwrap();
     let proc_name = args[4].clone();
     let server_addr = args[5].clone();
     let pcm_endpoint = args[6].clone();
+    let settings = match args.get(7) {
+        Some(path) => Settings::load(path).unwrap(),
+        None => Settings::default(),
+    };
 
     let cfg = Config::new(
         ip,
@@ -54,14 +91,38 @@ fn main() {
         proc_name,
         server_addr,
         pcm_endpoint,
+        settings,
     );
 
-    monitor::run(
+    let res = monitor::run(
         cfg.ip,
         cfg.cluster_port,
         cfg.file_transfer_port,
         cfg.proc_name,
         cfg.server_addr,
         cfg.pcm_endpoint,
+        cfg.settings,
     );
+    if let Err(e) = res {
+        println!("Failed to start the monitor: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Reads the settings file, or exits if it cannot be used
+///
+/// # Arguments
+///
+/// - `path`: The path of the file to read
+fn load_settings(path: &str) -> Settings {
+    match Settings::load(path) {
+        Ok(settings) => settings,
+        Err(e) => exit_with(format!("Cannot load the settings from {}: {}", path, e)),
+    }
+}
+
+/// Prints why the monitor cannot go on, and exits with an error
+fn exit_with<T: Display>(msg: T) -> ! {
+    println!("{}", msg);
+    std::process::exit(1);
 }
@@ -0,0 +1,248 @@
+//! Judges the node and process metrics against the alert rules from the settings.
+//!
+//! A rule fires once its condition held for the configured duration and resolves as soon as the
+//! condition stops holding. While a rule is firing, its metric is compared to the `clear` value
+//! instead of the threshold, so a value oscillating around the threshold does not flap.
+
+use crate::config::{AlertCondition, AlertRule, AlertTarget};
+use crate::monitor::events::AlertEvent;
+use crate::monitor::stats::{NodeData, ProcData};
+use std::collections::HashMap;
+
+/// The state of a rule for the node or for one process
+///
+/// # Properties
+/// -`since_ms`: When the condition started holding, if it holds
+/// -`firing`: Whether the alert was raised and not resolved yet
+/// -`last_value`: The value of the metric the last time the rule was evaluated
+struct RuleState {
+    since_ms: Option<u64>,
+    firing: bool,
+    last_value: f32,
+}
+
+/// Evaluates every alert rule and keeps track of which ones are firing
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    states: HashMap<(usize, Option<i32>), RuleState>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        AlertEngine {
+            rules,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Evaluates every rule on the current data and returns the alerts that fired or resolved
+    ///
+    /// The alerts of processes that are no longer tracked are resolved.
+    ///
+    /// # Arguments
+    ///
+    /// - `node`: The node's object
+    /// - `procs`: The tracked processes
+    /// - `now_ms`: The current time, in milliseconds
+    pub fn evaluate(
+        &mut self,
+        node: &NodeData,
+        procs: &HashMap<i32, ProcData>,
+        now_ms: u64,
+    ) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+
+        for i in 0..self.rules.len() {
+            match self.rules[i].target {
+                AlertTarget::Node => {
+                    if let Some(v) = node.metric(&self.rules[i].metric) {
+                        events.extend(self.step(i, None, v, node.get_id(), now_ms));
+                    }
+                }
+                AlertTarget::Process => {
+                    for (pid, p) in procs {
+                        if let Some(v) = p.metric(&self.rules[i].metric) {
+                            events.extend(self.step(i, Some(*pid), v, node.get_id(), now_ms));
+                        }
+                    }
+                }
+            }
+        }
+
+        let gone: Vec<(usize, Option<i32>)> = self
+            .states
+            .keys()
+            .filter(|(_i, pid)| pid.is_some_and(|pid| !procs.contains_key(&pid)))
+            .copied()
+            .collect();
+
+        for key in gone {
+            let state = self.states.remove(&key).unwrap();
+            if state.firing {
+                events.push(AlertEvent::new(
+                    &self.rules[key.0],
+                    false,
+                    node.get_id(),
+                    key.1,
+                    state.last_value,
+                ));
+            }
+        }
+
+        events
+    }
+
+    /// Evaluates one rule on one value
+    ///
+    /// # Arguments
+    ///
+    /// - `i`: The index of the rule
+    /// - `pid`: The process the value belongs to, or `None` for the node
+    /// - `value`: The current value of the metric
+    /// - `node_id`: This node's ID
+    /// - `now_ms`: The current time, in milliseconds
+    fn step(
+        &mut self,
+        i: usize,
+        pid: Option<i32>,
+        value: f32,
+        node_id: u8,
+        now_ms: u64,
+    ) -> Option<AlertEvent> {
+        let rule = &self.rules[i];
+        let state = self.states.entry((i, pid)).or_insert(RuleState {
+            since_ms: Some(now_ms),
+            firing: false,
+            last_value: value,
+        });
+
+        let limit = if state.firing {
+            rule.clear.unwrap_or(rule.threshold)
+        } else {
+            rule.threshold
+        };
+
+        let holds = match rule.condition {
+            AlertCondition::Above => value > limit,
+            AlertCondition::Below => value < limit,
+            AlertCondition::Unchanged => value == state.last_value,
+        };
+
+        state.last_value = value;
+
+        if !holds {
+            state.since_ms = match rule.condition {
+                // the value just changed, so it starts being unchanged from now on
+                AlertCondition::Unchanged => Some(now_ms),
+                _ => None,
+            };
+
+            if state.firing {
+                state.firing = false;
+                return Some(AlertEvent::new(rule, false, node_id, pid, value));
+            }
+            return None;
+        }
+
+        let since = *state.since_ms.get_or_insert(now_ms);
+
+        if !state.firing && now_ms.saturating_sub(since) >= rule.for_s * 1000 {
+            state.firing = true;
+            return Some(AlertEvent::new(rule, true, node_id, pid, value));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::http_requests::RequestSerializable;
+    use crate::config::Severity;
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+
+    fn rule(target: AlertTarget, metric: &str, condition: AlertCondition) -> AlertRule {
+        AlertRule {
+            name: "test".to_owned(),
+            target,
+            metric: metric.to_owned(),
+            condition,
+            threshold: 80.0,
+            clear: Some(75.0),
+            for_s: 30,
+            severity: Severity::Critical,
+        }
+    }
+
+    fn node_at(temperature: f32) -> NodeData {
+        let mut source = FakeSource::new(NodeInfo::default());
+        source.push_snapshot(Snapshot {
+            temperature: vec![temperature],
+            ..Default::default()
+        });
+        NodeData::new(&mut source)
+    }
+
+    fn fired(events: &[AlertEvent]) -> Vec<bool> {
+        events
+            .iter()
+            .map(|e| e.serialize().contains("\"alertFiring\""))
+            .collect()
+    }
+
+    #[test]
+    fn threshold_needs_the_duration_and_resolves_with_hysteresis() {
+        let mut engine = AlertEngine::new(vec![rule(
+            AlertTarget::Node,
+            "temperature",
+            AlertCondition::Above,
+        )]);
+        let procs = HashMap::new();
+
+        assert!(engine.evaluate(&node_at(85.0), &procs, 0).is_empty());
+        assert!(engine.evaluate(&node_at(85.0), &procs, 20_000).is_empty());
+        assert_eq!(
+            fired(&engine.evaluate(&node_at(85.0), &procs, 30_000)),
+            vec![true]
+        );
+        // between the clear value and the threshold, still firing
+        assert!(engine.evaluate(&node_at(78.0), &procs, 40_000).is_empty());
+        assert_eq!(
+            fired(&engine.evaluate(&node_at(70.0), &procs, 50_000)),
+            vec![false]
+        );
+    }
+
+    #[test]
+    fn unchanged_progress_fires_and_resolves_on_change() {
+        let mut engine = AlertEngine::new(vec![rule(
+            AlertTarget::Process,
+            "progress",
+            AlertCondition::Unchanged,
+        )]);
+        let node = node_at(50.0);
+        let mut procs = HashMap::new();
+        procs.insert(7, ProcData::new(7, 0, &Snapshot::default()));
+
+        assert!(engine.evaluate(&node, &procs, 0).is_empty());
+        assert!(engine.evaluate(&node, &procs, 29_000).is_empty());
+        assert_eq!(fired(&engine.evaluate(&node, &procs, 30_000)), vec![true]);
+
+        let mut sample = Snapshot::default();
+        sample.processes.insert(7, Default::default());
+        procs
+            .get_mut(&7)
+            .unwrap()
+            .update(10.0, 0.0, 0.0, 0.0, 0.0, &sample);
+        assert_eq!(fired(&engine.evaluate(&node, &procs, 31_000)), vec![false]);
+
+        // a process that goes away while firing is resolved
+        assert_eq!(fired(&engine.evaluate(&node, &procs, 61_000)), vec![true]);
+        assert_eq!(
+            fired(&engine.evaluate(&node, &HashMap::new(), 62_000)),
+            vec![false]
+        );
+    }
+}
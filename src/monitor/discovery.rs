@@ -0,0 +1,99 @@
+//! Keeps the list of tracked processes in sync with the processes running on the node.
+//!
+//! DWM ranks may be launched after the monitor started or exit while it is running, so the list
+//! built at startup by [ProcData::fetch_all] is periodically compared against the system.
+
+use crate::monitor::events::{ProcessEvent, ProcessEventKind};
+use crate::monitor::source::SystemSource;
+use crate::monitor::stats::ProcData;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Adds the processes with the given name that are not yet tracked and retires the tracked
+/// processes that are no longer running.
+///
+/// Returns one event per process added or removed, in that order.
+///
+/// # Arguments
+///
+/// - `proc_name`: The name of the processes to track
+/// - `node_id`: This node's ID
+/// - `procs`: The tracked processes
+/// - `source`: Where to read the running processes from
+pub fn discover(
+    proc_name: &str,
+    node_id: u8,
+    procs: &mut HashMap<i32, ProcData>,
+    source: &mut dyn SystemSource,
+) -> Vec<ProcessEvent> {
+    let mut events = Vec::new();
+    let running = source.processes();
+
+    for p in running.iter().filter(|p| p.name == proc_name) {
+        if let Entry::Vacant(e) = procs.entry(p.pid) {
+            e.insert(ProcData::from_info(p, node_id));
+            events.push(ProcessEvent::new(
+                node_id,
+                p.pid,
+                ProcessEventKind::Appeared,
+            ));
+        }
+    }
+
+    let alive: HashSet<i32> = running.iter().map(|p| p.pid).collect();
+    let gone: Vec<i32> = procs
+        .keys()
+        .filter(|pid| !alive.contains(pid))
+        .copied()
+        .collect();
+
+    for pid in gone {
+        procs.remove(&pid);
+        events.push(ProcessEvent::new(
+            node_id,
+            pid,
+            ProcessEventKind::Disappeared,
+        ));
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::sampler::ProcSample;
+    use crate::monitor::source::{FakeSource, NodeInfo, ProcessInfo};
+
+    fn process(pid: i32, name: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: name.to_owned(),
+            usage: ProcSample::default(),
+        }
+    }
+
+    #[test]
+    fn reports_processes_that_appear_and_disappear() {
+        let mut source = FakeSource::new(NodeInfo::default());
+        source
+            .push_processes(vec![process(1, "dwm"), process(2, "bash")])
+            .push_processes(vec![process(1, "dwm"), process(3, "dwm")])
+            .push_processes(vec![process(3, "dwm")]);
+
+        let mut procs = ProcData::fetch_all("dwm", 0, &mut source);
+
+        let events = discover("dwm", 0, &mut procs, &mut source);
+        assert_eq!(
+            events,
+            vec![ProcessEvent::new(0, 3, ProcessEventKind::Appeared)]
+        );
+
+        let events = discover("dwm", 0, &mut procs, &mut source);
+        assert_eq!(
+            events,
+            vec![ProcessEvent::new(0, 1, ProcessEventKind::Disappeared)]
+        );
+        assert!(procs.contains_key(&3) && procs.len() == 1);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::communication::http_requests::{to_bytes, MessageKind, RequestSerializable, Stamp};
+use crate::config::{AlertRule, Encoding, Severity};
+use crate::monitor::memory::OomRisk;
+use serde::{Serialize, Serializer};
+
+/// The kinds of lifecycle changes a tracked process can go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ProcessEventKind {
+    /// A new process with the monitored name was found
+    #[serde(rename = "processAppeared")]
+    Appeared,
+    /// A tracked process is no longer running
+    #[serde(rename = "processDisappeared")]
+    Disappeared,
+    /// A process reported the end of its run
+    #[serde(rename = "processFinished")]
+    Finished,
+    /// The connection of a process broke before the end of its run
+    #[serde(rename = "processFailed")]
+    Failed,
+}
+
+/// Notifies the server of a change in the set of tracked processes
+///
+/// # Properties
+/// -`node_id`: The id of the node the process belongs to
+/// -`pid`: The PID of the process
+/// -`kind`: What happened to the process
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessEvent {
+    #[serde(rename = "event")]
+    kind: ProcessEventKind,
+    node_id: u8,
+    pid: i32,
+}
+
+impl ProcessEvent {
+    pub fn new(node_id: u8, pid: i32, kind: ProcessEventKind) -> Self {
+        ProcessEvent { node_id, pid, kind }
+    }
+}
+
+impl RequestSerializable for ProcessEvent {
+    fn kind(&self) -> MessageKind {
+        MessageKind::Event
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// Warns the server the node is about to run out of memory
+///
+/// # Properties
+/// -`node_id`: The id of the node running out of memory
+/// -`pid`: The PID of the tracked process most likely to be killed, if any
+/// -`risk`: The estimated risk of running out of memory
+/// -`available_ram`: The RAM that can still be allocated without swapping, in KB
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename = "oomWarning", rename_all = "camelCase")]
+pub struct OomWarning {
+    node_id: u8,
+    pid: Option<i32>,
+    #[serde(flatten)]
+    risk: OomRisk,
+    available_ram: u64,
+}
+
+impl OomWarning {
+    pub fn new(node_id: u8, pid: Option<i32>, risk: OomRisk, available_ram: u64) -> Self {
+        OomWarning {
+            node_id,
+            pid,
+            risk,
+            available_ram,
+        }
+    }
+}
+
+impl RequestSerializable for OomWarning {
+    fn kind(&self) -> MessageKind {
+        MessageKind::Event
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// Notifies the server an alert rule started or stopped firing
+///
+/// # Properties
+/// -`rule`: The name of the rule
+/// -`firing`: Whether the alert fired or resolved
+/// -`severity`: How serious the alert is
+/// -`node_id`: The id of the node the alert was raised on
+/// -`pid`: The PID of the process the alert was raised on, if the rule applies to processes
+/// -`metric`: The name of the metric the rule watches
+/// -`value`: The value of the metric when the alert fired or resolved
+/// -`threshold`: The threshold of the rule
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertEvent {
+    #[serde(rename = "event", serialize_with = "alert_event_name")]
+    firing: bool,
+    rule: String,
+    severity: Severity,
+    node_id: u8,
+    pid: Option<i32>,
+    metric: String,
+    value: f32,
+    threshold: f32,
+}
+
+impl AlertEvent {
+    pub fn new(rule: &AlertRule, firing: bool, node_id: u8, pid: Option<i32>, value: f32) -> Self {
+        AlertEvent {
+            firing,
+            rule: rule.name.clone(),
+            severity: rule.severity,
+            node_id,
+            pid,
+            metric: rule.metric.clone(),
+            value,
+            threshold: rule.threshold,
+        }
+    }
+}
+
+impl RequestSerializable for AlertEvent {
+    fn kind(&self) -> MessageKind {
+        MessageKind::Event
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// Sends whether an alert fired or resolved as the name of the event
+fn alert_event_name<S: Serializer>(firing: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if *firing {
+        "alertFiring"
+    } else {
+        "alertResolved"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::http_requests::SCHEMA_VERSION;
+    use crate::monitor::memory::OomLevel;
+    use serde_json::{json, Value};
+
+    fn sent(message: &dyn RequestSerializable) -> Value {
+        serde_json::from_str(&message.serialize()).unwrap()
+    }
+
+    #[test]
+    fn events_keep_the_wire_format() {
+        assert_eq!(
+            sent(&ProcessEvent::new(2, 10, ProcessEventKind::Appeared)),
+            json!({"schemaVersion": SCHEMA_VERSION, "event": "processAppeared", "nodeId": 2, "pid": 10})
+        );
+
+        let risk = OomRisk {
+            level: OomLevel::Critical,
+            seconds_left: Some(12.5),
+        };
+        assert_eq!(
+            sent(&OomWarning::new(2, None, risk, 1024)),
+            json!({
+                "schemaVersion": SCHEMA_VERSION,
+                "event": "oomWarning",
+                "nodeId": 2,
+                "pid": null,
+                "level": "critical",
+                "secondsLeft": 12.5,
+                "availableRam": 1024
+            })
+        );
+    }
+}
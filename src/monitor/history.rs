@@ -0,0 +1,120 @@
+//! Keeps the recent states of the node and of the tracked processes, so they can be queried
+//! after the fact.
+//!
+//! A copy of the node and of every process is kept after each sample of the system usage, and
+//! dropped once it is older than [HistorySettings::max_age_s].
+
+use crate::config::HistorySettings;
+use crate::monitor::stats::{NodeData, ProcData};
+use std::collections::{HashMap, VecDeque};
+
+/// The state of the node and of the processes at the time of a sample
+///
+/// # Properties
+/// -`taken_at_ms`: When the sample was taken, in milliseconds since the UNIX epoch
+/// -`node`: The node's object
+/// -`processes`: Every tracked process, sorted by PID
+pub struct HistorySample {
+    pub taken_at_ms: u64,
+    pub node: NodeData,
+    pub processes: Vec<ProcData>,
+}
+
+/// The recent samples, oldest first
+pub struct History {
+    settings: HistorySettings,
+    samples: VecDeque<HistorySample>,
+}
+
+impl History {
+    pub fn new(settings: HistorySettings) -> Self {
+        History {
+            settings,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Keeps a copy of the node and of the processes, and forgets the samples that are too old
+    ///
+    /// # Arguments
+    ///
+    /// - `taken_at_ms`: When the sample was taken
+    /// - `node`: The node's object
+    /// - `procs`: The tracked processes
+    pub fn record(&mut self, taken_at_ms: u64, node: &NodeData, procs: &HashMap<i32, ProcData>) {
+        let mut processes: Vec<ProcData> = procs.values().cloned().collect();
+        processes.sort_unstable_by_key(|p| p.get_pid());
+
+        self.samples.push_back(HistorySample {
+            taken_at_ms,
+            node: node.clone(),
+            processes,
+        });
+
+        let oldest = taken_at_ms.saturating_sub(self.settings.max_age_s * 1000);
+        while let Some(s) = self.samples.front() {
+            if s.taken_at_ms >= oldest {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Estimates how long a process needs to complete its task, from how fast its progress grew
+    /// since the oldest sample kept. Returns `None` while the progress is not growing.
+    ///
+    /// # Arguments
+    ///
+    /// - `p`: The process, as it is now
+    /// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+    pub fn eta_s(&self, p: &ProcData, now_ms: u64) -> Option<f32> {
+        let (then_ms, then) = self.samples.iter().find_map(|s| {
+            let then = s.processes.iter().find(|o| o.get_pid() == p.get_pid())?;
+            Some((s.taken_at_ms, then.metric("progress")?))
+        })?;
+        let progress = p.metric("progress")?;
+
+        let elapsed_s = now_ms.saturating_sub(then_ms) as f32 / 1000.0;
+        let rate = (progress - then) / elapsed_s;
+        if !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+
+        Some((100.0 - progress).max(0.0) / rate)
+    }
+
+    /// Returns the samples taken at or after a time, oldest first
+    ///
+    /// # Arguments
+    ///
+    /// - `from_ms`: The time of the oldest sample to return, in milliseconds since the UNIX epoch
+    pub fn since(&self, from_ms: u64) -> impl Iterator<Item = &HistorySample> {
+        self.samples
+            .iter()
+            .filter(move |s| s.taken_at_ms >= from_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::sampler::Snapshot;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+
+    #[test]
+    fn forgets_the_samples_older_than_the_max_age() {
+        let node = NodeData::new(&mut FakeSource::new(NodeInfo::default()));
+        let mut procs = HashMap::new();
+        procs.insert(10, ProcData::new(10, 0, &Snapshot::default()));
+
+        let mut history = History::new(HistorySettings { max_age_s: 10 });
+        for t in [0, 5_000, 10_000, 15_000] {
+            history.record(t, &node, &procs);
+        }
+
+        let times: Vec<u64> = history.since(0).map(|s| s.taken_at_ms).collect();
+        assert_eq!(times, vec![5_000, 10_000, 15_000]);
+        assert_eq!(history.since(10_000).count(), 2);
+        assert_eq!(history.since(0).next().unwrap().processes.len(), 1);
+    }
+}
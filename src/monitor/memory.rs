@@ -0,0 +1,232 @@
+//! Estimates how close the node is to running out of memory.
+//!
+//! DWM partitions that are too large push the node into swap long before the kernel OOM killer
+//! steps in, so the estimate combines the memory still available, the rate at which the tracked
+//! processes are growing and the memory pressure reported by the kernel (PSI).
+
+use crate::config::MemorySettings;
+use crate::monitor::events::OomWarning;
+use crate::monitor::sampler::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The file the kernel exposes the memory pressure stall information in
+const PRESSURE_FILE: &str = "/proc/pressure/memory";
+
+/// The share of time, in %, tasks were stalled waiting for memory over the last 10 seconds
+///
+/// # Properties
+/// -`some`: The time at least one task was stalled
+/// -`full`: The time all non-idle tasks were stalled at once
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryPressure {
+    pub some: f32,
+    pub full: f32,
+}
+
+/// Reads the memory pressure of the node
+///
+/// Returns `None` if the kernel does not support PSI.
+pub fn read_pressure() -> Option<MemoryPressure> {
+    parse_pressure(&fs::read_to_string(PRESSURE_FILE).ok()?)
+}
+
+/// Parses the contents of `/proc/pressure/memory`
+///
+/// # Format
+/// ```text
+/// some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+/// full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+/// ```
+pub fn parse_pressure(contents: &str) -> Option<MemoryPressure> {
+    let mut pressure = MemoryPressure::default();
+
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next()?;
+        let avg10 = fields
+            .find_map(|f| f.strip_prefix("avg10="))?
+            .parse()
+            .ok()?;
+
+        match kind {
+            "some" => pressure.some = avg10,
+            "full" => pressure.full = avg10,
+            _ => {}
+        }
+    }
+
+    Some(pressure)
+}
+
+/// How likely the node is to run out of memory, from lowest to highest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OomLevel {
+    #[default]
+    Low,
+    Elevated,
+    High,
+    Critical,
+}
+
+/// The estimated risk of the node running out of memory
+///
+/// # Properties
+/// -`level`: How likely the node is to run out of memory
+/// -`seconds_left`: The time until the available memory and swap are exhausted at the current
+///   growth rate of the tracked processes, if they are growing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OomRisk {
+    pub level: OomLevel,
+    pub seconds_left: Option<f32>,
+}
+
+/// Estimates the risk of the node running out of memory
+///
+/// # Arguments
+///
+/// - `snapshot`: The latest sample of the system usage
+/// - `settings`: The thresholds of each level
+pub fn estimate(snapshot: &Snapshot, settings: &MemorySettings) -> OomRisk {
+    let free_swap = snapshot.total_swap.saturating_sub(snapshot.used_swap);
+    let left = (snapshot.available_ram + free_swap) as f32;
+
+    let growth: f32 = snapshot
+        .processes
+        .values()
+        .map(|p| p.ram_growth.max(0.0))
+        .sum();
+
+    let seconds_left = if growth > 0.0 {
+        Some(left / growth)
+    } else {
+        None
+    };
+
+    let pressure = snapshot.pressure.unwrap_or_default();
+    let swapping = snapshot.used_swap > 0;
+    let time_under = |limit: u64| seconds_left.is_some_and(|s| s < limit as f32);
+
+    let level = if time_under(settings.critical_seconds) || pressure.full >= settings.full_pressure
+    {
+        OomLevel::Critical
+    } else if time_under(settings.warn_seconds) || pressure.some >= settings.some_pressure {
+        OomLevel::High
+    } else if swapping || pressure.some > 0.0 {
+        OomLevel::Elevated
+    } else {
+        OomLevel::Low
+    };
+
+    OomRisk {
+        level,
+        seconds_left,
+    }
+}
+
+/// Tracks the risk over time and decides when to warn the server
+///
+/// A warning is raised every time the risk rises to [OomLevel::High] or above. It is not repeated
+/// while the risk stays there, unless it gets worse.
+pub struct OomWatch {
+    settings: MemorySettings,
+    last: OomLevel,
+}
+
+impl OomWatch {
+    pub fn new(settings: MemorySettings) -> Self {
+        OomWatch {
+            settings,
+            last: OomLevel::Low,
+        }
+    }
+
+    /// Stores the estimated risk in the snapshot and returns a warning if it got high enough
+    ///
+    /// # Arguments
+    ///
+    /// - `snapshot`: The latest sample of the system usage
+    /// - `node_id`: This node's ID
+    pub fn observe(&mut self, snapshot: &mut Snapshot, node_id: u8) -> Option<OomWarning> {
+        let risk = estimate(snapshot, &self.settings);
+        snapshot.oom_risk = risk;
+
+        let previous = self.last;
+        self.last = risk.level;
+
+        if risk.level < OomLevel::High || risk.level <= previous {
+            return None;
+        }
+
+        // the kernel picks the process using the most memory
+        let pid = snapshot
+            .processes
+            .iter()
+            .max_by_key(|(_pid, p)| p.ram)
+            .map(|(pid, _p)| *pid);
+
+        Some(OomWarning::new(node_id, pid, risk, snapshot.available_ram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::sampler::ProcSample;
+
+    fn snapshot(available_ram: u64, growth: f32) -> Snapshot {
+        let mut s = Snapshot {
+            available_ram,
+            ..Default::default()
+        };
+        s.processes.insert(
+            1,
+            ProcSample {
+                ram: 500_000,
+                ram_growth: growth,
+                ..Default::default()
+            },
+        );
+        s
+    }
+
+    #[test]
+    fn parses_the_pressure_file() {
+        let contents = "some avg10=12.50 avg60=3.00 avg300=1.00 total=100\n\
+                        full avg10=1.25 avg60=0.00 avg300=0.00 total=10\n";
+
+        assert_eq!(
+            parse_pressure(contents),
+            Some(MemoryPressure {
+                some: 12.5,
+                full: 1.25
+            })
+        );
+        assert_eq!(parse_pressure("some total=1"), None);
+    }
+
+    #[test]
+    fn warns_once_when_the_memory_runs_out() {
+        let mut watch = OomWatch::new(MemorySettings::default());
+
+        let mut s = snapshot(100_000, 0.0);
+        assert!(watch.observe(&mut s, 0).is_none());
+        assert_eq!(s.oom_risk.level, OomLevel::Low);
+
+        // 100 MB left, growing 1 MB/s
+        let mut s = snapshot(100_000, 1_000.0);
+        let warning = watch.observe(&mut s, 0);
+        assert_eq!(s.oom_risk.level, OomLevel::High);
+        assert_eq!(s.oom_risk.seconds_left, Some(100.0));
+        assert!(warning.is_some());
+
+        let mut s = snapshot(90_000, 1_000.0);
+        assert!(watch.observe(&mut s, 0).is_none());
+
+        let mut s = snapshot(20_000, 1_000.0);
+        assert!(watch.observe(&mut s, 0).is_some());
+        assert_eq!(s.oom_risk.level, OomLevel::Critical);
+    }
+}
@@ -0,0 +1,107 @@
+//! Samples the system usage of the node and of the tracked processes in the background.
+//!
+//! Refreshing sysinfo is expensive, so it is only done here, on a fixed interval, and only for
+//! the data the monitor needs (see [SysinfoSource](crate::monitor::source::SysinfoSource)).
+//! Everything else reads the latest [Snapshot].
+//! Sampling on a fixed interval is also what makes the CPU usage reported by sysinfo meaningful,
+//! since it is computed from the time elapsed between two refreshes.
+
+use crate::monitor::memory::{MemoryPressure, OomRisk};
+use crate::monitor::source::SystemSource;
+use crate::monitor::stats::ProcData;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// The usage data of a process at the time of a sample
+///
+/// # Properties
+/// -`cpu`: The CPU usage of the process
+/// -`ram`: The RAM consumed by the process, in KB
+/// -`ram_growth`: How fast the RAM consumed by the process grew since the previous sample,
+///   in KB/s
+#[derive(Debug, Clone, Default)]
+pub struct ProcSample {
+    pub cpu: f32,
+    pub ram: u64,
+    pub ram_growth: f32,
+}
+
+/// The usage data of the node and of the tracked processes at the time of a sample
+///
+/// # Properties
+/// -`taken_at_ms`: When the sample was taken, in milliseconds since the UNIX epoch
+/// -`cpu_usage`: The percentage of the CPU used in total
+/// -`used_ram`: The RAM used in the node, in KB
+/// -`available_ram`: The RAM that can still be allocated without swapping, in KB
+/// -`used_swap`: The swap used in the node, in KB
+/// -`total_swap`: The swap available on the node, in KB
+/// -`pressure`: The memory pressure, if the kernel reports it
+/// -`oom_risk`: The estimated risk of running out of memory
+/// -`temperature`: The temperature in ºC of each CPU core
+/// -`frequency`: The average frequency of the CPU cores, in MHz, if it can be read
+/// -`processes`: The usage of each tracked process still running, by PID
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub taken_at_ms: u64,
+    pub cpu_usage: f32,
+    pub used_ram: u64,
+    pub available_ram: u64,
+    pub used_swap: u64,
+    pub total_swap: u64,
+    pub pressure: Option<MemoryPressure>,
+    pub oom_risk: OomRisk,
+    pub temperature: Vec<f32>,
+    pub frequency: Option<u64>,
+    pub processes: HashMap<i32, ProcSample>,
+}
+
+impl Snapshot {
+    /// Computes how fast each process grew since the previous sample
+    ///
+    /// # Arguments
+    ///
+    /// - `previous`: The sample taken before this one
+    pub fn compute_growth(&mut self, previous: &Snapshot) {
+        let elapsed = self.taken_at_ms.saturating_sub(previous.taken_at_ms) as f32 / 1000.0;
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        for (pid, p) in self.processes.iter_mut() {
+            if let Some(prev) = previous.processes.get(pid) {
+                p.ram_growth = (p.ram as f32 - prev.ram as f32) / elapsed;
+            }
+        }
+    }
+}
+
+/// Samples the system usage forever, replacing the latest snapshot after each sample
+///
+/// # Arguments
+///
+/// - `source`: Where to read the system usage from
+/// - `procs`: The tracked processes
+/// - `snapshot`: Where to store the latest snapshot
+/// - `interval`: The time between two samples
+/// - `on_sample`: Called with each new sample before it replaces the latest snapshot
+pub fn start_sampler<S: SystemSource, F: FnMut(&mut Snapshot)>(
+    mut source: S,
+    procs: &Mutex<HashMap<i32, ProcData>>,
+    snapshot: &Mutex<Snapshot>,
+    interval: Duration,
+    mut on_sample: F,
+) {
+    loop {
+        let pids: Vec<i32> = procs.lock().unwrap().keys().copied().collect();
+        let mut s = source.sample(&pids);
+
+        s.compute_growth(&snapshot.lock().unwrap());
+        on_sample(&mut s);
+
+        *snapshot.lock().unwrap() = s;
+
+        thread::sleep(interval);
+    }
+}
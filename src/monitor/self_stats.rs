@@ -0,0 +1,125 @@
+//! The monitor's own metrics, as opposed to the ones it collects on the node.
+
+use crate::communication::http_requests::{to_bytes, MessageKind, RequestSerializable, Stamp};
+use crate::config::Encoding;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The state of the connection to the room partitioner server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected = 0,
+    Connecting = 1,
+    Connected = 2,
+}
+
+impl ConnectionState {
+    /// The name of the state as sent to the server
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            2 => ConnectionState::Connected,
+            1 => ConnectionState::Connecting,
+            _ => ConnectionState::Disconnected,
+        }
+    }
+}
+
+/// The counters of one sink
+///
+/// # Properties
+/// -`name`: The name of the sink
+/// -`state`: The state of the connection to the server
+/// -`connects`: The number of times the connection to the server was established
+/// -`failures`: The number of failed connection attempts, broken connections and failed requests
+/// -`sent`: The number of messages delivered
+/// -`dropped`: The number of messages dropped because the queue was full or delivery failed
+/// -`rate_limited`: The number of messages dropped by the rate limit of the sink
+/// -`queued`: The number of messages waiting to be sent
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SinkStats {
+    pub name: String,
+    #[serde(serialize_with = "state_name")]
+    state: AtomicU8,
+    pub connects: AtomicU64,
+    pub failures: AtomicU64,
+    pub sent: AtomicU64,
+    pub dropped: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub queued: AtomicU64,
+}
+
+impl SinkStats {
+    pub fn new(name: &str) -> Self {
+        SinkStats {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        ConnectionState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+}
+
+/// Counters shared by every thread of the monitor
+///
+/// # Properties
+/// -`sinks`: The counters of every sink the updates go to
+/// -`rejected_ingest`: The number of clients of the ingest server turned away for a missing or
+///   wrong token
+/// -`rejected_files`: The number of files turned away for a missing or wrong token
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitorStats {
+    sinks: Mutex<Vec<Arc<SinkStats>>>,
+    pub rejected_ingest: AtomicU64,
+    pub rejected_files: AtomicU64,
+}
+
+impl MonitorStats {
+    /// Adds the counters of a new sink
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the sink
+    pub fn register_sink(&self, name: &str) -> Arc<SinkStats> {
+        let stats = Arc::new(SinkStats::new(name));
+        self.sinks.lock().unwrap().push(Arc::clone(&stats));
+
+        stats
+    }
+
+    /// Returns the counters of every sink
+    pub fn sinks(&self) -> Vec<Arc<SinkStats>> {
+        self.sinks.lock().unwrap().clone()
+    }
+}
+
+impl RequestSerializable for MonitorStats {
+    fn kind(&self) -> MessageKind {
+        MessageKind::Stats
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// Sends the state of the connection by name
+fn state_name<S: Serializer>(state: &AtomicU8, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(ConnectionState::from_u8(state.load(Ordering::Relaxed)).name())
+}
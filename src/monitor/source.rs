@@ -0,0 +1,223 @@
+//! Abstracts where the system usage data comes from.
+//!
+//! The monitor reads the node through [SysinfoSource]. The tests replay scripted readings through
+//! [FakeSource] instead, so the collectors in [crate::monitor::stats] can be checked without
+//! depending on the machine they run on.
+
+use crate::monitor::memory::{read_pressure, OomRisk};
+use crate::monitor::sampler::{ProcSample, Snapshot};
+use std::collections::HashMap;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sysinfo::{
+    ComponentExt, ProcessExt, Processor, ProcessorExt, RefreshKind, System as Sys, SystemExt,
+};
+
+/// The data of the node that does not change while the monitor is running
+///
+/// # Properties
+/// -`cores`: The number of physical cores of the node
+/// -`threads`: The number of logical processors of the node
+/// -`total_ram`: The total RAM available on the node
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfo {
+    pub cores: usize,
+    pub threads: usize,
+    pub total_ram: u64,
+}
+
+/// A process running on the node
+///
+/// # Properties
+/// -`pid`: The PID of the process
+/// -`name`: The name of the process
+/// -`usage`: The CPU and RAM used by the process
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub name: String,
+    pub usage: ProcSample,
+}
+
+/// Provides the readings of the system the collectors work on
+pub trait SystemSource {
+    /// Returns the data of the node that does not change over time
+    fn node_info(&mut self) -> NodeInfo;
+
+    /// Takes a new reading of the node and of the given processes
+    ///
+    /// # Arguments
+    ///
+    /// - `pids`: The PIDs of the tracked processes
+    fn sample(&mut self, pids: &[i32]) -> Snapshot;
+
+    /// Lists every process currently running on the node
+    fn processes(&mut self) -> Vec<ProcessInfo>;
+}
+
+/// Reads the live system with sysinfo
+///
+/// Only the data the monitor needs is refreshed on each call.
+pub struct SysinfoSource {
+    sys: Sys,
+}
+
+impl SysinfoSource {
+    pub fn new() -> Self {
+        let sys = Sys::new_with_specifics(
+            RefreshKind::new()
+                .with_cpu()
+                .with_memory()
+                .with_components_list(),
+        );
+
+        SysinfoSource { sys }
+    }
+}
+
+/// Returns the host name of the node, or `node` when it cannot be read
+pub fn host_name() -> String {
+    Sys::new().host_name().unwrap_or_else(|| "node".to_owned())
+}
+
+/// Returns the average frequency of the processors, or `None` when it cannot be read, such as in
+/// some virtual machines
+fn average_frequency(processors: &[Processor]) -> Option<u64> {
+    let total: u64 = processors.iter().map(|p| p.frequency()).sum();
+
+    match total {
+        0 => None,
+        _ => Some(total / processors.len() as u64),
+    }
+}
+
+impl SystemSource for SysinfoSource {
+    fn node_info(&mut self) -> NodeInfo {
+        NodeInfo {
+            cores: self.sys.physical_core_count().unwrap_or(0),
+            threads: self.sys.processors().len(),
+            total_ram: self.sys.total_memory(),
+        }
+    }
+
+    fn sample(&mut self, pids: &[i32]) -> Snapshot {
+        self.sys.refresh_cpu();
+        self.sys.refresh_memory();
+        self.sys.refresh_components();
+
+        let mut processes = HashMap::new();
+        for pid in pids {
+            if self.sys.refresh_process(*pid) {
+                if let Some(p) = self.sys.process(*pid) {
+                    processes.insert(
+                        *pid,
+                        ProcSample {
+                            cpu: p.cpu_usage(),
+                            ram: p.memory(),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        }
+
+        Snapshot {
+            taken_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            cpu_usage: self.sys.global_processor_info().cpu_usage(),
+            used_ram: self.sys.used_memory(),
+            available_ram: self.sys.available_memory(),
+            used_swap: self.sys.used_swap(),
+            total_swap: self.sys.total_swap(),
+            pressure: read_pressure(),
+            oom_risk: OomRisk::default(),
+            temperature: self
+                .sys
+                .components()
+                .iter()
+                .filter(|comp| comp.label().starts_with("Core "))
+                .map(|comp| comp.temperature())
+                .collect(),
+            frequency: average_frequency(self.sys.processors()),
+            processes,
+        }
+    }
+
+    fn processes(&mut self) -> Vec<ProcessInfo> {
+        self.sys.refresh_processes();
+
+        self.sys
+            .processes()
+            .iter()
+            .map(|(pid, p)| ProcessInfo {
+                pid: *pid,
+                name: p.name().to_owned(),
+                usage: ProcSample {
+                    cpu: p.cpu_usage(),
+                    ram: p.memory(),
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+}
+
+/// Replays scripted readings, one per call, in the order they were pushed
+///
+/// Once the script runs out, the last reading is repeated.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeSource {
+    info: NodeInfo,
+    snapshots: VecDeque<Snapshot>,
+    process_lists: VecDeque<Vec<ProcessInfo>>,
+}
+
+#[cfg(test)]
+impl FakeSource {
+    pub fn new(info: NodeInfo) -> Self {
+        FakeSource {
+            info,
+            ..Default::default()
+        }
+    }
+
+    /// Queues the reading returned by a future call to [SystemSource::sample]
+    pub fn push_snapshot(&mut self, snapshot: Snapshot) -> &mut Self {
+        self.snapshots.push_back(snapshot);
+        self
+    }
+
+    /// Queues the list returned by a future call to [SystemSource::processes]
+    pub fn push_processes(&mut self, processes: Vec<ProcessInfo>) -> &mut Self {
+        self.process_lists.push_back(processes);
+        self
+    }
+
+    fn next<T: Clone + Default>(queue: &mut VecDeque<T>) -> T {
+        if queue.len() > 1 {
+            queue.pop_front().unwrap()
+        } else {
+            queue.front().cloned().unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl SystemSource for FakeSource {
+    fn node_info(&mut self) -> NodeInfo {
+        self.info.clone()
+    }
+
+    fn sample(&mut self, pids: &[i32]) -> Snapshot {
+        let mut s = Self::next(&mut self.snapshots);
+        s.processes.retain(|pid, _| pids.contains(pid));
+        s
+    }
+
+    fn processes(&mut self) -> Vec<ProcessInfo> {
+        Self::next(&mut self.process_lists)
+    }
+}
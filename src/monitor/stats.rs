@@ -1,7 +1,10 @@
-use crate::communication::http_requests::RequestSerializable;
-use rand::Rng;
+use crate::communication::http_requests::{to_bytes, MessageKind, RequestSerializable, Stamp};
+use crate::config::Encoding;
+use crate::monitor::memory::{MemoryPressure, OomLevel};
+use crate::monitor::sampler::Snapshot;
+use crate::monitor::source::{ProcessInfo, SystemSource};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use sysinfo::{ComponentExt, ProcessExt, ProcessorExt, System as Sys, SystemExt};
 
 /// Stores usage data relative to the node
 ///
@@ -15,79 +18,159 @@ use sysinfo::{ComponentExt, ProcessExt, ProcessorExt, System as Sys, SystemExt};
 /// -`cpu_usage`: The percentage of the CPU used in total
 /// -`total_ram`: The total RAM available on the node
 /// -`used_ram`: The RAM used in the node
+/// -`available_ram`: The RAM that can still be allocated without swapping
+/// -`used_swap`: The swap used in the node
+/// -`total_swap`: The swap available on the node
+/// -`pressure`: The memory pressure, if the kernel reports it
+/// -`oom_risk`: How likely the node is to run out of memory
 /// -`temperature`: The temperature in ºC of each CPU core
-#[derive(Debug)]
+/// -`frequency`: The average frequency of the CPU cores, in MHz, if it can be read. Only for the
+///   tools of the node, it is left out of the messages
+/// -`ingested_at`: When the latest message from the cluster program was received, in milliseconds
+///   since the UNIX epoch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct NodeData {
     node_id: u8,
     cores: usize,
     threads: usize,
+    #[serde(rename = "cpu")]
     cpu_usage: f32,
     total_ram: u64,
     used_ram: u64,
+    available_ram: u64,
+    used_swap: u64,
+    total_swap: u64,
+    #[serde(flatten, with = "pressure_fields")]
+    pressure: Option<MemoryPressure>,
+    oom_risk: OomLevel,
     temperature: Vec<f32>,
+    #[serde(default, skip_serializing)]
+    frequency: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ingested_at: Option<u64>,
 }
 
 impl NodeData {
-    /// Populates a new NodeData struct with data retrieved with sysinfo
-    pub fn new() -> Self {
-        let s = Sys::new_all();
+    /// The name of every metric [NodeData::metric] returns
+    pub const METRICS: [&'static str; 13] = [
+        "cores",
+        "threads",
+        "cpu_usage",
+        "frequency",
+        "total_ram",
+        "used_ram",
+        "available_ram",
+        "used_swap",
+        "total_swap",
+        "pressure_some",
+        "pressure_full",
+        "oom_risk",
+        "temperature",
+    ];
+
+    /// Populates a new NodeData struct with data retrieved from the given source
+    ///
+    /// # Arguments
+    ///
+    /// - `source`: Where to read the system usage from
+    pub fn new(source: &mut dyn SystemSource) -> Self {
+        let info = source.node_info();
+        let s = source.sample(&[]);
 
         let node_id = 0; // rand::thread_rng().gen();
-        let used_ram = s.used_memory();
-        let total_ram = s.total_memory();
-        let cores = s.physical_core_count().unwrap();
-        let threads = s.processors().len();
-        let cpu_usage = s.global_processor_info().cpu_usage();
-
-        let temperature = s
-            .components()
-            .iter()
-            .filter(|comp| comp.label().starts_with("Core "))
-            .map(|comp| comp.temperature())
-            .collect();
 
         //println!("Node created {node_id}");
 
         NodeData {
             node_id,
-            cores,
-            threads,
-            cpu_usage,
-            total_ram,
-            used_ram,
-            temperature,
+            cores: info.cores,
+            threads: info.threads,
+            cpu_usage: s.cpu_usage,
+            total_ram: info.total_ram,
+            used_ram: s.used_ram,
+            available_ram: s.available_ram,
+            used_swap: s.used_swap,
+            total_swap: s.total_swap,
+            pressure: s.pressure,
+            oom_risk: s.oom_risk.level,
+            temperature: s.temperature,
+            frequency: s.frequency,
+            ingested_at: None,
         }
     }
 
     /// Updates volatile data.
     /// - CPU usage
-    /// - used RAM
+    /// - used and available RAM
+    /// - swap
+    /// - memory pressure and out of memory risk
     /// - temperature (ºC)
+    /// - frequency (MHz)
     ///
     /// # Arguments
     ///
-    /// - `sys`: An instance of [Sys] to get the usage data
-    pub fn update(&mut self, sys: &mut Sys) {
-        sys.refresh_all();
-
-        self.cpu_usage = sys.global_processor_info().cpu_usage();
-        self.used_ram = sys.used_memory();
+    /// - `snapshot`: The latest sample of the system usage
+    pub fn update(&mut self, snapshot: &Snapshot) {
+        self.cpu_usage = snapshot.cpu_usage;
+        self.used_ram = snapshot.used_ram;
+        self.available_ram = snapshot.available_ram;
+        self.used_swap = snapshot.used_swap;
+        self.total_swap = snapshot.total_swap;
+        self.pressure = snapshot.pressure;
+        self.oom_risk = snapshot.oom_risk.level;
+        self.temperature = snapshot.temperature.clone();
+        self.frequency = snapshot.frequency;
+    }
 
-        self.temperature = sys
-            .components()
-            .iter()
-            .filter(|comp| comp.label().starts_with("Core "))
-            .map(|comp| comp.temperature())
-            .collect();
+    /// Returns the value of a numeric field, by name.
+    /// For the temperature, the hottest core is returned.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the field
+    pub fn metric(&self, name: &str) -> Option<f32> {
+        match name {
+            "cores" => Some(self.cores as f32),
+            "threads" => Some(self.threads as f32),
+            "cpu_usage" => Some(self.cpu_usage),
+            "total_ram" => Some(self.total_ram as f32),
+            "used_ram" => Some(self.used_ram as f32),
+            "available_ram" => Some(self.available_ram as f32),
+            "used_swap" => Some(self.used_swap as f32),
+            "total_swap" => Some(self.total_swap as f32),
+            "pressure_some" => self.pressure.map(|p| p.some),
+            "pressure_full" => self.pressure.map(|p| p.full),
+            "oom_risk" => Some(self.oom_risk as u8 as f32),
+            "temperature" => self.temperature.iter().copied().reduce(f32::max),
+            "frequency" => self.frequency.map(|f| f as f32),
+            _ => None,
+        }
     }
 
     pub fn get_id(&self) -> u8 {
         self.node_id
     }
 
+    pub fn get_frequency(&self) -> Option<u64> {
+        self.frequency
+    }
+
+    pub fn get_temperature(&self) -> &[f32] {
+        &self.temperature
+    }
+
+    pub fn get_oom_risk(&self) -> OomLevel {
+        self.oom_risk
+    }
+
     pub fn set_id(&mut self, id: u8) {
         self.node_id = id;
     }
+
+    pub fn set_ingested_at(&mut self, at_ms: u64) {
+        self.ingested_at = Some(at_ms);
+    }
 }
 
 /// Stores data relative to a process.
@@ -102,105 +185,171 @@ impl NodeData {
 /// -`pid`: The PID of this process
 /// -`cpu`: The CPU usage of this process
 /// -`ram`: The RAM consumed by this process
+/// -`ram_growth`: How fast the RAM consumed by this process is growing, per second
 /// -`send_t`: The time it took to send data to the neighbor nodes
 /// -`recv_t`: The time it took to receive data from the neighbor nodes
 /// -`delay_t`: The time the delay pass took
 /// -`scatter_t`: The time the scatter pass took
 /// -`progress`: The progress percentage
+/// -`ingested_at`: When the latest message from this process was received, in milliseconds since
+///   the UNIX epoch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ProcData {
     node_id: u8,
     pid: i32,
     cpu: f32,
     ram: u64,
+    ram_growth: f32,
+    #[serde(rename = "sendTime")]
     send_t: f32,
+    #[serde(rename = "receiveTime")]
     recv_t: f32,
+    #[serde(rename = "delayTime")]
     delay_t: f32,
+    #[serde(rename = "scatterTime")]
     scatter_t: f32,
     progress: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ingested_at: Option<u64>,
 }
 
 impl ProcData {
+    /// The name of every metric [ProcData::metric] returns
+    pub const METRICS: [&'static str; 8] = [
+        "cpu",
+        "ram",
+        "ram_growth",
+        "send_t",
+        "recv_t",
+        "delay_t",
+        "scatter_t",
+        "progress",
+    ];
+
     /// Generates a new HashMap with all processes with the given name
     ///
     /// # Arguments
     ///
     /// - `proc_name`: The name of the process to analyse
     /// - `node_id`: This node's ID
-    /// - `sys`: An instance of [Sys] to get all the system usage data from
-    pub fn fetch_all(proc_name: &str, node_id: u8, sys: &mut Sys) -> HashMap<i32, Self> {
-        sys.refresh_all();
-
-        let mut map = HashMap::new();
-
-        for (k, v) in sys
+    /// - `source`: Where to read the running processes from
+    pub fn fetch_all(
+        proc_name: &str,
+        node_id: u8,
+        source: &mut dyn SystemSource,
+    ) -> HashMap<i32, Self> {
+        source
             .processes()
             .iter()
-            // .map(|(pid, proc)| proc)
-            .filter(|(_pid, p)| p.name() == proc_name)
-            .map(|(pid, p)| {
-                (
-                    *pid,
-                    Self {
-                        node_id,
-                        pid: *pid,
-                        cpu: p.cpu_usage(),
-                        ram: p.memory(),
-                        send_t: 0.0,
-                        recv_t: 0.0,
-                        delay_t: 0.0,
-                        scatter_t: 0.0,
-                        progress: 0.0,
-                    },
-                )
-            })
-        {
-            map.insert(k, v);
+            .filter(|p| p.name == proc_name)
+            .map(|p| (p.pid, Self::from_info(p, node_id)))
+            .collect()
+    }
+
+    /// Creates the data of a running process
+    ///
+    /// # Arguments
+    ///
+    /// - `info`: The process as listed by a [SystemSource]
+    /// - `node_id`: This node's ID
+    pub fn from_info(info: &ProcessInfo, node_id: u8) -> Self {
+        Self {
+            node_id,
+            pid: info.pid,
+            cpu: info.usage.cpu,
+            ram: info.usage.ram,
+            ram_growth: info.usage.ram_growth,
+            send_t: 0.0,
+            recv_t: 0.0,
+            delay_t: 0.0,
+            scatter_t: 0.0,
+            progress: 0.0,
+            ingested_at: None,
+        }
+    }
+
+    /// Creates the data of a process that is not tracked yet
+    ///
+    /// In case the PID is not in the snapshot, the usage data will be 0 until the next sample.
+    ///
+    /// # Arguments
+    ///
+    /// - `pid`: The PID of the process
+    /// - `node_id`: This node's ID
+    /// - `snapshot`: The latest sample of the system usage
+    pub fn new(pid: i32, node_id: u8, snapshot: &Snapshot) -> Self {
+        let (cpu, ram, ram_growth) = match snapshot.processes.get(&pid) {
+            Some(p) => (p.cpu, p.ram, p.ram_growth),
+            None => (0.0, 0, 0.0),
+        };
+
+        Self {
+            node_id,
+            pid,
+            cpu,
+            ram,
+            ram_growth,
+            send_t: 0.0,
+            recv_t: 0.0,
+            delay_t: 0.0,
+            scatter_t: 0.0,
+            progress: 0.0,
+            ingested_at: None,
         }
+    }
 
-        return map;
+    /// Refreshes the CPU and RAM usage of the process, leaving its progress as is.
+    ///
+    /// In case this object's PID is not found, nothing changes.
+    ///
+    /// # Parameters
+    /// -`snapshot`: The latest sample of the system usage
+    pub fn update_usage(&mut self, snapshot: &Snapshot) {
+        if let Some(p) = snapshot.processes.get(&self.pid) {
+            self.ram = p.ram;
+            self.ram_growth = p.ram_growth;
+            self.cpu = p.cpu;
+        }
     }
 
-    pub fn new(pid: i32, node_id: u8, sys: &mut Sys) -> Self {
-        sys.refresh_all();
-        let p1 = sys
-            .processes()
-            .iter()
-            .filter(|(pid1, _pro)| pid == **pid1)
-            .map(|(_pid1, pro)| pro)
-            .last();
-
-        match p1 {
-            Some(p) => Self {
-                node_id,
-                pid: pid,
-                cpu: p.cpu_usage(),
-                ram: p.memory(),
-                send_t: 0.0,
-                recv_t: 0.0,
-                delay_t: 0.0,
-                scatter_t: 0.0,
-                progress: 0.0,
-            },
-            None => Self {
-                node_id: 0,
-                pid: 0,
-                cpu: 0.0,
-                ram: 0,
-                send_t: 0.0,
-                recv_t: 0.0,
-                delay_t: 0.0,
-                scatter_t: 0.0,
-                progress: 0.0,
-            },
+    pub fn get_pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn get_node_id(&self) -> u8 {
+        self.node_id
+    }
+
+    pub fn set_ingested_at(&mut self, at_ms: u64) {
+        self.ingested_at = Some(at_ms);
+    }
+
+    /// Returns the value of a numeric field, by name
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the field
+    pub fn metric(&self, name: &str) -> Option<f32> {
+        match name {
+            "cpu" => Some(self.cpu),
+            "ram" => Some(self.ram as f32),
+            "ram_growth" => Some(self.ram_growth),
+            "send_t" => Some(self.send_t),
+            "recv_t" => Some(self.recv_t),
+            "delay_t" => Some(self.delay_t),
+            "scatter_t" => Some(self.scatter_t),
+            "progress" => Some(self.progress),
+            _ => None,
         }
     }
 
     /// Updates the volatile data of the process
-    /// - RAM usage
+    /// - RAM usage and growth
     /// - CPU usage
     /// - progress
     ///
-    /// In case this object's PID is not found, all values will be 0.
+    /// In case this object's PID is not in the sample yet, its usage is left as is.
     ///
     /// # Parameters
     /// -`progress`: The progress until the end of the task
@@ -208,7 +357,7 @@ impl ProcData {
     /// -`recv_t`: The time it took to receive data from the neighbor nodes
     /// -`delay_t`: The time the delay pass took
     /// -`scatter_t`: The time the scatter pass took
-    /// -`sys`: An instance of [Sys] to get all the system usage data from
+    /// -`snapshot`: The latest sample of the system usage
     pub fn update(
         &mut self,
         progress: f32,
@@ -216,131 +365,273 @@ impl ProcData {
         recv_t: f32,
         delay_t: f32,
         scatter_t: f32,
-        sys: &mut Sys,
+        snapshot: &Snapshot,
     ) {
-        sys.refresh_all();
-
-        let proc_opt = sys.process(self.pid);
-
-        match proc_opt {
-            Some(p) => {
-                self.ram = p.memory();
-                self.cpu = p.cpu_usage();
-                self.progress = progress;
-                self.send_t = send_t;
-                self.recv_t = recv_t;
-                self.delay_t = delay_t;
-                self.scatter_t = scatter_t;
-            }
-            None => {
-                self.ram = 0;
-                self.cpu = 0.0;
-                self.progress = 0.0;
-                self.send_t = 0.0;
-                self.recv_t = 0.0;
-                self.delay_t = 0.0;
-                self.scatter_t = 0.0;
-            }
-        };
+        self.progress = progress;
+        self.send_t = send_t;
+        self.recv_t = recv_t;
+        self.delay_t = delay_t;
+        self.scatter_t = scatter_t;
+        self.update_usage(snapshot);
     }
 }
 
 impl RequestSerializable for NodeData {
-    fn serialize(&self) -> String {
-        /*
-            cores: usize,
-        threads: usize,
-        cpu_usage: f32,
-        total_ram: u64,
-        used_ram: u64,
-        temperature: Vec<f32>,
-             */
-        let node_id = self.node_id.to_string();
-        let cores = self.cores.to_string();
-        let threads = self.threads.to_string();
-        let cpu_usage = self.cpu_usage.to_string();
-        let total_ram = self.total_ram.to_string();
-        let used_ram = self.used_ram.to_string();
-        let mut temperature = String::from("[");
-
-        println!("SENDING: {node_id}, {cpu_usage}, {total_ram}, {used_ram}");
-
-        // for v in &self.temperature {
-        //     temperature.push_str(&*v.to_string());
-        //     temperature.push_str(", ");
-        // }
-        let siz = self.temperature.len();
-        for i in 0..siz {
-            temperature.push_str(&self.temperature[i].to_string());
-            if i < siz - 1 {
-                temperature.push_str(", ");
-            }
-        }
+    fn kind(&self) -> MessageKind {
+        MessageKind::Node
+    }
 
-        temperature.push(']');
-
-        let res = "{\"nodeId\":".to_owned()
-            + &node_id
-            + ",\"cores\":"
-            + &cores
-            + ",\"threads\":"
-            + &threads
-            + ",\"cpu\":"
-            + &cpu_usage
-            + ",\"totalRam\":"
-            + &total_ram
-            + ",\"usedRam\":"
-            + &used_ram
-            + ",\"temperature\":"
-            + &temperature
-            + "}";
-
-        return res;
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
     }
 }
 
 impl RequestSerializable for ProcData {
-    fn serialize(&self) -> String {
-        /*
-        pid: i32,
-        cpu: f32,
-        ram: u64,
-        progress: usize,
-        */
-        let node_id = self.node_id.to_string();
-        let pid = self.pid.to_string();
-        let cpu = self.cpu.to_string();
-        let ram = self.ram.to_string();
-        let progress = self.progress.to_string();
-        let send_t = self.send_t.to_string();
-        let recv_t = self.recv_t.to_string();
-        let delay_t = self.delay_t.to_string();
-        let scatter_t = self.scatter_t.to_string();
-
-        // let mut res =2
-        //     String::with_capacity(25 + pid.len() + cpu.len() + ram.len() + progress.len());
-
-        //res = "?pid=".to_owned() + &pid + "&cpu=" + &cpu + "&ram=" + &ram + "&progress=" + &progress;
-        let res = "{\"pid\":".to_owned()
-            + &pid
-            + ",\"nodeId\":"
-            + &node_id
-            + ",\"cpu\":"
-            + &cpu
-            + ",\"ram\":"
-            + &ram
-            + ",\"progress\":"
-            + &progress
-            + ",\"sendTime\":"
-            + &send_t
-            + ",\"receiveTime\":"
-            + &recv_t
-            + ",\"delayTime\":"
-            + &delay_t
-            + ",\"scatterTime\":"
-            + &scatter_t
-            + "}";
-
-        return res;
+    fn kind(&self) -> MessageKind {
+        MessageKind::Process
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// The latest state of the node and of the processes that reported during a coalescing window
+///
+/// # Properties
+/// -`node`: The node's object
+/// -`processes`: The processes that reported, by PID
+#[derive(Debug, Serialize)]
+pub struct UpdateBatch<'a> {
+    node: &'a NodeData,
+    processes: Vec<&'a ProcData>,
+}
+
+impl<'a> UpdateBatch<'a> {
+    pub fn new(node: &'a NodeData, processes: Vec<&'a ProcData>) -> Self {
+        UpdateBatch { node, processes }
+    }
+}
+
+impl RequestSerializable for UpdateBatch<'_> {
+    fn kind(&self) -> MessageKind {
+        MessageKind::Batch
+    }
+
+    fn encode_stamped(&self, encoding: Encoding, stamp: Option<&Stamp>) -> Vec<u8> {
+        to_bytes(self, encoding, stamp)
+    }
+}
+
+/// Sends the memory pressure as two fields, both `null` when the kernel does not report it
+mod pressure_fields {
+    use crate::monitor::memory::MemoryPressure;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize, Default)]
+    #[serde(rename_all = "camelCase", default)]
+    struct Fields {
+        memory_pressure_some: Option<f32>,
+        memory_pressure_full: Option<f32>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        pressure: &Option<MemoryPressure>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Fields {
+            memory_pressure_some: pressure.map(|p| p.some),
+            memory_pressure_full: pressure.map(|p| p.full),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<MemoryPressure>, D::Error> {
+        let fields = Fields::deserialize(deserializer)?;
+
+        Ok(fields
+            .memory_pressure_some
+            .zip(fields.memory_pressure_full)
+            .map(|(some, full)| MemoryPressure { some, full }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::http_requests::SCHEMA_VERSION;
+    use crate::monitor::sampler::ProcSample;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+
+    fn info() -> NodeInfo {
+        NodeInfo {
+            cores: 4,
+            threads: 4,
+            total_ram: 1_000_000,
+        }
+    }
+
+    fn process(pid: i32, name: &str, cpu: f32, ram: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: name.to_owned(),
+            usage: ProcSample {
+                cpu,
+                ram,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn node_data_follows_the_snapshots() {
+        let mut source = FakeSource::new(info());
+        source
+            .push_snapshot(Snapshot {
+                cpu_usage: 10.0,
+                used_ram: 200_000,
+                temperature: vec![45.0, 46.0],
+                ..Default::default()
+            })
+            .push_snapshot(Snapshot {
+                cpu_usage: 90.0,
+                used_ram: 800_000,
+                temperature: vec![70.0, 71.0],
+                ..Default::default()
+            });
+
+        let mut node = NodeData::new(&mut source);
+        assert_eq!(node.cores, 4);
+        assert_eq!(node.total_ram, 1_000_000);
+        assert_eq!(node.cpu_usage, 10.0);
+
+        node.update(&source.sample(&[]));
+        assert_eq!(node.cpu_usage, 90.0);
+        assert_eq!(node.used_ram, 800_000);
+        assert_eq!(node.temperature, vec![70.0, 71.0]);
+    }
+
+    #[test]
+    fn fetch_all_only_keeps_processes_with_the_name() {
+        let mut source = FakeSource::new(info());
+        source.push_processes(vec![
+            process(10, "dwm", 50.0, 1000),
+            process(11, "bash", 1.0, 10),
+            process(12, "dwm", 60.0, 2000),
+        ]);
+
+        let procs = ProcData::fetch_all("dwm", 3, &mut source);
+
+        let mut pids: Vec<i32> = procs.keys().copied().collect();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![10, 12]);
+        assert_eq!(procs[&12].ram, 2000);
+        assert_eq!(procs[&12].node_id, 3);
+    }
+
+    #[test]
+    fn process_update_reads_the_snapshot() {
+        let mut source = FakeSource::new(info());
+        let mut processes = HashMap::new();
+        processes.insert(
+            10,
+            ProcSample {
+                cpu: 75.0,
+                ram: 4096,
+                ram_growth: 0.5,
+            },
+        );
+        source.push_snapshot(Snapshot {
+            processes,
+            ..Default::default()
+        });
+
+        let mut p = ProcData::new(10, 0, &Snapshot::default());
+        assert_eq!(p.pid, 10);
+        assert_eq!(p.ram, 0);
+
+        p.update(50.0, 1.0, 2.0, 3.0, 4.0, &source.sample(&[10]));
+        assert_eq!(p.cpu, 75.0);
+        assert_eq!(p.ram, 4096);
+        assert_eq!(p.progress, 50.0);
+
+        // not sampled yet, the usage stays as it was
+        p.update(60.0, 5.0, 6.0, 7.0, 8.0, &source.sample(&[]));
+        assert_eq!(p.progress, 60.0);
+        assert_eq!(p.send_t, 5.0);
+        assert_eq!(p.scatter_t, 8.0);
+        assert_eq!(p.cpu, 75.0);
+        assert_eq!(p.ram, 4096);
+        assert_eq!(p.ram_growth, 0.5);
+    }
+
+    #[test]
+    fn node_data_round_trips_through_the_wire_format() {
+        // pins the field names of the node and that the decimals are kept. The values are not
+        // whole numbers, as those used to be sent without a decimal part
+        let wire = r#"{"nodeId":2,"cores":4,"threads":4,"cpu":12.5,"totalRam":1000000,
+            "usedRam":200000,"availableRam":700000,"usedSwap":0,"totalSwap":0,
+            "memoryPressureSome":null,"memoryPressureFull":null,"oomRisk":"low",
+            "temperature":[45.5, 46.5]}"#;
+        let node: NodeData = serde_json::from_str(wire).unwrap();
+        assert_eq!(node.cpu_usage, 12.5);
+        assert_eq!(node.pressure, None);
+
+        let json = RequestSerializable::serialize(&node);
+        assert_eq!(serde_json::from_str::<NodeData>(&json).unwrap(), node);
+
+        let mut expected: serde_json::Value = serde_json::from_str(wire).unwrap();
+        expected["schemaVersion"] = SCHEMA_VERSION.into();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn proc_data_round_trips_through_the_wire_format() {
+        let wire = r#"{"pid":10,"nodeId":2,"cpu":75.5,"ram":4096,"ramGrowth":0.5,"progress":50.5,
+            "sendTime":1.5,"receiveTime":2.5,"delayTime":3.5,"scatterTime":4.5}"#;
+        let p: ProcData = serde_json::from_str(wire).unwrap();
+        assert_eq!(p.recv_t, 2.5);
+
+        let json = RequestSerializable::serialize(&p);
+        assert_eq!(serde_json::from_str::<ProcData>(&json).unwrap(), p);
+
+        let mut expected: serde_json::Value = serde_json::from_str(wire).unwrap();
+        expected["schemaVersion"] = SCHEMA_VERSION.into();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn binary_encodings_round_trip() {
+        let mut p = ProcData::new(10, 2, &Snapshot::default());
+        p.progress = 42.5;
+
+        let cbor = p.encode(Encoding::Cbor);
+        assert_eq!(
+            ciborium::de::from_reader::<ProcData, _>(&cbor[..]).unwrap(),
+            p
+        );
+
+        let msgpack = p.encode(Encoding::MessagePack);
+        assert_eq!(rmp_serde::from_slice::<ProcData>(&msgpack).unwrap(), p);
+    }
+
+    #[test]
+    fn non_finite_values_are_sent_as_null() {
+        let mut p = ProcData::new(10, 0, &Snapshot::default());
+        p.cpu = f32::NAN;
+        p.progress = f32::INFINITY;
+
+        let json: serde_json::Value =
+            serde_json::from_str(&RequestSerializable::serialize(&p)).unwrap();
+        assert!(json["cpu"].is_null());
+        assert!(json["progress"].is_null());
     }
 }
@@ -0,0 +1,542 @@
+//! A dashboard of a single node in the terminal, for when the only way into the node is an SSH
+//! session.
+//!
+//! The dashboard shows the node's CPU, frequency, temperature and memory, and every tracked
+//! process with its progress, estimated time left, CPU and RAM, and the recent times of its steps.
+//!
+//! It reads the node and the processes from one of two [Feed]s:
+//!
+//! - the [API](crate::communication::rest) of a monitor running on the node
+//! - its own collectors, without a running monitor. The progress and the step times are then
+//!   always 0, as only the monitor receives them from the cluster program
+//!
+//! Press `q` or `Esc` to quit.
+
+use crate::communication::http_requests::now_ms;
+use crate::config::{HistorySettings, Settings};
+use crate::monitor::discovery::discover;
+use crate::monitor::history::History;
+use crate::monitor::memory::OomWatch;
+use crate::monitor::sampler::Snapshot;
+use crate::monitor::source::{SysinfoSource, SystemSource};
+use crate::monitor::stats::{NodeData, ProcData};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use reqwest::blocking::Client;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How long the samples are kept for the sparklines and the estimated times left
+const HISTORY_S: u64 = 300;
+
+/// The steps of the cluster program, with the name of the [ProcData::metric] they are read from
+const STEPS: [(&str, &str); 4] = [
+    ("send_t", "send"),
+    ("recv_t", "recv"),
+    ("delay_t", "delay"),
+    ("scatter_t", "scatter"),
+];
+
+/// The height of the panel of a process, borders included
+const PROCESS_HEIGHT: u16 = 6;
+
+/// Where the dashboard reads the node and the processes from
+pub enum Feed {
+    /// Polls the API of a running monitor
+    Api { base_url: String, client: Client },
+    /// Samples the node with its own collectors
+    Local(Box<Collectors>),
+}
+
+/// The collectors of the dashboard, when there is no running monitor to read from
+///
+/// # Properties
+/// -`proc_name`: The name of the processes to track
+/// -`source`: Where to read the system usage from
+/// -`node`: The node's object
+/// -`procs`: The tracked processes
+/// -`oom_watch`: Estimates the risk of running out of memory
+/// -`last`: The previous sample, to compute how fast the processes grow
+pub struct Collectors {
+    proc_name: String,
+    source: SysinfoSource,
+    node: NodeData,
+    procs: HashMap<i32, ProcData>,
+    oom_watch: OomWatch,
+    last: Snapshot,
+}
+
+impl Collectors {
+    /// Samples the node and the processes
+    fn poll(&mut self) -> (NodeData, HashMap<i32, ProcData>) {
+        let node_id = self.node.get_id();
+        discover(&self.proc_name, node_id, &mut self.procs, &mut self.source);
+
+        let pids: Vec<i32> = self.procs.keys().copied().collect();
+        let mut s = self.source.sample(&pids);
+        s.compute_growth(&self.last);
+        self.oom_watch.observe(&mut s, node_id);
+
+        self.node.update(&s);
+        for p in self.procs.values_mut() {
+            p.update_usage(&s);
+        }
+        self.last = s;
+
+        (self.node.clone(), self.procs.clone())
+    }
+}
+
+impl Feed {
+    /// Reads from the API of a running monitor
+    ///
+    /// # Arguments
+    ///
+    /// - `base_url`: The URL the API is served on, such as `http://127.0.0.1:8080`
+    pub fn api(base_url: &str) -> Self {
+        Feed::Api {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client: Client::builder()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// Samples the node without a running monitor
+    ///
+    /// # Arguments
+    ///
+    /// - `proc_name`: The name of the processes to track
+    /// - `settings`: How the risk of running out of memory is estimated
+    pub fn local(proc_name: String, settings: &Settings) -> Self {
+        let mut source = SysinfoSource::new();
+        let node = NodeData::new(&mut source);
+
+        Feed::Local(Box::new(Collectors {
+            proc_name,
+            source,
+            node,
+            procs: HashMap::new(),
+            oom_watch: OomWatch::new(settings.memory.clone()),
+            last: Snapshot::default(),
+        }))
+    }
+
+    /// Describes where the data comes from, for the status line
+    fn describe(&self) -> String {
+        match self {
+            Feed::Api { base_url, .. } => format!("API at {}", base_url),
+            Feed::Local(c) => format!("Local collectors for {}", c.proc_name),
+        }
+    }
+
+    /// Reads the node and the processes as they are now
+    fn poll(&mut self) -> Result<(NodeData, HashMap<i32, ProcData>), Box<dyn Error>> {
+        match self {
+            Feed::Api { base_url, client } => {
+                let node = serde_json::from_slice(
+                    &client
+                        .get(format!("{}/node", base_url))
+                        .send()?
+                        .error_for_status()?
+                        .bytes()?,
+                )?;
+                let processes: Vec<ProcData> = serde_json::from_slice(
+                    &client
+                        .get(format!("{}/processes", base_url))
+                        .send()?
+                        .error_for_status()?
+                        .bytes()?,
+                )?;
+
+                Ok((
+                    node,
+                    processes.into_iter().map(|p| (p.get_pid(), p)).collect(),
+                ))
+            }
+            Feed::Local(c) => Ok(c.poll()),
+        }
+    }
+}
+
+/// What the dashboard shows
+///
+/// # Properties
+/// -`source`: Where the data comes from
+/// -`node`: The node's object, once read
+/// -`procs`: The tracked processes
+/// -`history`: The recent samples, for the sparklines and the estimated times left
+/// -`error`: Why the latest poll failed, if it did
+struct Dashboard {
+    source: String,
+    node: Option<NodeData>,
+    procs: HashMap<i32, ProcData>,
+    history: History,
+    error: Option<String>,
+}
+
+impl Dashboard {
+    fn new(source: String) -> Self {
+        Dashboard {
+            source,
+            node: None,
+            procs: HashMap::new(),
+            history: History::new(HistorySettings {
+                max_age_s: HISTORY_S,
+            }),
+            error: None,
+        }
+    }
+
+    /// Keeps the result of a poll
+    ///
+    /// # Arguments
+    ///
+    /// - `polled`: The node and the processes, or why they could not be read
+    /// - `now_ms`: The current time, in milliseconds since the UNIX epoch
+    fn refresh(
+        &mut self,
+        polled: Result<(NodeData, HashMap<i32, ProcData>), Box<dyn Error>>,
+        now_ms: u64,
+    ) {
+        match polled {
+            Ok((node, procs)) => {
+                self.history.record(now_ms, &node, &procs);
+                self.node = Some(node);
+                self.procs = procs;
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    /// Returns the values a step of a process took in the kept samples, oldest first
+    fn step_times(&self, pid: i32, metric: &str) -> Vec<f32> {
+        self.history
+            .since(0)
+            .filter_map(|s| s.processes.iter().find(|p| p.get_pid() == pid))
+            .filter_map(|p| p.metric(metric))
+            .collect()
+    }
+}
+
+/// Shows the dashboard until `q` or `Esc` is pressed
+///
+/// # Arguments
+///
+/// - `feed`: Where to read the node and the processes from
+/// - `interval`: The time between two polls
+pub fn run(mut feed: Feed, interval: Duration) -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let result = show(&mut terminal, &mut feed, interval);
+
+    // the terminal is restored even when drawing failed
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
+
+/// Polls the feed and redraws the dashboard until the user quits
+fn show<B: Backend>(
+    terminal: &mut Terminal<B>,
+    feed: &mut Feed,
+    interval: Duration,
+) -> io::Result<()> {
+    let mut dashboard = Dashboard::new(feed.describe());
+    let mut next_poll = Instant::now();
+
+    loop {
+        if Instant::now() >= next_poll {
+            dashboard.refresh(feed.poll(), now_ms());
+            next_poll = Instant::now() + interval;
+        }
+
+        terminal.draw(|f| draw(f, &dashboard, now_ms()))?;
+
+        if event::poll(next_poll.saturating_duration_since(Instant::now()))? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || ctrl_c;
+
+                if key.kind == KeyEventKind::Press && quit {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Draws the whole dashboard
+fn draw(frame: &mut Frame, dashboard: &Dashboard, now_ms: u64) {
+    let [node_area, procs_area, status_area] = Layout::vertical([
+        Constraint::Length(6),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.size());
+
+    match &dashboard.node {
+        Some(node) => draw_node(frame, node, node_area),
+        None => frame.render_widget(
+            Paragraph::new("Waiting for the first sample...").block(Block::bordered()),
+            node_area,
+        ),
+    }
+
+    let mut processes: Vec<&ProcData> = dashboard.procs.values().collect();
+    processes.sort_unstable_by_key(|p| p.get_pid());
+
+    let shown = (procs_area.height / PROCESS_HEIGHT) as usize;
+    let areas = Layout::vertical(vec![
+        Constraint::Length(PROCESS_HEIGHT);
+        shown.min(processes.len())
+    ])
+    .split(procs_area);
+    for (p, area) in processes.iter().zip(areas.iter()) {
+        draw_process(frame, dashboard, p, *area, now_ms);
+    }
+
+    let hidden = processes.len().saturating_sub(shown);
+    let mut status = format!("{} | {} tracked", dashboard.source, processes.len());
+    if hidden > 0 {
+        status += &format!(", {} not shown", hidden);
+    }
+    if let Some(e) = &dashboard.error {
+        status += &format!(" | failed to refresh: {}", e);
+    }
+    status += " | q to quit";
+    let style = match dashboard.error {
+        Some(_) => Style::default().fg(Color::Red),
+        None => Style::default(),
+    };
+    frame.render_widget(Paragraph::new(status).style(style), status_area);
+}
+
+/// Draws the panel of the node
+fn draw_node(frame: &mut Frame, node: &NodeData, area: Rect) {
+    let block = Block::bordered().title(format!(" Node {} ", node.get_id()));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [cpu_area, ram_area, details_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(2),
+    ])
+    .areas(inner);
+
+    let metric = |name| node.metric(name).unwrap_or(0.0);
+    let frequency = match node.metric("frequency") {
+        Some(f) => format!("{} MHz", f),
+        None => "unknown frequency".to_owned(),
+    };
+    let temperature = match node.get_temperature() {
+        [] => "no temperature sensor".to_owned(),
+        t => t
+            .iter()
+            .map(|t| format!("{:.1}ºC", t))
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .ratio(ratio(metric("cpu_usage"), 100.0))
+            .label(format!(
+                "CPU {:.1}% | {} cores | {} | {}",
+                metric("cpu_usage"),
+                metric("cores"),
+                frequency,
+                temperature
+            ))
+            .gauge_style(Style::default().fg(Color::Cyan)),
+        cpu_area,
+    );
+
+    frame.render_widget(
+        Gauge::default()
+            .ratio(ratio(metric("used_ram"), metric("total_ram")))
+            .label(format!(
+                "RAM {} / {}",
+                size(metric("used_ram")),
+                size(metric("total_ram"))
+            ))
+            .gauge_style(Style::default().fg(Color::Green)),
+        ram_area,
+    );
+
+    let pressure = match (node.metric("pressure_some"), node.metric("pressure_full")) {
+        (Some(some), Some(full)) => format!("pressure {:.1}% some, {:.1}% full", some, full),
+        _ => "no memory pressure reported".to_owned(),
+    };
+    let details = vec![
+        Line::from(format!(
+            "Available {} | swap {} / {}",
+            size(metric("available_ram")),
+            size(metric("used_swap")),
+            size(metric("total_swap"))
+        )),
+        Line::from(format!("OOM risk {:?} | {}", node.get_oom_risk(), pressure)),
+    ];
+    frame.render_widget(Paragraph::new(details), details_area);
+}
+
+/// Draws the panel of a process
+fn draw_process(frame: &mut Frame, dashboard: &Dashboard, p: &ProcData, area: Rect, now_ms: u64) {
+    let block =
+        Block::bordered().title(format!(" PID {} | rank {} ", p.get_pid(), p.get_node_id()));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [progress_area, usage_area, steps_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(2),
+    ])
+    .areas(inner);
+
+    let metric = |name| p.metric(name).unwrap_or(0.0);
+    let eta = match dashboard.history.eta_s(p, now_ms) {
+        Some(s) => format!("ETA {}", duration(s)),
+        None => "ETA unknown".to_owned(),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .ratio(ratio(metric("progress"), 100.0))
+            .label(format!("{:.1}% | {}", metric("progress"), eta))
+            .gauge_style(Style::default().fg(Color::Blue)),
+        progress_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new(format!(
+            "CPU {:.1}% | RAM {} ({}/s)",
+            metric("cpu"),
+            size(metric("ram")),
+            size(metric("ram_growth"))
+        )),
+        usage_area,
+    );
+
+    let columns = Layout::horizontal([Constraint::Ratio(1, 4); 4]).split(steps_area);
+    for ((metric, step), area) in STEPS.iter().zip(columns.iter()) {
+        let times = dashboard.step_times(p.get_pid(), metric);
+        let [label_area, spark_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Length(1)]).areas(*area);
+
+        frame.render_widget(
+            Paragraph::new(format!("{} {}", step, times.last().copied().unwrap_or(0.0))),
+            label_area,
+        );
+
+        // only the latest times fit, and the bars are relative to the slowest of them
+        let shown = &times[times.len().saturating_sub(spark_area.width as usize)..];
+        let slowest = shown.iter().copied().fold(0.0, f32::max);
+        let bars: Vec<u64> = shown
+            .iter()
+            .map(|t| (ratio(*t, slowest) * 100.0) as u64)
+            .collect();
+        frame.render_widget(
+            Sparkline::default()
+                .data(&bars)
+                .max(100)
+                .style(Style::default().fg(Color::Yellow)),
+            spark_area,
+        );
+    }
+}
+
+/// Returns how much of the total a value is, between 0 and 1
+fn ratio(value: f32, total: f32) -> f64 {
+    if total > 0.0 && value.is_finite() {
+        (value / total).clamp(0.0, 1.0) as f64
+    } else {
+        0.0
+    }
+}
+
+/// Writes a size given in KB in the largest unit it has at least one of
+fn size(kb: f32) -> String {
+    if kb.abs() >= 1024.0 * 1024.0 {
+        format!("{:.1} GB", kb / (1024.0 * 1024.0))
+    } else if kb.abs() >= 1024.0 {
+        format!("{:.1} MB", kb / 1024.0)
+    } else {
+        format!("{:.0} KB", kb)
+    }
+}
+
+/// Writes a number of seconds as hours, minutes and seconds
+fn duration(s: f32) -> String {
+    let s = s.round() as u64;
+
+    match s {
+        0..=59 => format!("{}s", s),
+        60..=3599 => format!("{}m{:02}s", s / 60, s % 60),
+        _ => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::source::{FakeSource, NodeInfo};
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn draws_the_node_and_every_process() {
+        let node = NodeData::new(&mut FakeSource::new(NodeInfo {
+            cores: 4,
+            total_ram: 1024 * 1024,
+            ..Default::default()
+        }));
+        let mut snapshot = Snapshot::default();
+        snapshot.processes.insert(10, Default::default());
+
+        let mut dashboard = Dashboard::new("test".to_owned());
+        let mut p = ProcData::new(10, 2, &snapshot);
+        for (i, progress) in [10.0, 30.0, 50.0].iter().enumerate() {
+            p.update(*progress, 1.5, 2.0, 0.5, 1.0, &snapshot);
+            let procs: HashMap<i32, ProcData> = vec![(10, p.clone())].into_iter().collect();
+            dashboard.refresh(Ok((node.clone(), procs)), i as u64 * 10_000);
+        }
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 14)).unwrap();
+        terminal.draw(|f| draw(f, &dashboard, 20_000)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let text: String = (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer.get(x, y).symbol())
+                    .collect::<String>()
+                    + "\n"
+            })
+            .collect();
+
+        assert!(text.contains("Node 0"));
+        assert!(text.contains("RAM 0 KB / 1.0 GB"));
+        assert!(text.contains("PID 10 | rank 2"));
+        // 40% in 20 seconds, so 25 more seconds
+        assert!(text.contains("50.0% | ETA 25s"));
+        assert!(text.contains("send 1.5"));
+        assert!(text.contains("test | 1 tracked | q to quit"));
+    }
+}
@@ -2,10 +2,36 @@
 /// With help from <https://gist.github.com/ThatsNoMoon/edc16ab072d470d3a7f9d996c8fc9dec>
 use std::io::Write;
 use std::net::TcpStream;
-use std::process;
+use std::process::{self, Command};
 
 fn start() -> TcpStream {
-    TcpStream::connect("127.0.0.1:49152").unwrap()
+    let mut stream = TcpStream::connect("127.0.0.1:49152").unwrap();
+    authenticate(&mut stream);
+
+    stream
+}
+
+/// Sends a fresh token first when MONITOR_SETTINGS names the settings of a monitor with a secret.
+/// The token is made by `monitor token ingest`, found in MONITOR_BIN or the PATH
+fn authenticate(stream: &mut TcpStream) {
+    let settings = match std::env::var("MONITOR_SETTINGS") {
+        Ok(settings) => settings,
+        Err(_) => return,
+    };
+    let bin = std::env::var("MONITOR_BIN").unwrap_or_else(|_| "monitor".to_owned());
+
+    let out = Command::new(bin)
+        .args(["token", "ingest", &settings])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "monitor token failed");
+    let hex = String::from_utf8(out.stdout).unwrap();
+    let token: Vec<u8> = (0..hex.trim().len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+
+    stream.write_all(&token).unwrap();
 }
 
 fn send_to_open(i: f32, mut stream: &TcpStream) {
@@ -49,6 +75,7 @@ fn send(percent: f32) {
     match TcpStream::connect("127.0.0.1:49152") {
         Ok(mut stream) => {
             println!("Successfully connected to server in port 49152");
+            authenticate(&mut stream);
 
             let mut a = [0; 13];
             fetch_message(&mut a, percent);